@@ -0,0 +1,228 @@
+/*
+ * Copyright 2025 Kenny Root
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use log::info;
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+use tokio::time::Instant;
+
+use crate::{devices::Device, mqtt::MqttMessage};
+
+/// How long a doorbell may go without any DNS query before it is reported offline.
+pub const DEFAULT_DEVICE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+pub const ONLINE: &str = "online";
+pub const OFFLINE: &str = "offline";
+
+/// Topic suffix carrying `online` or `offline` for a doorbell.
+pub fn availability_topic(device: &Device) -> String {
+    device.topic("availability")
+}
+
+pub fn availability_message(device: &Device, online: bool) -> MqttMessage {
+    let payload = if online { ONLINE } else { OFFLINE };
+    MqttMessage::Publish {
+        topic: availability_topic(device),
+        payload: payload.as_bytes().to_vec(),
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    device: Device,
+    last_seen: Instant,
+    /// What was last reported; nothing yet for a doorbell only known from config.
+    online: Option<bool>,
+}
+
+/// Tracks when each known doorbell last made any DNS query.
+///
+/// Doorbells keep resolving NTP, cloud and heartbeat hosts while idle, so a
+/// doorbell that goes quiet has most likely lost power or dropped off Wi-Fi.
+#[derive(Debug)]
+pub struct Availability {
+    timeout: Duration,
+    /// Keyed by device key, so a doorbell matched by MAC keeps one state when
+    /// its address changes.
+    devices: Mutex<HashMap<String, State>>,
+}
+
+impl Default for Availability {
+    fn default() -> Self {
+        Self::new(DEFAULT_DEVICE_TIMEOUT)
+    }
+}
+
+impl Availability {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            devices: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// How often [`Availability::expire`] should run to notice silence promptly.
+    pub fn check_interval(&self) -> Duration {
+        (self.timeout / 10).clamp(Duration::from_secs(1), Duration::from_secs(60))
+    }
+
+    /// Starts tracking a doorbell known before it has been seen, such as one
+    /// from the configuration or pressed before a restart, so that it is
+    /// reported offline if it stays silent.
+    pub fn watch(&self, device: Device) {
+        let mut devices = self.devices.lock().unwrap();
+        devices.entry(device.key.clone()).or_insert(State {
+            device,
+            last_seen: Instant::now(),
+            online: None,
+        });
+    }
+
+    /// Whether traffic from `device` should be tracked.
+    pub fn knows(&self, device: &Device) -> bool {
+        self.devices.lock().unwrap().contains_key(&device.key)
+    }
+
+    /// Records traffic from `device`, returning an `online` message if it was not
+    /// already online.
+    pub fn seen(&self, device: &Device) -> Option<MqttMessage> {
+        let now = Instant::now();
+        let mut devices = self.devices.lock().unwrap();
+        let state = devices.entry(device.key.clone()).or_insert(State {
+            device: device.clone(),
+            last_seen: now,
+            online: None,
+        });
+        state.device = device.clone();
+        state.last_seen = now;
+        match state.online.replace(true) {
+            Some(true) => return None,
+            Some(false) => info!("{} is back online", device.name),
+            None => {}
+        }
+        Some(availability_message(device, true))
+    }
+
+    /// Marks doorbells that have been silent for longer than the timeout offline,
+    /// returning an `offline` message for each.
+    pub fn expire(&self) -> Vec<MqttMessage> {
+        let now = Instant::now();
+        let mut devices = self.devices.lock().unwrap();
+        devices
+            .iter_mut()
+            .filter(|(_, state)| state.online != Some(false))
+            .filter(|(_, state)| now - state.last_seen > self.timeout)
+            .map(|(_, state)| {
+                state.online = Some(false);
+                info!(
+                    "{} has been silent for {:?}; marking it offline",
+                    state.device.name, self.timeout
+                );
+                availability_message(&state.device, false)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(message: &MqttMessage) -> &str {
+        std::str::from_utf8(message.payload()).unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_silent_device_goes_offline_and_back() {
+        let availability = Availability::new(Duration::from_millis(20));
+        let device = Device::new("192.168.1.100");
+
+        let online = availability.seen(&device).unwrap();
+        assert_eq!(online.topic(), "ringdet-192.168.1.100/availability");
+        assert_eq!(payload(&online), ONLINE);
+        assert!(availability.seen(&device).is_none());
+        assert!(availability.expire().is_empty());
+
+        tokio::time::advance(Duration::from_millis(30)).await;
+        let offline = availability.expire();
+        assert_eq!(offline.len(), 1);
+        assert_eq!(payload(&offline[0]), OFFLINE);
+        assert!(availability.expire().is_empty(), "offline is reported once");
+
+        let online = availability.seen(&device).unwrap();
+        assert_eq!(payload(&online), ONLINE);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_watched_device_goes_offline_unless_seen() {
+        let availability = Availability::new(Duration::from_millis(20));
+        let silent = Device::new("192.168.1.100");
+        let active = Device::new("192.168.1.101");
+        availability.watch(silent.clone());
+        availability.watch(active.clone());
+        assert!(availability.knows(&silent));
+        assert!(!availability.knows(&Device::new("192.168.1.102")));
+
+        let online = availability.seen(&active).unwrap();
+        assert_eq!(payload(&online), ONLINE);
+
+        tokio::time::advance(Duration::from_millis(30)).await;
+        assert!(availability.seen(&active).is_none());
+        let offline = availability.expire();
+        assert_eq!(offline.len(), 1);
+        assert_eq!(offline[0].topic(), "ringdet-192.168.1.100/availability");
+        assert_eq!(payload(&offline[0]), OFFLINE);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mac_keeps_one_state_across_addresses() {
+        let availability = Availability::new(Duration::from_millis(20));
+        let mut device = Device::new("192.168.1.40");
+        device.key = "a4cf12000001".to_string();
+        device.slug = device.key.clone();
+
+        assert!(availability.seen(&device).is_some());
+        tokio::time::advance(Duration::from_millis(15)).await;
+        device.address = "192.168.1.41".to_string();
+        assert!(availability.seen(&device).is_none(), "already online");
+
+        tokio::time::advance(Duration::from_millis(15)).await;
+        assert!(
+            availability.expire().is_empty(),
+            "the old address does not report the doorbell offline"
+        );
+        tokio::time::advance(Duration::from_millis(10)).await;
+        assert_eq!(availability.expire().len(), 1);
+    }
+
+    #[test]
+    fn test_check_interval() {
+        assert_eq!(
+            Availability::default().check_interval(),
+            Duration::from_secs(60)
+        );
+        assert_eq!(
+            Availability::new(Duration::from_secs(30)).check_interval(),
+            Duration::from_secs(3)
+        );
+        assert_eq!(
+            Availability::new(Duration::ZERO).check_interval(),
+            Duration::from_secs(1)
+        );
+    }
+}
@@ -14,46 +14,240 @@
  * limitations under the License.
  */
 
-use anyhow::{Context, Result};
-use log::{error, info};
-use std::path::PathBuf;
+use anyhow::{anyhow, Context, Result};
+use log::{error, info, warn};
+use std::{
+    collections::HashSet, future::Future, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration,
+};
 use tokio::{
+    net::TcpListener,
     signal::{
         self,
         unix::{signal, SignalKind},
     },
-    sync::mpsc::{self},
+    sync::{broadcast, mpsc},
 };
 
 use crate::{
+    builder::BridgeBuilder,
     dns_service::DnsService,
+    event::BridgeEvent,
+    fanout::{FanOut, PublisherSlot, PublisherStats, DEFAULT_QUEUE_SIZE},
+    health::{HealthCheck, HealthConfig},
+    http,
     listener::DnsListener,
     messaging::MessagePublisher,
-    mqtt::{MqttClient, MqttMessage},
+    metrics::Metrics,
+    mqtt::{MqttConfig, MqttMessage, MqttProtocol},
+    mqtt5_service::Mqtt5Service,
     mqtt_service::MqttService,
+    outbox::{Outbox, OutboxConfig},
+    resilience::{CircuitBreaker, ResilienceConfig, Resilient},
+    source::{SourceState, DEFAULT_SOURCE_TIMEOUT},
+    supervisor::{self, ListenerHealth, SupervisorConfig},
+    systemd::Notifier,
 };
 
+pub const DEFAULT_EVENT_QUEUE_SIZE: usize = 10;
+
+/// Events kept for slow [`Bridge::subscribe`] receivers.
+pub const EVENT_BROADCAST_CAPACITY: usize = 64;
+
+/// What the bridge does once it is asked to stop.
+#[derive(Debug, Clone)]
+pub struct ShutdownConfig {
+    /// How long publishers get to drain their queues.
+    pub grace: Duration,
+    /// Whether to send each publisher's death message.
+    pub send_death: bool,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            grace: Duration::from_secs(5),
+            send_death: true,
+        }
+    }
+}
+
 pub struct Bridge {
-    dns_listener: Box<dyn DnsListener>,
-    message_publisher: Option<Box<dyn MessagePublisher>>,
+    dns_listeners: Vec<Box<dyn DnsListener>>,
+    publishers: Vec<PublisherSlot>,
+    notifier: Notifier,
+    event_queue_size: usize,
+    publisher_queue_size: usize,
+    shutdown: ShutdownConfig,
+    supervisor: SupervisorConfig,
+    metrics: Arc<Metrics>,
+    metrics_listener: Option<SocketAddr>,
+    health: HealthConfig,
+    /// Listeners expected to bind a dnstap socket, for the health check and
+    /// readiness: those that track a dnstap source.
+    dnstap_listeners: usize,
+    source_timeout: Duration,
+    events: broadcast::Sender<BridgeEvent>,
+}
+
+/// Creates the MQTT publisher matching the configured protocol version.
+pub fn mqtt_publisher(mqtt_config: &MqttConfig) -> Box<dyn MessagePublisher> {
+    info!(
+        "MQTT {:?} configured to {}:{}",
+        mqtt_config.protocol, mqtt_config.host, mqtt_config.port
+    );
+    match mqtt_config.protocol {
+        MqttProtocol::V311 => Box::new(MqttService::from_config(mqtt_config)),
+        MqttProtocol::V5 => Box::new(Mqtt5Service::from_config(mqtt_config)),
+    }
+}
+
+fn count_dnstap_listeners(dns_listeners: &[Box<dyn DnsListener>]) -> usize {
+    dns_listeners
+        .iter()
+        .filter(|listener| listener.source().is_some())
+        .count()
 }
 
 impl Bridge {
-    pub fn new(dns_socket_path: PathBuf) -> Self {
+    pub fn builder() -> BridgeBuilder {
+        BridgeBuilder::new()
+    }
+
+    pub(crate) fn assemble(
+        dns_listeners: Vec<Box<dyn DnsListener>>,
+        publishers: Vec<Box<dyn MessagePublisher>>,
+    ) -> Self {
         Self {
-            dns_listener: Box::new(DnsService::new(dns_socket_path)),
-            message_publisher: None,
+            dnstap_listeners: count_dnstap_listeners(&dns_listeners),
+            dns_listeners,
+            publishers: publishers.into_iter().map(PublisherSlot::new).collect(),
+            notifier: Notifier::default(),
+            event_queue_size: DEFAULT_EVENT_QUEUE_SIZE,
+            publisher_queue_size: DEFAULT_QUEUE_SIZE,
+            shutdown: ShutdownConfig::default(),
+            supervisor: SupervisorConfig::default(),
+            metrics: Arc::default(),
+            metrics_listener: None,
+            health: HealthConfig::default(),
+            source_timeout: DEFAULT_SOURCE_TIMEOUT,
+            events: broadcast::channel(EVENT_BROADCAST_CAPACITY).0,
         }
     }
 
+    pub fn new(dns_socket_path: PathBuf) -> Self {
+        let metrics = Arc::new(Metrics::default());
+        let dns_service = DnsService::new(dns_socket_path).with_metrics(Arc::clone(&metrics));
+        Self::assemble(vec![Box::new(dns_service)], vec![]).with_metrics(metrics)
+    }
+
     pub fn from_components(
         dns_listener: Box<dyn DnsListener>,
         message_publisher: Option<Box<dyn MessagePublisher>>,
     ) -> Self {
-        Self {
-            dns_listener,
-            message_publisher,
+        Self::assemble(vec![dns_listener], message_publisher.into_iter().collect())
+    }
+
+    pub fn with_publishers(
+        dns_listener: Box<dyn DnsListener>,
+        publishers: Vec<Box<dyn MessagePublisher>>,
+    ) -> Self {
+        Self::assemble(vec![dns_listener], publishers)
+    }
+
+    /// Replaces the DNS listeners, for example with one using a socket-activated listener.
+    pub fn with_dns_listener(mut self, dns_listener: Box<dyn DnsListener>) -> Self {
+        self.dns_listeners = vec![dns_listener];
+        self.dnstap_listeners = count_dnstap_listeners(&self.dns_listeners);
+        self
+    }
+
+    pub(crate) fn with_queue_sizes(mut self, event: usize, publisher: usize) -> Self {
+        self.event_queue_size = event;
+        self.publisher_queue_size = publisher;
+        self
+    }
+
+    pub(crate) fn with_shutdown(mut self, shutdown: ShutdownConfig) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Sets how failed DNS listeners are restarted and reported.
+    pub fn with_supervisor(mut self, supervisor: SupervisorConfig) -> Self {
+        self.supervisor = supervisor;
+        self
+    }
+
+    /// Records counters in `metrics`, which DNS listeners should share.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Serves the metrics over HTTP at `/metrics` and the health check at `/healthz`.
+    pub fn with_metrics_listener(mut self, addr: SocketAddr) -> Self {
+        self.metrics_listener = Some(addr);
+        self
+    }
+
+    /// Sets when `/healthz` reports the bridge as unhealthy.
+    pub fn with_health(mut self, health: HealthConfig) -> Self {
+        self.health = health;
+        self
+    }
+
+    /// Sets how long a dnstap source may send nothing before it is reported down.
+    pub fn with_source_timeout(mut self, timeout: Duration) -> Self {
+        self.source_timeout = timeout;
+        self
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// Hands a message to the publishers, or logs it when there are none, and
+    /// tells subscribers about any event it carries.
+    fn deliver(&self, fanout: &FanOut, message: MqttMessage) {
+        if let Some(event) = message.bridge_event() {
+            // Nobody listening is not an error.
+            let _ = self.events.send(event);
         }
+        if !self.publishers.is_empty() {
+            fanout.dispatch(message);
+        } else {
+            // Log message if no publisher is configured
+            info!(
+                "{}: {}",
+                message.topic(),
+                String::from_utf8_lossy(message.payload())
+            );
+        }
+    }
+
+    /// Tells every publisher the bridge's availability without holding up the event loop.
+    fn broadcast_status(&self, status: &'static str) {
+        for slot in &self.publishers {
+            let publisher = Arc::clone(&slot.publisher);
+            tokio::spawn(async move {
+                if let Err(e) = publisher.send_status(status).await {
+                    warn!("Failed to send status via {}: {}", publisher.name(), e);
+                }
+            });
+        }
+    }
+
+    /// Reports readiness, status and watchdog pings to a service manager.
+    pub fn with_notifier(mut self, notifier: Notifier) -> Self {
+        self.notifier = notifier;
+        self
+    }
+
+    /// Adds another publisher that receives every message alongside the existing ones.
+    pub fn add_publisher(mut self, publisher: Box<dyn MessagePublisher>) -> Self {
+        info!("Publishing to {}", publisher.name());
+        self.publishers.push(PublisherSlot::new(publisher));
+        self
     }
 
     pub fn with_mqtt(
@@ -64,81 +258,302 @@ impl Bridge {
         mqtt_password: String,
         mqtt_topic_prefix: String,
     ) -> Self {
-        let mqtt_client = MqttClient::new(
-            mqtt_host.as_str(),
-            mqtt_port,
-            mqtt_username.as_str(),
-            mqtt_password.as_str(),
-        );
-        info!("MQTT configured to {}:{}", mqtt_host, mqtt_port);
+        Self::with_mqtt_config(
+            dns_socket_path,
+            MqttConfig::new(
+                mqtt_host,
+                mqtt_port,
+                mqtt_username,
+                mqtt_password,
+                mqtt_topic_prefix,
+            ),
+        )
+    }
 
-        let mqtt_service = MqttService::new(*mqtt_client.client.clone(), mqtt_topic_prefix);
+    pub fn with_mqtt_config(dns_socket_path: PathBuf, mqtt_config: MqttConfig) -> Self {
+        Self::from_components(
+            Box::new(DnsService::new(dns_socket_path)),
+            Some(mqtt_publisher(&mqtt_config)),
+        )
+    }
 
-        Self {
-            dns_listener: Box::new(DnsService::new(dns_socket_path)),
-            message_publisher: Some(Box::new(mqtt_service)),
+    /// Puts a timeout, retries and a circuit breaker around every publisher.
+    ///
+    /// Apply this before [`Bridge::with_outbox`] so that messages rejected by an open
+    /// circuit are spooled rather than lost.
+    pub fn with_resilience(mut self, resilience_config: ResilienceConfig) -> Self {
+        for slot in &mut self.publishers {
+            let resilient = Resilient::new(Arc::clone(&slot.publisher), resilience_config.clone());
+            slot.breaker = Some(resilient.breaker());
+            slot.publisher = Arc::new(resilient);
         }
+        self
+    }
+
+    /// Queues messages on disk while a publisher is failing.
+    ///
+    /// With more than one publisher, each gets its own spool file named after it,
+    /// for example `outbox.jsonl.mqtt`.
+    pub fn with_outbox(mut self, outbox_config: OutboxConfig) -> Result<Self> {
+        let shared = self.publishers.len() > 1;
+        for slot in &mut self.publishers {
+            let mut config = outbox_config.clone();
+            if shared {
+                let mut path = config.path.into_os_string();
+                path.push(format!(".{}", slot.stats.name()));
+                config.path = path.into();
+            }
+            info!(
+                "Outbox for {} enabled at {}",
+                slot.stats.name(),
+                config.path.display()
+            );
+            slot.publisher = Arc::new(Outbox::shared(Arc::clone(&slot.publisher), config)?);
+        }
+        Ok(self)
     }
 
     pub fn has_mqtt_config(&self) -> bool {
-        self.message_publisher.is_some()
+        !self.publishers.is_empty()
     }
 
-    pub async fn start(&self) -> Result<()> {
-        let (tx, mut rx) = mpsc::channel::<MqttMessage>(10);
+    /// Circuit breakers for each publisher wrapped by [`Bridge::with_resilience`].
+    pub fn circuit_breakers(&self) -> Vec<Arc<CircuitBreaker>> {
+        self.publishers
+            .iter()
+            .filter_map(|slot| slot.breaker.clone())
+            .collect()
+    }
+
+    /// Delivery counters for each publisher, in the order they were added.
+    pub fn publisher_stats(&self) -> Vec<Arc<PublisherStats>> {
+        self.publishers
+            .iter()
+            .map(|slot| Arc::clone(&slot.stats))
+            .collect()
+    }
+
+    /// Receives every doorbell press the bridge detects while it runs, and every
+    /// dnstap source going down or coming back.
+    ///
+    /// A subscriber that falls more than [`EVENT_BROADCAST_CAPACITY`] events behind
+    /// gets a `Lagged` error and skips ahead.
+    pub fn subscribe(&self) -> broadcast::Receiver<BridgeEvent> {
+        self.events.subscribe()
+    }
 
+    /// Runs until SIGINT or SIGHUP arrives.
+    pub async fn start(&self) -> Result<()> {
         // Watch for interrupts so we can send death message via MQTT.
         let mut hup_signal = signal(SignalKind::hangup()).context("couldn't listen for SIGHUP")?;
 
-        error!("Server ready");
+        self.run_until(async move {
+            tokio::select! {
+                _ = signal::ctrl_c() => {},
+                _ = hup_signal.recv() => {},
+            }
+        })
+        .await
+    }
 
-        if let Some(ref publisher) = self.message_publisher {
-            publisher.send_birth().await?;
+    /// Runs until `shutdown` completes, without installing any signal handlers.
+    ///
+    /// Pass `token.cancelled()` to stop the bridge with a cancellation token.
+    pub async fn run_until<F>(&self, shutdown: F) -> Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        let (tx, mut rx) = mpsc::channel::<MqttMessage>(self.event_queue_size);
+        self.metrics.set_event_queue(&tx);
+        tokio::pin!(shutdown);
+
+        self.metrics.set_publishers(&self.publishers);
+        let sources: Vec<Arc<SourceState>> = self
+            .dns_listeners
+            .iter()
+            .filter_map(|listener| listener.source())
+            .collect();
+        let metrics_server = match self.metrics_listener {
+            Some(addr) => {
+                let listener = TcpListener::bind(addr)
+                    .await
+                    .with_context(|| format!("Cannot listen for metrics on {}", addr))?;
+                info!("Serving metrics on http://{}/metrics", addr);
+                let health = Arc::new(HealthCheck::new(
+                    self.health.clone(),
+                    self.dnstap_listeners,
+                    Arc::clone(&self.metrics),
+                    &self.publishers,
+                    sources.clone(),
+                ));
+                let handler = http::routes(vec![self.metrics.handler(), health.handler()]);
+                Some(tokio::spawn(http::serve(listener, handler)))
+            }
+            None => None,
+        };
+
+        for slot in &self.publishers {
+            let publisher = &slot.publisher;
+            if let Err(e) = publisher.connect().await {
+                error!("Failed to connect {}: {}", publisher.name(), e);
+            } else if let Err(e) = publisher.send_birth().await {
+                error!("Failed to send birth via {}: {}", publisher.name(), e);
+            }
+        }
+        let fanout = FanOut::start(&self.publishers, self.publisher_queue_size);
+        // Sources start out up, so a consumer knows them before any goes down.
+        for source in &sources {
+            self.deliver(&fanout, source.state_message());
         }
 
-        // Start DNS listener in a separate task
-        let dns_listener = self.dns_listener.box_clone();
-        let tx_clone = tx.clone();
-        let dns_task = tokio::spawn(async move { dns_listener.start_listening(tx_clone).await });
+        // Start each DNS listener in a separate, supervised task
+        let (health_tx, mut health_rx) = mpsc::channel::<ListenerHealth>(10);
+        let dns_tasks: Vec<_> = self
+            .dns_listeners
+            .iter()
+            .enumerate()
+            .map(|(index, dns_listener)| {
+                supervisor::supervise(
+                    index,
+                    dns_listener.box_clone(),
+                    tx.clone(),
+                    health_tx.clone(),
+                    self.supervisor.clone(),
+                )
+            })
+            .collect();
+        let mut down_listeners = HashSet::new();
+        let mut failure = None;
+
+        let status = format!(
+            "Forwarding doorbell presses to {} publishers",
+            self.publishers.len()
+        );
+        // Ready only once every dnstap socket accepts connections.
+        let ready = self.metrics.wait_bound(self.dnstap_listeners as u64);
+        tokio::pin!(ready);
+        let mut is_ready = false;
+
+        // Pinging from the event loop itself means a wedged loop stops the pings.
+        let mut watchdog = self.notifier.watchdog_interval().map(tokio::time::interval);
+        let mut source_check = tokio::time::interval(
+            (self.source_timeout / 10).clamp(Duration::from_secs(1), Duration::from_secs(60)),
+        );
 
         loop {
             tokio::select! {
-                _ = signal::ctrl_c() => {
-                    break;
+                _ = async {
+                    match watchdog.as_mut() {
+                        Some(interval) => interval.tick().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    if let Err(e) = self.notifier.watchdog() {
+                        warn!("Cannot ping service manager watchdog: {:#}", e);
+                    }
                 },
-                _ = hup_signal.recv() => {
+                _ = &mut shutdown => {
                     break;
                 },
-                msg = rx.recv() => {
-                    if let Some(message) = msg {
-                        if let Some(ref publisher) = self.message_publisher {
-                            if let Err(e) = publisher.publish(message).await {
-                                error!("Failed to publish message: {}", e);
-                            }
-                        } else {
-                            // Log message if no publisher is configured
-                            match message {
-                                MqttMessage::Publish { topic, payload } => {
-                                    info!(
-                                        "{}: {}",
-                                        topic,
-                                        String::from_utf8_lossy(payload.as_slice())
-                                    );
+                _ = &mut ready, if !is_ready => {
+                    is_ready = true;
+                    info!("Server ready");
+                    if let Err(e) = self.notifier.ready(&status) {
+                        warn!("Cannot notify service manager: {:#}", e);
+                    }
+                },
+                _ = source_check.tick() => {
+                    for source in &sources {
+                        match source.update(self.source_timeout) {
+                            Some(true) => {
+                                warn!(
+                                    "dnstap source {} is down: {} connections, silent for {:?}",
+                                    source.name(),
+                                    source.connections(),
+                                    source.silent_for()
+                                );
+                                let status = format!("dnstap source {} down", source.name());
+                                if let Err(e) = self.notifier.status(&status) {
+                                    warn!("Cannot notify service manager: {:#}", e);
                                 }
+                            },
+                            Some(false) => info!("dnstap source {} is back", source.name()),
+                            None => continue,
+                        }
+                        self.deliver(&fanout, source.state_message());
+                    }
+                },
+                Some(health) = health_rx.recv() => match health {
+                    ListenerHealth::Down { listener, failures, error } => {
+                        error!(
+                            "DNS listener {} is down after {} failures: {}",
+                            listener, failures, error
+                        );
+                        if down_listeners.is_empty() {
+                            self.broadcast_status("offline");
+                        }
+                        down_listeners.insert(listener);
+                        if let Err(e) = self.notifier.status(&format!("DNS listener down: {}", error)) {
+                            warn!("Cannot notify service manager: {:#}", e);
+                        }
+                        if self.supervisor.exit_on_failure {
+                            failure = Some(anyhow!(
+                                "DNS listener {} failed {} times: {}",
+                                listener, failures, error
+                            ));
+                            break;
+                        }
+                    },
+                    ListenerHealth::Up { listener } => {
+                        down_listeners.remove(&listener);
+                        if down_listeners.is_empty() {
+                            self.broadcast_status("online");
+                            if let Err(e) = self.notifier.status(&status) {
+                                warn!("Cannot notify service manager: {:#}", e);
                             }
                         }
+                    },
+                },
+                msg = rx.recv() => {
+                    if let Some(message) = msg {
+                        self.deliver(&fanout, message);
                     }
                 },
             }
         }
 
-        if let Some(ref publisher) = self.message_publisher {
-            publisher.send_death().await?;
+        if let Err(e) = self.notifier.stopping() {
+            warn!("Cannot notify service manager: {:#}", e);
         }
 
-        // Cancel DNS listener task
-        dns_task.abort();
+        fanout.shutdown(self.shutdown.grace).await;
+        for slot in &self.publishers {
+            let (publisher, stats) = (&slot.publisher, &slot.stats);
+            if self.shutdown.send_death {
+                if let Err(e) = publisher.send_death().await {
+                    error!("Failed to send death via {}: {}", publisher.name(), e);
+                }
+            }
+            info!(
+                "{}: {} published, {} failed, {} dropped",
+                stats.name(),
+                stats.published(),
+                stats.failed(),
+                stats.dropped()
+            );
+        }
+
+        // Cancel DNS listener tasks
+        for dns_task in dns_tasks {
+            dns_task.stop().await;
+        }
+        if let Some(metrics_server) = metrics_server {
+            metrics_server.abort();
+        }
 
-        Ok(())
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
     }
 }
@@ -0,0 +1,401 @@
+/*
+ * Copyright 2025 Kenny Root
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use anyhow::{bail, Result};
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    availability::Availability,
+    bridge::{mqtt_publisher, Bridge, ShutdownConfig, DEFAULT_EVENT_QUEUE_SIZE},
+    detect::{default_rules, ClientFilter, DetectionRule, Detector},
+    devices::DeviceMap,
+    dns_service::DnsService,
+    fanout::DEFAULT_QUEUE_SIZE,
+    health::HealthConfig,
+    listener::DnsListener,
+    messaging::MessagePublisher,
+    metrics::Metrics,
+    mqtt::MqttConfig,
+    net::IpCidr,
+    outbox::OutboxConfig,
+    presses::PressCounters,
+    resilience::ResilienceConfig,
+    ringing::Ringing,
+    supervisor::SupervisorConfig,
+    systemd::Notifier,
+};
+
+/// Assembles a [`Bridge`] from listeners, publishers and detection settings.
+///
+/// Detection rules and client filters apply to the dnstap listeners added with
+/// [`BridgeBuilder::dns_socket`] or [`BridgeBuilder::dns_service`]; custom listeners
+/// decide for themselves what counts as a press.
+#[derive(Default)]
+pub struct BridgeBuilder {
+    dns_services: Vec<DnsService>,
+    listeners: Vec<Box<dyn DnsListener>>,
+    publishers: Vec<Box<dyn MessagePublisher>>,
+    rules: Vec<DetectionRule>,
+    filter: ClientFilter,
+    event_queue_size: Option<usize>,
+    publisher_queue_size: Option<usize>,
+    resilience: Option<ResilienceConfig>,
+    outbox: Option<OutboxConfig>,
+    shutdown: ShutdownConfig,
+    supervisor: SupervisorConfig,
+    notifier: Option<Notifier>,
+    metrics_listener: Option<SocketAddr>,
+    health: HealthConfig,
+    device_timeout: Option<Duration>,
+    source_timeout: Option<Duration>,
+    ringing_hold: Option<Duration>,
+    press_counts: Option<PathBuf>,
+    device_map: Option<PathBuf>,
+}
+
+impl BridgeBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Listens for dnstap on a socket path, or `@name` for a Linux abstract socket.
+    pub fn dns_socket(self, path: impl Into<PathBuf>) -> Self {
+        self.dns_service(DnsService::new(path.into()))
+    }
+
+    /// Adds a dnstap listener that is already configured, for example with socket options.
+    pub fn dns_service(mut self, service: DnsService) -> Self {
+        self.dns_services.push(service);
+        self
+    }
+
+    /// Adds a custom source of doorbell messages.
+    pub fn listener(mut self, listener: Box<dyn DnsListener>) -> Self {
+        self.listeners.push(listener);
+        self
+    }
+
+    pub fn mqtt(self, config: MqttConfig) -> Self {
+        self.publisher(mqtt_publisher(&config))
+    }
+
+    pub fn publisher(mut self, publisher: Box<dyn MessagePublisher>) -> Self {
+        self.publishers.push(publisher);
+        self
+    }
+
+    /// Adds a detection rule. Once any rule is added the built-in EZVIZ rules are not used.
+    pub fn rule(mut self, rule: DetectionRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn allow_clients(mut self, cidr: IpCidr) -> Self {
+        self.filter.allow.push(cidr);
+        self
+    }
+
+    pub fn deny_clients(mut self, cidr: IpCidr) -> Self {
+        self.filter.deny.push(cidr);
+        self
+    }
+
+    /// Messages that may wait between the listeners and the publishers.
+    pub fn event_queue_size(mut self, size: usize) -> Self {
+        self.event_queue_size = Some(size);
+        self
+    }
+
+    /// Messages that may wait for each publisher before new ones are dropped.
+    pub fn publisher_queue_size(mut self, size: usize) -> Self {
+        self.publisher_queue_size = Some(size);
+        self
+    }
+
+    pub fn resilience(mut self, config: ResilienceConfig) -> Self {
+        self.resilience = Some(config);
+        self
+    }
+
+    pub fn outbox(mut self, config: OutboxConfig) -> Self {
+        self.outbox = Some(config);
+        self
+    }
+
+    /// How long publishers may drain their queues once the bridge is stopping.
+    pub fn shutdown_grace(mut self, grace: Duration) -> Self {
+        self.shutdown.grace = grace;
+        self
+    }
+
+    /// Whether to send death messages on shutdown; on by default.
+    pub fn send_death(mut self, send_death: bool) -> Self {
+        self.shutdown.send_death = send_death;
+        self
+    }
+
+    /// Sets how failed listeners are restarted and when the bridge gives up on them.
+    pub fn supervisor(mut self, supervisor: SupervisorConfig) -> Self {
+        self.supervisor = supervisor;
+        self
+    }
+
+    pub fn notifier(mut self, notifier: Notifier) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    /// Serves Prometheus metrics at `/metrics` on this address.
+    pub fn metrics_listener(mut self, addr: SocketAddr) -> Self {
+        self.metrics_listener = Some(addr);
+        self
+    }
+
+    /// Sets how long a doorbell may go without any DNS query before it is reported offline.
+    pub fn device_timeout(mut self, timeout: Duration) -> Self {
+        self.device_timeout = Some(timeout);
+        self
+    }
+
+    /// Adds a ringing binary sensor per doorbell that stays on for `hold` after each press.
+    pub fn ringing_hold(mut self, hold: Duration) -> Self {
+        self.ringing_hold = Some(hold);
+        self
+    }
+
+    /// Saves press counts to `path` so they survive restarts.
+    pub fn press_counts(mut self, path: impl Into<PathBuf>) -> Self {
+        self.press_counts = Some(path.into());
+        self
+    }
+
+    /// Reads doorbell names, slugs and areas from the JSON file at `path`.
+    pub fn device_map(mut self, path: impl Into<PathBuf>) -> Self {
+        self.device_map = Some(path.into());
+        self
+    }
+
+    /// Sets how long a dnstap source may send nothing before it is reported down.
+    pub fn source_timeout(mut self, timeout: Duration) -> Self {
+        self.source_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets when the health check served next to the metrics fails.
+    pub fn health(mut self, health: HealthConfig) -> Self {
+        self.health = health;
+        self
+    }
+
+    pub fn build(self) -> Result<Bridge> {
+        if self.dns_services.is_empty() && self.listeners.is_empty() {
+            bail!("At least one DNS listener is required");
+        }
+        if self.dns_services.is_empty()
+            && (!self.rules.is_empty() || self.filter != ClientFilter::default())
+        {
+            bail!("Detection rules and client filters need a dnstap listener");
+        }
+
+        let event_queue_size = self.event_queue_size.unwrap_or(DEFAULT_EVENT_QUEUE_SIZE);
+        let publisher_queue_size = self.publisher_queue_size.unwrap_or(DEFAULT_QUEUE_SIZE);
+        if event_queue_size == 0 || publisher_queue_size == 0 {
+            bail!("Queue sizes must be at least 1");
+        }
+
+        if let Some(ref resilience) = self.resilience {
+            if resilience.timeout.is_zero() {
+                bail!("Publisher timeout must be greater than zero");
+            }
+            if resilience.failure_threshold == 0 {
+                bail!("Circuit breaker failure threshold must be at least 1");
+            }
+        }
+        if self.supervisor.failure_threshold == 0 {
+            bail!("Listener failure threshold must be at least 1");
+        }
+        if self.device_timeout.is_some_and(|timeout| timeout.is_zero()) {
+            bail!("Device timeout must be greater than zero");
+        }
+        if self.ringing_hold.is_some_and(|hold| hold.is_zero()) {
+            bail!("Ringing hold time must be greater than zero");
+        }
+        if self.source_timeout.is_some_and(|timeout| timeout.is_zero()) {
+            bail!("DNS source timeout must be greater than zero");
+        }
+        if self.outbox.is_some() && self.publishers.is_empty() {
+            bail!("An outbox needs at least one publisher");
+        }
+
+        let rules = if self.rules.is_empty() {
+            default_rules()
+        } else {
+            self.rules
+        };
+        let detector = Arc::new(Detector::new(rules, self.filter)?);
+        let metrics = Arc::new(Metrics::default());
+        let presses = Arc::new(match self.press_counts {
+            Some(path) => PressCounters::open(path)?,
+            None => PressCounters::default(),
+        });
+        let devices = Arc::new(match self.device_map {
+            Some(path) => DeviceMap::load(&path)?,
+            None => DeviceMap::default(),
+        });
+        let availability = Arc::new(match self.device_timeout {
+            Some(timeout) => Availability::new(timeout),
+            None => Availability::default(),
+        });
+        // Doorbells known without a press this run can still be reported offline.
+        let known = devices
+            .addresses()
+            .into_iter()
+            .chain(presses.addresses())
+            .chain(detector.known_clients().iter().map(IpAddr::to_string))
+            .collect::<Vec<_>>();
+        for address in known {
+            availability.watch(devices.resolve(&address));
+        }
+        let doorbells = Arc::default();
+        let ringing = self.ringing_hold.map(|hold| Arc::new(Ringing::new(hold)));
+
+        let mut listeners: Vec<Box<dyn DnsListener>> = self
+            .dns_services
+            .into_iter()
+            .map(|service| {
+                let mut service = service
+                    .with_detector(Arc::clone(&detector))
+                    .with_metrics(Arc::clone(&metrics))
+                    .with_press_counters(Arc::clone(&presses))
+                    .with_doorbells(Arc::clone(&doorbells))
+                    .with_availability(Arc::clone(&availability))
+                    .with_devices(Arc::clone(&devices));
+                if let Some(ringing) = &ringing {
+                    service = service.with_ringing(Arc::clone(ringing));
+                }
+                Box::new(service) as Box<dyn DnsListener>
+            })
+            .collect();
+        listeners.extend(self.listeners);
+
+        let mut bridge = Bridge::assemble(listeners, self.publishers)
+            .with_queue_sizes(event_queue_size, publisher_queue_size)
+            .with_shutdown(self.shutdown)
+            .with_supervisor(self.supervisor)
+            .with_metrics(metrics)
+            .with_health(self.health);
+        if let Some(timeout) = self.source_timeout {
+            bridge = bridge.with_source_timeout(timeout);
+        }
+        if let Some(addr) = self.metrics_listener {
+            bridge = bridge.with_metrics_listener(addr);
+        }
+        if let Some(notifier) = self.notifier {
+            bridge = bridge.with_notifier(notifier);
+        }
+        if let Some(resilience) = self.resilience {
+            bridge = bridge.with_resilience(resilience);
+        }
+        if let Some(outbox) = self.outbox {
+            bridge = bridge.with_outbox(outbox)?;
+        }
+        Ok(bridge)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json_lines::{JsonLinesService, JsonLinesTarget};
+
+    fn json_publisher() -> Box<dyn MessagePublisher> {
+        Box::new(JsonLinesService::new(JsonLinesTarget::Stdout).unwrap())
+    }
+
+    #[test]
+    fn test_builds_with_defaults() {
+        let bridge = BridgeBuilder::new()
+            .dns_socket("/run/ring-detector/dns.sock")
+            .publisher(json_publisher())
+            .build()
+            .unwrap();
+        assert_eq!(bridge.publisher_stats()[0].name(), "json");
+        assert!(bridge.circuit_breakers().is_empty());
+    }
+
+    #[test]
+    fn test_requires_listener() {
+        let error = BridgeBuilder::new()
+            .publisher(json_publisher())
+            .build()
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("DNS listener"));
+    }
+
+    #[test]
+    fn test_rejects_invalid_settings() {
+        let builder = || BridgeBuilder::new().dns_socket("@ring-detector");
+
+        assert!(builder().event_queue_size(0).build().is_err());
+        assert!(builder().publisher_queue_size(0).build().is_err());
+        assert!(builder()
+            .outbox(OutboxConfig::new(PathBuf::from("/tmp/outbox")))
+            .build()
+            .is_err());
+        assert!(builder()
+            .rule(DetectionRule::new("doorbell", "a.example"))
+            .rule(DetectionRule::new("doorbell", "b.example"))
+            .build()
+            .is_err());
+        assert!(builder()
+            .resilience(ResilienceConfig {
+                failure_threshold: 0,
+                ..Default::default()
+            })
+            .build()
+            .is_err());
+        assert!(builder()
+            .supervisor(SupervisorConfig {
+                failure_threshold: 0,
+                ..Default::default()
+            })
+            .build()
+            .is_err());
+        assert!(builder().device_timeout(Duration::ZERO).build().is_err());
+        assert!(builder().source_timeout(Duration::ZERO).build().is_err());
+        assert!(builder().ringing_hold(Duration::ZERO).build().is_err());
+        assert!(builder()
+            .device_map("/nonexistent/devices.json")
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_wraps_publishers_in_resilience() {
+        let bridge = BridgeBuilder::new()
+            .dns_socket("@ring-detector")
+            .publisher(json_publisher())
+            .resilience(ResilienceConfig::default())
+            .build()
+            .unwrap();
+        assert_eq!(bridge.circuit_breakers().len(), 1);
+    }
+}
@@ -0,0 +1,247 @@
+/*
+ * Copyright 2025 Kenny Root
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::{dns_message::normalize_name, net::IpCidr};
+use anyhow::{bail, Result};
+use std::{collections::HashSet, net::IpAddr};
+
+pub const QTYPE_A: u16 = 1;
+pub const QTYPE_AAAA: u16 = 28;
+pub const QTYPE_SVCB: u16 = 64;
+pub const QTYPE_HTTPS: u16 = 65;
+pub const QTYPE_ANY: u16 = 255;
+
+/// A DNS query that means a doorbell button was pressed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DetectionRule {
+    pub name: String,
+    /// Name to match, such as `alarm.eu.s3.amazonaws.com`; case, a trailing dot and
+    /// Unicode or punycode spellings do not matter.
+    pub qname: String,
+    /// Query types that count as a press; empty matches every type.
+    pub qtypes: Vec<u16>,
+}
+
+impl DetectionRule {
+    /// Creates a rule matching A and AAAA queries for `qname`.
+    pub fn new(name: impl Into<String>, qname: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            qname: qname.into(),
+            qtypes: vec![QTYPE_A, QTYPE_AAAA],
+        }
+    }
+
+    pub fn with_qtypes(mut self, qtypes: Vec<u16>) -> Self {
+        self.qtypes = qtypes;
+        self
+    }
+
+    pub fn matches(&self, qname: &str, qtype: u16) -> bool {
+        normalize_name(qname) == normalize_name(&self.qname) && self.matches_qtype(qtype)
+    }
+
+    fn matches_qtype(&self, qtype: u16) -> bool {
+        self.qtypes.is_empty() || self.qtypes.contains(&qtype)
+    }
+}
+
+/// The EZVIZ doorbell uploads a snapshot to one of these hosts on every press.
+pub fn default_rules() -> Vec<DetectionRule> {
+    vec![
+        DetectionRule::new("ezviz-eu", "alarm.eu.s3.amazonaws.com"),
+        DetectionRule::new("ezviz-us", "alarm.use.s3.amazonaws.com"),
+    ]
+}
+
+/// Restricts which DNS clients may be reported as doorbells.
+///
+/// Denied blocks win over allowed ones; with no allowed blocks every client not
+/// denied is accepted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientFilter {
+    pub allow: Vec<IpCidr>,
+    pub deny: Vec<IpCidr>,
+}
+
+impl ClientFilter {
+    pub fn allows(&self, client: &IpAddr) -> bool {
+        if self.deny.iter().any(|net| net.contains(client)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(client))
+    }
+}
+
+/// Decides which queries from which clients are doorbell presses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Detector {
+    rules: Vec<DetectionRule>,
+    filter: ClientFilter,
+}
+
+impl Default for Detector {
+    fn default() -> Self {
+        Self {
+            rules: default_rules(),
+            filter: ClientFilter::default(),
+        }
+    }
+}
+
+impl Detector {
+    /// Validates the rules and normalizes their names.
+    pub fn new(mut rules: Vec<DetectionRule>, filter: ClientFilter) -> Result<Self> {
+        if rules.is_empty() {
+            bail!("At least one detection rule is required");
+        }
+        let mut names = HashSet::new();
+        for rule in &mut rules {
+            rule.qname = normalize_name(&rule.qname);
+            if rule.qname.is_empty() {
+                bail!("Detection rule {:?} has an empty qname", rule.name);
+            }
+            if !names.insert(rule.name.as_str()) {
+                bail!("Duplicate detection rule {:?}", rule.name);
+            }
+        }
+        Ok(Self { rules, filter })
+    }
+
+    pub fn rules(&self) -> &[DetectionRule] {
+        &self.rules
+    }
+
+    /// Clients allowed one address at a time, which are doorbells by configuration.
+    pub fn known_clients(&self) -> Vec<IpAddr> {
+        self.filter
+            .allow
+            .iter()
+            .filter_map(IpCidr::host)
+            .filter(|client| self.filter.allows(client))
+            .collect()
+    }
+
+    /// Returns the rule a query from `client` matches, if the client is not filtered out.
+    pub fn detect(&self, client: &IpAddr, qname: &str, qtype: u16) -> Option<&DetectionRule> {
+        if !self.filter.allows(client) {
+            return None;
+        }
+        let qname = normalize_name(qname);
+        self.rules
+            .iter()
+            .find(|rule| rule.qname == qname && rule.matches_qtype(qtype))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn test_default_rules() {
+        let detector = Detector::default();
+        let client = ip("192.168.1.100");
+
+        let rule = detector.detect(&client, "alarm.use.s3.amazonaws.com", QTYPE_A);
+        assert_eq!(rule.unwrap().name, "ezviz-us");
+        assert!(detector
+            .detect(&client, "Alarm.EU.s3.amazonaws.com.", QTYPE_AAAA)
+            .is_some());
+        assert!(detector
+            .detect(&client, "alarm.use.s3.amazonaws.com", 16)
+            .is_none());
+        assert!(detector.detect(&client, "example.com", QTYPE_A).is_none());
+    }
+
+    #[test]
+    fn test_normalized_rules() {
+        let rules = vec![
+            DetectionRule::new("upper", "Doorbell.Example."),
+            DetectionRule::new("idn", "türklingel.example"),
+        ];
+        let detector = Detector::new(rules, ClientFilter::default()).unwrap();
+        let client = ip("192.168.1.100");
+
+        assert_eq!(detector.rules()[0].qname, "doorbell.example");
+        let rule = detector.detect(&client, "dOoRbElL.eXaMpLe", QTYPE_A);
+        assert_eq!(rule.unwrap().name, "upper");
+        let rule = detector.detect(&client, "xn--trklingel-q9a.example.", QTYPE_A);
+        assert_eq!(rule.unwrap().name, "idn");
+    }
+
+    #[test]
+    fn test_rule_with_any_qtype() {
+        let rule = DetectionRule::new("any", "doorbell.example").with_qtypes(vec![]);
+        assert!(rule.matches("doorbell.example", QTYPE_HTTPS));
+        assert!(rule.matches("doorbell.example", 4242));
+
+        let rule = DetectionRule::new("svcb", "doorbell.example").with_qtypes(vec![
+            QTYPE_SVCB,
+            QTYPE_HTTPS,
+            QTYPE_ANY,
+        ]);
+        assert!(rule.matches("doorbell.example", QTYPE_ANY));
+        assert!(!rule.matches("doorbell.example", QTYPE_A));
+    }
+
+    #[test]
+    fn test_client_filter() {
+        let filter = ClientFilter {
+            allow: vec!["192.168.1.0/24".parse().unwrap()],
+            deny: vec!["192.168.1.13".parse().unwrap()],
+        };
+        let detector = Detector::new(default_rules(), filter).unwrap();
+
+        let qname = "alarm.use.s3.amazonaws.com";
+        assert!(detector
+            .detect(&ip("192.168.1.12"), qname, QTYPE_A)
+            .is_some());
+        assert!(detector
+            .detect(&ip("192.168.1.13"), qname, QTYPE_A)
+            .is_none());
+        assert!(detector.detect(&ip("10.0.0.1"), qname, QTYPE_A).is_none());
+        assert!(detector.known_clients().is_empty());
+
+        let filter = ClientFilter {
+            allow: vec![
+                "192.168.1.12".parse().unwrap(),
+                "192.168.1.13".parse().unwrap(),
+                "10.0.0.0/8".parse().unwrap(),
+            ],
+            deny: vec!["192.168.1.13".parse().unwrap()],
+        };
+        let detector = Detector::new(default_rules(), filter).unwrap();
+        assert_eq!(detector.known_clients(), vec![ip("192.168.1.12")]);
+    }
+
+    #[test]
+    fn test_validation() {
+        assert!(Detector::new(vec![], ClientFilter::default()).is_err());
+
+        let duplicate = vec![
+            DetectionRule::new("doorbell", "a.example"),
+            DetectionRule::new("doorbell", "b.example"),
+        ];
+        assert!(Detector::new(duplicate, ClientFilter::default()).is_err());
+
+        let empty = vec![DetectionRule::new("doorbell", ".")];
+        assert!(Detector::new(empty, ClientFilter::default()).is_err());
+    }
+}
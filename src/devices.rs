@@ -0,0 +1,478 @@
+/*
+ * Copyright 2025 Kenny Root
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use anyhow::{bail, Context, Result};
+use log::info;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::net::IpCidr;
+
+/// Where Linux lists IPv4 neighbours with their hardware addresses.
+const ARP_TABLE: &str = "/proc/net/arp";
+
+/// How long a neighbour table lookup is reused before the table is read again.
+const ARP_TTL: Duration = Duration::from_secs(60);
+
+/// A doorbell as it is named and placed in Home Assistant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Device {
+    /// Client address the doorbell queries from.
+    pub address: String,
+    /// What the doorbell is known by when its address changes: its MAC if a device
+    /// map entry matched one, otherwise its address.
+    pub key: String,
+    /// Topic segment after `ringdet-`; the key unless configured.
+    pub slug: String,
+    pub name: String,
+    pub area: Option<String>,
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+}
+
+impl Device {
+    /// A doorbell with nothing configured, named after its address.
+    pub fn new(address: impl Into<String>) -> Self {
+        let address = address.into();
+        Self {
+            slug: address.clone(),
+            key: address.clone(),
+            name: format!("Doorbell {}", address),
+            address,
+            area: None,
+            manufacturer: None,
+            model: None,
+        }
+    }
+
+    /// Unique ID stem and discovery topic segment.
+    ///
+    /// Built from the key rather than the name or slug, so renaming a doorbell
+    /// updates its Home Assistant entities instead of leaving the old ones behind.
+    pub fn id(&self) -> String {
+        format!("ringdet-{}", self.key)
+    }
+
+    /// Topic suffix for one of the doorbell's states, `ringdet-<slug>/<leaf>`.
+    pub fn topic(&self, leaf: &str) -> String {
+        format!("ringdet-{}/{}", self.slug, leaf)
+    }
+}
+
+/// What a device map entry matches on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Client {
+    Cidr(IpCidr),
+    Mac([u8; 6]),
+}
+
+impl Client {
+    fn parse(value: &str) -> Result<Self> {
+        if let Some(mac) = parse_mac(value) {
+            return Ok(Self::Mac(mac));
+        }
+        let cidr = value
+            .parse()
+            .with_context(|| format!("Client {} is not an address, CIDR block or MAC", value))?;
+        Ok(Self::Cidr(cidr))
+    }
+
+    /// Whether the entry can only ever match one doorbell.
+    fn is_single(&self) -> bool {
+        match self {
+            Self::Cidr(cidr) => cidr.is_host(),
+            Self::Mac(_) => true,
+        }
+    }
+}
+
+fn parse_mac(value: &str) -> Option<[u8; 6]> {
+    let mut mac = [0; 6];
+    let mut octets = value.split([':', '-']);
+    for octet in mac.iter_mut() {
+        let hex = octets.next()?;
+        if hex.len() != 2 {
+            return None;
+        }
+        *octet = u8::from_str_radix(hex, 16).ok()?;
+    }
+    octets.next().is_none().then_some(mac)
+}
+
+/// A MAC as a device key, such as `a4cf12000001`.
+fn mac_key(mac: &[u8; 6]) -> String {
+    mac.iter().map(|octet| format!("{:02x}", octet)).collect()
+}
+
+/// A neighbour table lookup and when it was made.
+#[derive(Debug, Clone, Copy)]
+struct Neighbour {
+    read: Instant,
+    mac: Option<[u8; 6]>,
+}
+
+/// One entry of a device map file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EntryConfig {
+    client: String,
+    name: Option<String>,
+    slug: Option<String>,
+    area: Option<String>,
+    manufacturer: Option<String>,
+    model: Option<String>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    client: Client,
+    name: Option<String>,
+    slug: Option<String>,
+    area: Option<String>,
+    manufacturer: Option<String>,
+    model: Option<String>,
+}
+
+impl Entry {
+    fn matches(&self, ip: &IpAddr, mac: &mut impl FnMut() -> Option<[u8; 6]>) -> bool {
+        match &self.client {
+            Client::Cidr(cidr) => cidr.contains(ip),
+            Client::Mac(wanted) => mac() == Some(*wanted),
+        }
+    }
+}
+
+fn valid_slug(slug: &str) -> bool {
+    !slug.is_empty()
+        && slug
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Names, topic slugs, areas and hardware details for doorbells, keyed by client
+/// address, CIDR block or MAC.
+///
+/// Every field comes from the first matching entry that sets it, so a block can
+/// give a whole subnet an area while single addresses add names. Names and slugs
+/// may only be given to entries that match one doorbell. dnstap only carries IP
+/// addresses, so MAC entries are matched through the kernel's neighbour table and
+/// only work for IPv4 doorbells on a network the bridge is attached to.
+#[derive(Debug)]
+pub struct DeviceMap {
+    entries: Vec<Entry>,
+    arp_table: PathBuf,
+    arp_ttl: Duration,
+    /// Recent neighbour table lookups, so queries do not each read the table.
+    neighbours: Mutex<HashMap<IpAddr, Neighbour>>,
+    /// Doorbells last matched by MAC, for when the neighbour table forgets them.
+    by_mac: Mutex<HashMap<String, Device>>,
+}
+
+impl Default for DeviceMap {
+    fn default() -> Self {
+        Self {
+            entries: vec![],
+            arp_table: PathBuf::from(ARP_TABLE),
+            arp_ttl: ARP_TTL,
+            neighbours: Mutex::new(HashMap::new()),
+            by_mac: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl DeviceMap {
+    /// Reads a JSON array of entries such as
+    /// `{"client": "192.168.1.37", "name": "Front door", "slug": "front_door"}`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let data =
+            fs::read(path).with_context(|| format!("Cannot read device map {}", path.display()))?;
+        let map = Self::from_json(&data)
+            .with_context(|| format!("Invalid device map {}", path.display()))?;
+        info!(
+            "Loaded {} device map entries from {}",
+            map.entries.len(),
+            path.display()
+        );
+        Ok(map)
+    }
+
+    pub fn from_json(data: &[u8]) -> Result<Self> {
+        let configs: Vec<EntryConfig> = serde_json::from_slice(data)?;
+        let mut slugs = HashSet::new();
+        let mut entries = vec![];
+        for config in configs {
+            let client = Client::parse(&config.client)?;
+            if (config.name.is_some() || config.slug.is_some()) && !client.is_single() {
+                bail!(
+                    "{} matches more than one doorbell, so it cannot set a name or slug",
+                    config.client
+                );
+            }
+            if config.name.as_ref().is_some_and(|name| name.is_empty()) {
+                bail!("{} has an empty name", config.client);
+            }
+            if let Some(slug) = &config.slug {
+                if !valid_slug(slug) {
+                    bail!(
+                        "Slug {:?} may only use letters, digits, '-', '_' and '.'",
+                        slug
+                    );
+                }
+                if !slugs.insert(slug.clone()) {
+                    bail!("Slug {:?} is used more than once", slug);
+                }
+            }
+            entries.push(Entry {
+                client,
+                name: config.name,
+                slug: config.slug,
+                area: config.area,
+                manufacturer: config.manufacturer,
+                model: config.model,
+            });
+        }
+        Ok(Self {
+            entries,
+            ..Default::default()
+        })
+    }
+
+    #[cfg(test)]
+    fn with_arp_table(mut self, path: PathBuf) -> Self {
+        self.arp_table = path;
+        self
+    }
+
+    #[cfg(test)]
+    fn with_arp_ttl(mut self, ttl: Duration) -> Self {
+        self.arp_ttl = ttl;
+        self
+    }
+
+    /// Looks up the doorbell querying from `address`.
+    pub fn resolve(&self, address: &str) -> Device {
+        let mut device = Device::new(address);
+        let Ok(ip) = address.parse::<IpAddr>() else {
+            return device;
+        };
+
+        let mut neighbour = None;
+        let mut mac = || *neighbour.get_or_insert_with(|| self.neighbour_mac(&ip));
+        let mut by_mac = false;
+        let (mut name, mut slug) = (None, None);
+        for entry in self.entries.iter().filter(|e| e.matches(&ip, &mut mac)) {
+            if let Client::Mac(mac) = &entry.client {
+                if !by_mac {
+                    device.key = mac_key(mac);
+                }
+                by_mac = true;
+            }
+            name = name.or_else(|| entry.name.clone());
+            slug = slug.or_else(|| entry.slug.clone());
+            device.area = device.area.or_else(|| entry.area.clone());
+            device.manufacturer = device.manufacturer.or_else(|| entry.manufacturer.clone());
+            device.model = device.model.or_else(|| entry.model.clone());
+        }
+        device.name = name.unwrap_or(device.name);
+        device.slug = slug.unwrap_or_else(|| device.key.clone());
+
+        let mut known = self.by_mac.lock().unwrap();
+        if by_mac {
+            known.insert(address.to_string(), device.clone());
+        } else if neighbour == Some(None) {
+            // The neighbour table drops quiet hosts, so keep the last MAC match.
+            if let Some(known) = known.get(address) {
+                return known.clone();
+            }
+        }
+        device
+    }
+
+    /// Addresses of the doorbells entries name one at a time. MAC entries are
+    /// only found while the neighbour table lists them.
+    pub fn addresses(&self) -> Vec<String> {
+        self.entries
+            .iter()
+            .filter_map(|entry| match entry.client {
+                Client::Cidr(cidr) => cidr.host(),
+                Client::Mac(mac) => self.neighbour_ip(&mac),
+            })
+            .map(|ip| ip.to_string())
+            .collect()
+    }
+
+    /// Finds the IPv4 address of a neighbour with hardware address `mac`.
+    fn neighbour_ip(&self, mac: &[u8; 6]) -> Option<IpAddr> {
+        let table = fs::read_to_string(&self.arp_table).ok()?;
+        table.lines().skip(1).find_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let complete = fields.get(2).is_some_and(|flags| *flags != "0x0");
+            (complete && fields.get(3).and_then(|hw| parse_mac(hw)) == Some(*mac))
+                .then(|| fields.first()?.parse().ok())
+                .flatten()
+        })
+    }
+
+    /// Finds the hardware address of a neighbour, reading the kernel's ARP table
+    /// at most once per TTL for each address.
+    fn neighbour_mac(&self, ip: &IpAddr) -> Option<[u8; 6]> {
+        let now = Instant::now();
+        let mut neighbours = self.neighbours.lock().unwrap();
+        neighbours.retain(|_, neighbour| now.duration_since(neighbour.read) < self.arp_ttl);
+        if let Some(neighbour) = neighbours.get(ip) {
+            return neighbour.mac;
+        }
+        let mac = self.read_neighbour_mac(ip);
+        neighbours.insert(*ip, Neighbour { read: now, mac });
+        mac
+    }
+
+    /// Finds the hardware address of a neighbour in the kernel's ARP table.
+    fn read_neighbour_mac(&self, ip: &IpAddr) -> Option<[u8; 6]> {
+        let table = fs::read_to_string(&self.arp_table).ok()?;
+        let ip = ip.to_canonical().to_string();
+        table.lines().skip(1).find_map(|line| {
+            // IP address, HW type, flags, HW address, mask, device
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let complete = fields.get(2).is_some_and(|flags| *flags != "0x0");
+            (fields.first() == Some(&ip.as_str()) && complete)
+                .then(|| parse_mac(fields.get(3)?))
+                .flatten()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(json: &str) -> Result<DeviceMap> {
+        DeviceMap::from_json(json.as_bytes())
+    }
+
+    #[test]
+    fn test_first_match_sets_each_field() {
+        let devices = map(r#"[
+            {"client": "192.168.1.37", "name": "Front door", "slug": "front_door"},
+            {"client": "192.168.1.0/24", "area": "Entrance", "manufacturer": "EZVIZ"},
+            {"client": "0.0.0.0/0", "area": "Outside", "model": "DB1C"}
+        ]"#)
+        .unwrap();
+
+        let front = devices.resolve("192.168.1.37");
+        assert_eq!(front.name, "Front door");
+        assert_eq!(front.id(), "ringdet-192.168.1.37");
+        assert_eq!(front.topic("action"), "ringdet-front_door/action");
+        assert_eq!(front.area.as_deref(), Some("Entrance"));
+        assert_eq!(front.manufacturer.as_deref(), Some("EZVIZ"));
+        assert_eq!(front.model.as_deref(), Some("DB1C"));
+
+        assert_eq!(devices.addresses(), vec!["192.168.1.37".to_string()]);
+
+        let other = devices.resolve("192.168.1.38");
+        assert_eq!(other.name, "Doorbell 192.168.1.38");
+        assert_eq!(other.topic("action"), "ringdet-192.168.1.38/action");
+        assert_eq!(other.area.as_deref(), Some("Entrance"));
+
+        assert_eq!(devices.resolve("fd00::1"), Device::new("fd00::1"));
+    }
+
+    #[test]
+    fn test_matches_mac_through_neighbour_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let arp_table = dir.path().join("arp");
+        fs::write(
+            &arp_table,
+            "IP address       HW type     Flags       HW address            Mask     Device\n\
+             192.168.1.40     0x1         0x2         a4:cf:12:00:00:01     *        eth0\n\
+             192.168.1.41     0x1         0x0         00:00:00:00:00:00     *        eth0\n",
+        )
+        .unwrap();
+        let devices = map(r#"[{"client": "A4-CF-12-00-00-01", "name": "Back door"}]"#)
+            .unwrap()
+            .with_arp_table(arp_table.clone())
+            .with_arp_ttl(Duration::ZERO);
+
+        assert_eq!(devices.addresses(), vec!["192.168.1.40".to_string()]);
+        let back = devices.resolve("192.168.1.40");
+        assert_eq!(back.name, "Back door");
+        assert_eq!(back.id(), "ringdet-a4cf12000001");
+        assert_eq!(back.topic("action"), "ringdet-a4cf12000001/action");
+        assert_eq!(
+            devices.resolve("192.168.1.41").name,
+            "Doorbell 192.168.1.41"
+        );
+
+        fs::write(
+            &arp_table,
+            "IP address HW type Flags HW address Mask Device\n",
+        )
+        .unwrap();
+        assert_eq!(
+            devices.resolve("192.168.1.40").name,
+            "Back door",
+            "a forgotten neighbour keeps its last match"
+        );
+    }
+
+    #[test]
+    fn test_reuses_neighbour_lookups() {
+        let dir = tempfile::tempdir().unwrap();
+        let arp_table = dir.path().join("arp");
+        let devices = map(r#"[{"client": "a4:cf:12:00:00:01", "name": "Back door"}]"#)
+            .unwrap()
+            .with_arp_table(arp_table.clone());
+        assert_eq!(
+            devices.resolve("192.168.1.40").name,
+            "Doorbell 192.168.1.40"
+        );
+
+        fs::write(
+            &arp_table,
+            "IP address       HW type     Flags       HW address            Mask     Device
+             192.168.1.40     0x1         0x2         a4:cf:12:00:00:01     *        eth0
+",
+        )
+        .unwrap();
+        assert_eq!(
+            devices.resolve("192.168.1.40").name,
+            "Doorbell 192.168.1.40",
+            "the table is not read again within the TTL"
+        );
+
+        let devices = devices.with_arp_ttl(Duration::ZERO);
+        assert_eq!(devices.resolve("192.168.1.40").name, "Back door");
+    }
+
+    #[test]
+    fn test_rejects_invalid_entries() {
+        assert!(map(r#"[{"client": "doorbell"}]"#).is_err());
+        assert!(map(r#"[{"client": "192.168.1.0/24", "name": "Doors"}]"#).is_err());
+        assert!(map(r#"[{"client": "192.168.1.37", "slug": "front/door"}]"#).is_err());
+        assert!(map(r#"[{"client": "192.168.1.37", "name": ""}]"#).is_err());
+        assert!(map(r#"[{"client": "192.168.1.37", "colour": "red"}]"#).is_err());
+        assert!(map(
+            r#"[{"client": "192.168.1.37", "slug": "door"}, {"client": "192.168.1.38", "slug": "door"}]"#
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,129 @@
+/*
+ * Copyright 2025 Kenny Root
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use serde_json::{json, Value};
+
+use crate::{availability::availability_topic, devices::Device, mqtt::MqttMessage};
+
+/// Builds a Home Assistant discovery message. Topics in `config` are relative to
+/// `~`, which MQTT publishers set to their topic prefix.
+fn config_payload(device: &Device, mut config: Value) -> Vec<u8> {
+    let fields = config
+        .as_object_mut()
+        .expect("discovery config is an object");
+    fields.insert("~".to_string(), "".into());
+    fields.insert(
+        "availability".to_string(),
+        json!([
+            { "topic": "~/status" },
+            { "topic": format!("~/{}", availability_topic(device)) },
+        ]),
+    );
+    fields.insert("availability_mode".to_string(), "all".into());
+    let mut info = json!({
+        "identifiers": [device.id()],
+        "name": device.name,
+    });
+    for (key, value) in [
+        ("suggested_area", &device.area),
+        ("manufacturer", &device.manufacturer),
+        ("model", &device.model),
+    ] {
+        if let Some(value) = value {
+            info[key] = value.as_str().into();
+        }
+    }
+    fields.insert("device".to_string(), info);
+    config.to_string().into_bytes()
+}
+
+/// Discovery for the doorbell itself, published under the topic prefix.
+///
+/// The topic and IDs do not change when the doorbell is renamed, so republishing
+/// updates the existing device in Home Assistant.
+pub fn doorbell_config(device: &Device) -> MqttMessage {
+    MqttMessage::Publish {
+        topic: format!("{}/config", device.id()),
+        payload: config_payload(device, json!({ "unique_id": device.id() })),
+    }
+}
+
+/// Discovery for another entity of the doorbell, such as a sensor of its presses.
+pub fn entity_config(device: &Device, component: &str, object: &str, config: Value) -> MqttMessage {
+    let unique_id = format!("{}-{}", device.id(), object);
+    let mut config = config;
+    config["unique_id"] = unique_id.clone().into();
+    MqttMessage::Discovery {
+        component: component.to_string(),
+        topic: format!("{}/config", unique_id),
+        payload: config_payload(device, config),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(message: &MqttMessage) -> Value {
+        serde_json::from_slice(message.payload()).unwrap()
+    }
+
+    #[test]
+    fn test_entity_config() {
+        let message = entity_config(
+            &Device::new("192.168.1.100"),
+            "binary_sensor",
+            "ringing",
+            json!({ "name": "Ringing" }),
+        );
+        assert_eq!(message.component(), Some("binary_sensor"));
+        assert_eq!(message.topic(), "ringdet-192.168.1.100-ringing/config");
+
+        let config = payload(&message);
+        assert_eq!(config["name"], "Ringing");
+        assert_eq!(config["unique_id"], "ringdet-192.168.1.100-ringing");
+        assert_eq!(config["~"], "");
+        assert_eq!(
+            config["availability"][1]["topic"],
+            "~/ringdet-192.168.1.100/availability"
+        );
+        assert_eq!(config["device"]["identifiers"][0], "ringdet-192.168.1.100");
+        assert!(config["device"].get("suggested_area").is_none());
+    }
+
+    #[test]
+    fn test_renamed_doorbell_keeps_ids() {
+        let mut device = Device::new("192.168.1.37");
+        let before = doorbell_config(&device);
+
+        device.name = "Front door".to_string();
+        device.slug = "front_door".to_string();
+        device.area = Some("Entrance".to_string());
+        device.model = Some("DB1C".to_string());
+        let after = doorbell_config(&device);
+        assert_eq!(after.topic(), before.topic());
+
+        let config = payload(&after);
+        assert_eq!(config["unique_id"], payload(&before)["unique_id"]);
+        assert_eq!(config["device"]["identifiers"][0], "ringdet-192.168.1.37");
+        assert_eq!(config["device"]["name"], "Front door");
+        assert_eq!(config["device"]["suggested_area"], "Entrance");
+        assert_eq!(config["device"]["model"], "DB1C");
+        assert_eq!(
+            config["availability"][1]["topic"],
+            "~/ringdet-front_door/availability"
+        );
+    }
+}
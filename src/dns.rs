@@ -14,32 +14,67 @@
  * limitations under the License.
  */
 
-use super::{dnstap::Dnstap, mqtt::MqttMessage, net::parse_octets};
-use anyhow::{anyhow, Context, Result};
-use dns_parser::Packet as DnsPacket;
+use super::{
+    availability::Availability,
+    detect::Detector,
+    devices::{Device, DeviceMap},
+    discovery::doorbell_config,
+    dns_message::{normalize_name, rcode_name, Message as DnsMessage},
+    dnstap::Dnstap,
+    event::DoorbellEvent,
+    metrics::{DecodeFailure, Metrics},
+    mqtt::MqttMessage,
+    net::parse_octets,
+    presses::{press_configs, PressCounters},
+    ringing::{ringing_config, Ringing},
+    source::SourceState,
+};
+use anyhow::{Context, Result};
 use fstrm::reader;
 use log::{debug, info};
 use prost::{bytes::BytesMut, Message};
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     io::Read,
     net::IpAddr,
     os::unix::net::UnixStream,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
     time::Duration,
 };
 use tokio::{sync::mpsc::Sender, time::Instant};
 
-const HOST_EU: &str = "alarm.eu.s3.amazonaws.com";
-const HOST_US: &str = "alarm.use.s3.amazonaws.com";
-
 const IGNORE_DURATION: Duration = Duration::from_secs(1);
 
+/// How long a reported query is remembered, so its response is not reported again.
+const DUPLICATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// How long a press seen in a query waits for its response and response code
+/// once the resolver is known to log responses as well.
+const RESPONSE_WAIT: Duration = Duration::from_secs(1);
+
+/// Identifies a query and its response: client address, client port and DNS ID.
+type QueryKey = (IpAddr, Option<u32>, u16);
+
 pub struct DnsSocket {
     stream: UnixStream,
     sender: Sender<MqttMessage>,
     start_time: Instant,
     doorbells: Arc<Mutex<HashSet<String>>>,
+    detector: Arc<Detector>,
+    reported: Mutex<HashMap<QueryKey, Instant>>,
+    /// Set once the stream carries responses, so queries can wait for theirs.
+    responses_logged: AtomicBool,
+    /// Press messages held back until the response to their query arrives.
+    awaiting_response: Arc<Mutex<HashMap<QueryKey, Vec<MqttMessage>>>>,
+    metrics: Arc<Metrics>,
+    availability: Arc<Availability>,
+    source: Option<Arc<SourceState>>,
+    ringing: Option<Arc<Ringing>>,
+    presses: Option<Arc<PressCounters>>,
+    devices: Arc<DeviceMap>,
 }
 
 impl DnsSocket {
@@ -47,6 +82,7 @@ impl DnsSocket {
         stream: UnixStream,
         sender: Sender<MqttMessage>,
         doorbells: Arc<Mutex<HashSet<String>>>,
+        detector: Arc<Detector>,
     ) -> Self {
         debug!("Ignoring packets from DNS server for {:?}", IGNORE_DURATION);
         Self {
@@ -54,9 +90,54 @@ impl DnsSocket {
             sender,
             start_time: Instant::now() + IGNORE_DURATION,
             doorbells,
+            detector,
+            reported: Mutex::new(HashMap::new()),
+            responses_logged: AtomicBool::new(false),
+            awaiting_response: Arc::default(),
+            metrics: Arc::default(),
+            availability: Arc::default(),
+            source: None,
+            ringing: None,
+            presses: None,
+            devices: Arc::default(),
         }
     }
 
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Drives a ringing binary sensor for each doorbell, shared across connections.
+    pub fn with_ringing(mut self, ringing: Arc<Ringing>) -> Self {
+        self.ringing = Some(ringing);
+        self
+    }
+
+    /// Counts presses and publishes the counts, shared across listeners.
+    pub fn with_press_counters(mut self, presses: Arc<PressCounters>) -> Self {
+        self.presses = Some(presses);
+        self
+    }
+
+    /// Names doorbells and places them in areas.
+    pub fn with_devices(mut self, devices: Arc<DeviceMap>) -> Self {
+        self.devices = devices;
+        self
+    }
+
+    /// Records frames against the source this connection came from.
+    pub fn with_source(mut self, source: Arc<SourceState>) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// Shares last-seen times with the other connections of the same listener.
+    pub fn with_availability(mut self, availability: Arc<Availability>) -> Self {
+        self.availability = availability;
+        self
+    }
+
     pub async fn handle_stream(&self) -> Result<()> {
         info!("connected to DNS server");
         let _ = self.stream.set_nonblocking(false);
@@ -73,6 +154,10 @@ impl DnsSocket {
                 .read_exact(&mut buffer)
                 .with_context(|| format!("Expected to read {} bytes", frame.size()))?;
 
+            self.metrics.frame_read();
+            if let Some(source) = &self.source {
+                source.frame_read();
+            }
             if Instant::now() < self.start_time {
                 debug!("Discarding frames until timer expires");
                 continue;
@@ -89,81 +174,228 @@ impl DnsSocket {
     }
 
     async fn handle_frame(&self, buffer: BytesMut) -> Result<()> {
-        let dnstap: Dnstap = Dnstap::decode(buffer)?;
+        let dnstap: Dnstap =
+            Dnstap::decode(buffer).map_err(self.failure(DecodeFailure::Protobuf))?;
         let msg = dnstap
             .message
-            .context("dnstap frame did not have message")?;
+            .context("dnstap frame did not have message")
+            .map_err(self.failure(DecodeFailure::MissingMessage))?;
+
+        let client: IpAddr = parse_octets(msg.query_address())
+            .context("invalid IP source")
+            .map_err(self.failure(DecodeFailure::ClientAddress))?;
+        let resolver = dnstap
+            .identity
+            .map(|identity| String::from_utf8_lossy(&identity).into_owned());
 
-        let client: IpAddr = parse_octets(msg.query_address()).context("invalid IP source")?;
+        // A response repeats the question and adds the answers, so prefer it.
+        let wire = msg
+            .response_message
+            .or(msg.query_message)
+            .context("Got empty DNS message")
+            .map_err(self.failure(DecodeFailure::MissingDnsMessage))?;
+        let message = DnsMessage::parse(&wire).map_err(self.failure(DecodeFailure::Dns))?;
+        self.handle_message(&message, client, msg.query_port, resolver)
+            .await
+    }
 
-        match msg.query_message {
-            Some(query) => match DnsPacket::parse(&query.as_slice()) {
-                Ok(packet) => self.handle_packet(&packet, client).await,
-                Err(e) => Err(e.into()),
-            },
-            None => Err(anyhow!("Got empty query message")),
+    /// Counts a decode failure on its way to being returned.
+    fn failure<E: Into<anyhow::Error>>(
+        &self,
+        reason: DecodeFailure,
+    ) -> impl FnOnce(E) -> anyhow::Error + '_ {
+        move |e| {
+            self.metrics.decode_failed(reason);
+            e.into()
         }
     }
 
-    fn get_config_message(&self, client: &String) -> MqttMessage {
-        let topic = format!("ringdet-{}/config", client);
-        let payload = "{}".as_bytes().to_vec();
-        MqttMessage::Publish { topic, payload }
+    fn get_config_message(&self, device: &Device) -> MqttMessage {
+        doorbell_config(device)
     }
 
-    fn get_action_message(&self, client: &String) -> MqttMessage {
-        let topic = format!("ringdet-{}/action", client);
+    fn get_action_message(&self, device: &Device, event: DoorbellEvent) -> MqttMessage {
+        let topic = device.topic("action");
         let payload = "{action:\"pressed\"}".as_bytes().to_vec();
-        MqttMessage::Publish { topic, payload }
+        MqttMessage::Event {
+            topic,
+            payload,
+            event,
+        }
+    }
+
+    /// Returns the messages announcing a press, with a config message the first
+    /// time a doorbell is seen.
+    fn press_messages(&self, rule: &str, event: DoorbellEvent) -> Vec<MqttMessage> {
+        self.metrics.matched(rule, &event.device);
+        let device = self.devices.resolve(&event.device);
+        let new_client = self.doorbells.lock().unwrap().insert(event.device.clone());
+
+        let mut messages = vec![];
+        if new_client {
+            messages.push(self.get_config_message(&device));
+            if self.ringing.is_some() {
+                messages.push(ringing_config(&device));
+            }
+            if self.presses.is_some() {
+                messages.extend(press_configs(&device));
+            }
+        }
+        if let Some(ringing) = &self.ringing {
+            messages.extend(ringing.pressed(&device));
+        }
+        if let Some(presses) = &self.presses {
+            messages.extend(presses.pressed(&device, event.timestamp));
+        }
+        messages.push(self.get_action_message(&device, event));
+        messages
     }
 
-    async fn handle_packet<'a>(&self, packet: &'a DnsPacket<'a>, client: IpAddr) -> Result<()> {
-        let messages: Vec<MqttMessage> = packet
+    /// Remembers a query that was reported, returning false if it already was.
+    fn first_report(&self, key: QueryKey) -> bool {
+        let now = Instant::now();
+        let mut reported = self.reported.lock().unwrap();
+        reported.retain(|_, seen| now.duration_since(*seen) < DUPLICATE_WINDOW);
+        reported.insert(key, now).is_none()
+    }
+
+    async fn handle_message(
+        &self,
+        message: &DnsMessage,
+        client: IpAddr,
+        query_port: Option<u32>,
+        resolver: Option<String>,
+    ) -> Result<()> {
+        let mut messages: Vec<MqttMessage> = if message.response {
+            self.responses_logged.store(true, Ordering::Relaxed);
+            self.handle_response(message, client, query_port, resolver)
+        } else {
+            self.handle_query(message, client, query_port, resolver)
+        };
+        let await_response = !message.response
+            && !messages.is_empty()
+            && self.responses_logged.load(Ordering::Relaxed);
+
+        // Any query at all shows a known doorbell is still on the network.
+        let address = client.to_string();
+        let device = self.devices.resolve(&address);
+        let known =
+            self.doorbells.lock().unwrap().contains(&address) || self.availability.knows(&device);
+        if known {
+            messages.extend(self.availability.seen(&device));
+        }
+        if let Some(presses) = &self.presses {
+            presses.save().await;
+        }
+
+        if await_response {
+            self.await_response((client, query_port, message.id), messages);
+            return Ok(());
+        }
+        for m in messages {
+            self.sender.send(m).await.unwrap();
+        }
+
+        Ok(())
+    }
+
+    /// Holds back the messages for a press until its response supplies the
+    /// response code, sending them without one if no response comes in time.
+    fn await_response(&self, key: QueryKey, messages: Vec<MqttMessage>) {
+        self.awaiting_response
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_default()
+            .extend(messages);
+
+        let awaiting = Arc::clone(&self.awaiting_response);
+        let sender = self.sender.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(RESPONSE_WAIT).await;
+            let messages = awaiting.lock().unwrap().remove(&key);
+            if let Some(messages) = messages {
+                debug!("No response to query; reporting it without a response code");
+                for m in messages {
+                    let _ = sender.send(m).await;
+                }
+            }
+        });
+    }
+
+    fn handle_query(
+        &self,
+        message: &DnsMessage,
+        client: IpAddr,
+        query_port: Option<u32>,
+        resolver: Option<String>,
+    ) -> Vec<MqttMessage> {
+        message
             .questions
             .iter()
-            .filter(|q| {
-                matches!(
-                    q.qtype,
-                    dns_parser::QueryType::A | dns_parser::QueryType::AAAA
-                )
-            })
             .filter_map(|q| {
-                let name = &q.qname;
-                match name.to_string().as_str() {
-                    HOST_EU | HOST_US => {
-                        debug!("we got {} from {:?}", name, &client);
-                        let client_string = client.to_string();
-
-                        let new_client = {
-                            let mut lock = self.doorbells.lock().unwrap();
-                            if lock.get(&client_string) == None {
-                                lock.insert(client_string.clone());
-                                true
-                            } else {
-                                false
-                            }
-                        };
-
-                        let mut messages = vec![];
-                        if new_client {
-                            messages.push(self.get_config_message(&client_string));
-                        }
-
-                        messages.push(self.get_action_message(&client_string));
-
-                        Some(messages)
-                    }
-                    _ => None,
+                // Events report the name in one spelling however the query was cased.
+                let name = normalize_name(&q.qname);
+                let rule = self.detector.detect(&client, &name, q.qtype)?;
+                debug!("we got {} from {:?} ({})", name, &client, rule.name);
+                if !self.first_report((client, query_port, message.id)) {
+                    debug!("Query was already reported");
+                    return None;
                 }
+                let event = DoorbellEvent::new(client.to_string(), name, resolver.clone());
+                Some(self.press_messages(&rule.name, event))
             })
             .flatten()
-            .collect();
+            .collect()
+    }
 
-        for m in messages {
-            self.sender.send(m).await.unwrap();
-        }
+    /// Matches every name the response leads through, so aliases that CNAME to a
+    /// watched host are found too. A response is reported once; if its query
+    /// already was, only a press still waiting for it is released, carrying the
+    /// response code.
+    fn handle_response(
+        &self,
+        message: &DnsMessage,
+        client: IpAddr,
+        query_port: Option<u32>,
+        resolver: Option<String>,
+    ) -> Vec<MqttMessage> {
+        let Some(question) = message.questions.first() else {
+            return vec![];
+        };
+        let Some((name, rule)) = message.names().into_iter().find_map(|name| {
+            self.detector
+                .detect(&client, name, question.qtype)
+                .map(|rule| (name, rule))
+        }) else {
+            return vec![];
+        };
 
-        Ok(())
+        let rcode = rcode_name(message.rcode);
+        debug!(
+            "we got {} from {:?} in a {} response ({})",
+            name, &client, rcode, rule.name
+        );
+        let key = (client, query_port, message.id);
+        if !self.first_report(key) {
+            let Some(mut messages) = self.awaiting_response.lock().unwrap().remove(&key) else {
+                debug!("Query was already reported");
+                return vec![];
+            };
+            for m in &mut messages {
+                if let MqttMessage::Event { event, .. } = m {
+                    event.rcode = Some(rcode.clone());
+                }
+            }
+            return messages;
+        }
+        let event = DoorbellEvent::new(
+            client.to_string(),
+            normalize_name(&question.qname),
+            resolver,
+        )
+        .with_rcode(rcode);
+        self.press_messages(&rule.name, event)
     }
 }
 
@@ -174,17 +406,25 @@ mod tests {
 
     #[tokio::test]
     async fn test_get_config_message() {
-        let client = "192.168.1.100".to_string();
         let (tx, _) = mpsc::channel(1);
         let doorbells = Arc::new(Mutex::new(HashSet::new()));
         let (stream_a, _) = std::os::unix::net::UnixStream::pair().unwrap();
 
-        let dns_socket = DnsSocket::new(stream_a, tx, doorbells);
-        let message = dns_socket.get_config_message(&client);
+        let dns_socket = DnsSocket::new(stream_a, tx, doorbells, Arc::default());
+        let message = dns_socket.get_config_message(&Device::new("192.168.1.100"));
 
-        let MqttMessage::Publish { topic, payload } = message;
+        let MqttMessage::Publish { topic, payload } = message else {
+            panic!("config should be a plain publish");
+        };
         assert_eq!(topic, "ringdet-192.168.1.100/config");
-        assert_eq!(payload, "{}".as_bytes().to_vec());
+        let config: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(config["~"], "");
+        assert_eq!(config["availability"][0]["topic"], "~/status");
+        assert_eq!(
+            config["availability"][1]["topic"],
+            "~/ringdet-192.168.1.100/availability"
+        );
+        assert_eq!(config["availability_mode"], "all");
     }
 
     #[tokio::test]
@@ -194,11 +434,399 @@ mod tests {
         let doorbells = Arc::new(Mutex::new(HashSet::new()));
         let (stream_a, _) = std::os::unix::net::UnixStream::pair().unwrap();
 
-        let dns_socket = DnsSocket::new(stream_a, tx, doorbells);
-        let message = dns_socket.get_action_message(&client);
+        let dns_socket = DnsSocket::new(stream_a, tx, doorbells, Arc::default());
+        let event = DoorbellEvent::new(
+            client.clone(),
+            "alarm.use.s3.amazonaws.com".to_string(),
+            Some("unbound".to_string()),
+        );
+        let message = dns_socket.get_action_message(&Device::new(client), event.clone());
 
-        let MqttMessage::Publish { topic, payload } = message;
+        let MqttMessage::Event {
+            topic,
+            payload,
+            event: message_event,
+        } = message
+        else {
+            panic!("action should carry its event");
+        };
         assert_eq!(topic, "ringdet-192.168.1.100/action");
         assert_eq!(payload, "{action:\"pressed\"}".as_bytes().to_vec());
+        assert_eq!(message_event, event);
+    }
+
+    #[tokio::test]
+    async fn test_rule_for_https_queries() {
+        use crate::{
+            detect::{DetectionRule, QTYPE_HTTPS},
+            dns_message::Question,
+        };
+
+        let (tx, mut rx) = mpsc::channel(10);
+        let doorbells = Arc::new(Mutex::new(HashSet::new()));
+        let (stream_a, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        let rules =
+            vec![DetectionRule::new("https", "doorbell.example").with_qtypes(vec![QTYPE_HTTPS])];
+        let detector = Arc::new(Detector::new(rules, Default::default()).unwrap());
+        let dns_socket = DnsSocket::new(stream_a, tx, doorbells, detector);
+
+        let question = |qtype| Question {
+            qname: "doorbell.example".to_string(),
+            qtype,
+            qclass: 1,
+        };
+        let message = DnsMessage {
+            id: 1,
+            questions: vec![question(1), question(QTYPE_HTTPS)],
+            ..Default::default()
+        };
+        dns_socket
+            .handle_message(&message, "192.168.1.100".parse().unwrap(), None, None)
+            .await
+            .unwrap();
+        drop(dns_socket);
+
+        assert!(matches!(rx.recv().await, Some(MqttMessage::Publish { .. })));
+        assert!(matches!(rx.recv().await, Some(MqttMessage::Event { .. })));
+        assert_online(rx.recv().await);
+        assert!(rx.recv().await.is_none());
+    }
+
+    fn assert_online(message: Option<MqttMessage>) {
+        let message = message.expect("doorbell should be reported online");
+        assert_eq!(message.topic(), "ringdet-192.168.1.100/availability");
+        assert_eq!(message.payload(), b"online");
+    }
+
+    fn vendor_alias_response(rcode: u8) -> DnsMessage {
+        use crate::dns_message::{Answer, Question, TYPE_CNAME};
+
+        DnsMessage {
+            id: 7,
+            response: true,
+            rcode,
+            questions: vec![Question {
+                qname: "Doorbell.Vendor.example".to_string(),
+                qtype: 1,
+                qclass: 1,
+            }],
+            answers: vec![Answer {
+                name: "doorbell.vendor.example".to_string(),
+                rtype: TYPE_CNAME,
+                target: Some("alarm.use.s3.amazonaws.com".to_string()),
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_detects_cname_target_in_response() {
+        use crate::dns_message::RCODE_NOERROR;
+
+        let (tx, mut rx) = mpsc::channel(10);
+        let doorbells = Arc::new(Mutex::new(HashSet::new()));
+        let (stream_a, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        let dns_socket = DnsSocket::new(stream_a, tx, doorbells, Arc::default());
+
+        let response = vendor_alias_response(RCODE_NOERROR);
+        let client = "192.168.1.100".parse().unwrap();
+        dns_socket
+            .handle_message(&response, client, Some(5353), None)
+            .await
+            .unwrap();
+        drop(dns_socket);
+
+        assert!(matches!(rx.recv().await, Some(MqttMessage::Publish { .. })));
+        let Some(MqttMessage::Event { event, .. }) = rx.recv().await else {
+            panic!("response should be reported as a press");
+        };
+        assert_eq!(event.qname, "doorbell.vendor.example");
+        assert_eq!(event.rcode.as_deref(), Some("NOERROR"));
+        assert_online(rx.recv().await);
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_any_query_keeps_known_doorbell_online() {
+        use crate::dns_message::Question;
+
+        let (tx, mut rx) = mpsc::channel(10);
+        let doorbells = Arc::new(Mutex::new(HashSet::new()));
+        let (stream_a, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        let availability = Arc::new(Availability::new(Duration::from_millis(20)));
+        let dns_socket = DnsSocket::new(stream_a, tx, Arc::clone(&doorbells), Arc::default())
+            .with_availability(Arc::clone(&availability));
+        let client = "192.168.1.100".parse().unwrap();
+        let ntp = DnsMessage {
+            id: 3,
+            questions: vec![Question {
+                qname: "pool.ntp.org".to_string(),
+                qtype: 1,
+                qclass: 1,
+            }],
+            ..Default::default()
+        };
+
+        // Unknown clients are not tracked.
+        dns_socket
+            .handle_message(&ntp, client, None, None)
+            .await
+            .unwrap();
+        assert!(rx.try_recv().is_err());
+
+        doorbells
+            .lock()
+            .unwrap()
+            .insert("192.168.1.100".to_string());
+        dns_socket
+            .handle_message(&ntp, client, None, None)
+            .await
+            .unwrap();
+        assert_online(rx.recv().await);
+
+        tokio::time::advance(Duration::from_millis(30)).await;
+        let offline = availability.expire();
+        assert_eq!(offline.len(), 1);
+        assert_eq!(offline[0].payload(), b"offline");
+
+        dns_socket
+            .handle_message(&ntp, client, None, None)
+            .await
+            .unwrap();
+        assert_online(rx.recv().await);
+
+        // Doorbells known from the configuration are tracked before any press.
+        availability.watch(Device::new("192.168.1.101"));
+        dns_socket
+            .handle_message(&ntp, "192.168.1.101".parse().unwrap(), None, None)
+            .await
+            .unwrap();
+        let online = rx.recv().await.unwrap();
+        assert_eq!(online.topic(), "ringdet-192.168.1.101/availability");
+        assert_eq!(online.payload(), b"online");
+    }
+
+    #[tokio::test]
+    async fn test_press_turns_on_ringing_sensor() {
+        use crate::dns_message::Question;
+
+        let (tx, mut rx) = mpsc::channel(10);
+        let doorbells = Arc::new(Mutex::new(HashSet::new()));
+        let (stream_a, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        let dns_socket = DnsSocket::new(stream_a, tx, doorbells, Arc::default())
+            .with_ringing(Arc::new(Ringing::new(Duration::from_secs(10))));
+        let client = "192.168.1.100".parse().unwrap();
+        let press = |id| DnsMessage {
+            id,
+            questions: vec![Question {
+                qname: "alarm.use.s3.amazonaws.com".to_string(),
+                qtype: 1,
+                qclass: 1,
+            }],
+            ..Default::default()
+        };
+
+        dns_socket
+            .handle_message(&press(1), client, None, None)
+            .await
+            .unwrap();
+        dns_socket
+            .handle_message(&press(2), client, None, None)
+            .await
+            .unwrap();
+        drop(dns_socket);
+
+        let mut topics = vec![];
+        while let Some(message) = rx.recv().await {
+            topics.push(message.topic().to_string());
+        }
+        assert_eq!(
+            topics,
+            vec![
+                "ringdet-192.168.1.100/config",
+                "ringdet-192.168.1.100-ringing/config",
+                "ringdet-192.168.1.100/ringing",
+                "ringdet-192.168.1.100/action",
+                "ringdet-192.168.1.100/availability",
+                // The second press extends the hold without turning the sensor on again.
+                "ringdet-192.168.1.100/action",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_press_publishes_counts_under_device_slug() {
+        use crate::dns_message::Question;
+
+        let (tx, mut rx) = mpsc::channel(10);
+        let doorbells = Arc::new(Mutex::new(HashSet::new()));
+        let (stream_a, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        let dns_socket = DnsSocket::new(stream_a, tx, doorbells, Arc::default())
+            .with_press_counters(Arc::default())
+            .with_devices(Arc::new(
+                DeviceMap::from_json(
+                    br#"[{"client": "192.168.1.100", "name": "Front door", "slug": "front"}]"#,
+                )
+                .unwrap(),
+            ));
+        let press = DnsMessage {
+            id: 1,
+            questions: vec![Question {
+                qname: "alarm.use.s3.amazonaws.com".to_string(),
+                qtype: 1,
+                qclass: 1,
+            }],
+            ..Default::default()
+        };
+
+        dns_socket
+            .handle_message(&press, "192.168.1.100".parse().unwrap(), None, None)
+            .await
+            .unwrap();
+        drop(dns_socket);
+
+        let mut messages = vec![];
+        while let Some(message) = rx.recv().await {
+            messages.push(message);
+        }
+        let topics: Vec<_> = messages.iter().map(|m| m.topic()).collect();
+        assert_eq!(
+            topics,
+            vec![
+                "ringdet-192.168.1.100/config",
+                "ringdet-192.168.1.100-last_pressed/config",
+                "ringdet-192.168.1.100-presses/config",
+                "ringdet-192.168.1.100-presses_today/config",
+                // States follow the slug; discovery keeps the address-based IDs.
+                "ringdet-front/last_pressed/state",
+                "ringdet-front/presses/state",
+                "ringdet-front/presses_today/state",
+                "ringdet-front/action",
+                "ringdet-front/availability",
+            ]
+        );
+        assert_eq!(messages[5].payload(), b"1");
+        let config: serde_json::Value = serde_json::from_slice(messages[0].payload()).unwrap();
+        assert_eq!(config["device"]["name"], "Front door");
+    }
+
+    #[tokio::test]
+    async fn test_response_code_reaches_press_seen_in_query() {
+        use crate::dns_message::{Question, RCODE_NXDOMAIN};
+
+        let (tx, mut rx) = mpsc::channel(10);
+        let doorbells = Arc::new(Mutex::new(HashSet::new()));
+        let (stream_a, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        let dns_socket = DnsSocket::new(stream_a, tx, doorbells, Arc::default());
+        let client = "192.168.1.100".parse().unwrap();
+
+        // Any response shows the resolver logs responses as well as queries.
+        let mut other = DnsMessage {
+            id: 8,
+            response: true,
+            questions: vec![Question {
+                qname: "example.com".to_string(),
+                qtype: 1,
+                qclass: 1,
+            }],
+            ..Default::default()
+        };
+        dns_socket
+            .handle_message(&other, client, Some(5352), None)
+            .await
+            .unwrap();
+
+        let query = DnsMessage {
+            id: 9,
+            questions: vec![Question {
+                qname: "alarm.use.s3.amazonaws.com".to_string(),
+                qtype: 1,
+                qclass: 1,
+            }],
+            ..Default::default()
+        };
+        let mut response = query.clone();
+        response.response = true;
+        response.rcode = RCODE_NXDOMAIN;
+
+        dns_socket
+            .handle_message(&query, client, Some(5353), None)
+            .await
+            .unwrap();
+        let early = rx.try_recv();
+        assert!(
+            early.is_err(),
+            "press should wait for its response: {:?}",
+            early
+        );
+        dns_socket
+            .handle_message(&response, client, Some(5353), None)
+            .await
+            .unwrap();
+        dns_socket
+            .handle_message(&response, client, Some(5353), None)
+            .await
+            .unwrap();
+        other.id = 10;
+        dns_socket
+            .handle_message(&other, client, Some(5352), None)
+            .await
+            .unwrap();
+        drop(dns_socket);
+
+        assert!(matches!(rx.recv().await, Some(MqttMessage::Publish { .. })));
+        let Some(MqttMessage::Event { event, .. }) = rx.recv().await else {
+            panic!("query should be reported");
+        };
+        assert_eq!(event.rcode.as_deref(), Some("NXDOMAIN"));
+        assert_online(rx.recv().await);
+        // The press is not reported again, even once the wait is over.
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_query_is_reported_at_once_until_responses_are_seen() {
+        use crate::dns_message::{Question, RCODE_NXDOMAIN};
+
+        let (tx, mut rx) = mpsc::channel(10);
+        let doorbells = Arc::new(Mutex::new(HashSet::new()));
+        let (stream_a, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        let dns_socket = DnsSocket::new(stream_a, tx, doorbells, Arc::default());
+        let client = "192.168.1.100".parse().unwrap();
+
+        let query = DnsMessage {
+            id: 9,
+            questions: vec![Question {
+                qname: "alarm.use.s3.amazonaws.com".to_string(),
+                qtype: 1,
+                qclass: 1,
+            }],
+            ..Default::default()
+        };
+        let mut response = query.clone();
+        response.response = true;
+        response.rcode = RCODE_NXDOMAIN;
+
+        dns_socket
+            .handle_message(&query, client, Some(5353), None)
+            .await
+            .unwrap();
+        assert!(matches!(rx.recv().await, Some(MqttMessage::Publish { .. })));
+        let Some(MqttMessage::Event { event, .. }) = rx.recv().await else {
+            panic!("query should be reported");
+        };
+        assert_eq!(event.rcode, None);
+
+        // A retransmitted query is the same press.
+        dns_socket
+            .handle_message(&query, client, Some(5353), None)
+            .await
+            .unwrap();
+        dns_socket
+            .handle_message(&response, client, Some(5353), None)
+            .await
+            .unwrap();
+        drop(dns_socket);
+        assert_online(rx.recv().await);
+        assert!(rx.recv().await.is_none());
     }
 }
@@ -0,0 +1,557 @@
+/*
+ * Copyright 2025 Kenny Root
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use anyhow::{bail, Context, Result};
+use log::debug;
+use std::fmt::Write;
+
+const HEADER_LEN: usize = 12;
+const MAX_NAME_LEN: usize = 255;
+/// More compression pointers than this in one name can only be a loop.
+const MAX_POINTERS: usize = 32;
+
+pub const TYPE_CNAME: u16 = 5;
+
+pub const RCODE_NOERROR: u8 = 0;
+pub const RCODE_SERVFAIL: u8 = 2;
+pub const RCODE_NXDOMAIN: u8 = 3;
+pub const RCODE_REFUSED: u8 = 5;
+
+/// The mnemonic for a response code, such as `NXDOMAIN`.
+pub fn rcode_name(rcode: u8) -> String {
+    match rcode {
+        RCODE_NOERROR => "NOERROR".to_string(),
+        1 => "FORMERR".to_string(),
+        RCODE_SERVFAIL => "SERVFAIL".to_string(),
+        RCODE_NXDOMAIN => "NXDOMAIN".to_string(),
+        4 => "NOTIMP".to_string(),
+        RCODE_REFUSED => "REFUSED".to_string(),
+        other => format!("RCODE{}", other),
+    }
+}
+
+/// A question from a DNS message; types and classes are kept as numbers so new
+/// record types such as HTTPS do not make the message unreadable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Question {
+    /// Name in presentation format without the trailing dot, such as `example.com`.
+    pub qname: String,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+/// A record from the answer section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Answer {
+    pub name: String,
+    pub rtype: u16,
+    /// The alias target of a CNAME record.
+    pub target: Option<String>,
+}
+
+/// The parts of a DNS message that detection looks at.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Message {
+    pub id: u16,
+    /// Set for responses, clear for queries.
+    pub response: bool,
+    pub rcode: u8,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Answer>,
+}
+
+impl Message {
+    /// Every name a response leads through: the questions, the answer owners and
+    /// CNAME targets, in order and without repeats.
+    pub fn names(&self) -> Vec<&str> {
+        let questions = self.questions.iter().map(|q| q.qname.as_str());
+        let answers = self.answers.iter().flat_map(|answer| {
+            std::iter::once(answer.name.as_str()).chain(answer.target.as_deref())
+        });
+        let mut names: Vec<&str> = vec![];
+        for name in questions.chain(answers) {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        names
+    }
+}
+
+impl Message {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < HEADER_LEN {
+            bail!("DNS message is shorter than its header");
+        }
+        let mut reader = Reader { data, pos: 0 };
+        let id = reader.u16()?;
+        let flags = reader.u16()?;
+        let qdcount = reader.u16()?;
+        let ancount = reader.u16()?;
+        reader.pos = HEADER_LEN;
+
+        let questions = (0..qdcount)
+            .map(|i| {
+                reader
+                    .question()
+                    .with_context(|| format!("Invalid question {}", i))
+            })
+            .collect::<Result<_>>()?;
+        // A bad answer leaves the rest unreadable, but the question and the
+        // answers before it can still be matched.
+        let mut answers = vec![];
+        for i in 0..ancount {
+            match reader.answer() {
+                Ok(answer) => answers.push(answer),
+                Err(e) => {
+                    debug!("Ignoring DNS answers from {} on: {:#}", i, e);
+                    break;
+                }
+            }
+        }
+        Ok(Self {
+            id,
+            response: flags & 0x8000 != 0,
+            rcode: (flags & 0x000f) as u8,
+            questions,
+            answers,
+        })
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn u8_at(&self, pos: usize) -> Result<u8> {
+        match self.data.get(pos) {
+            Some(&value) => Ok(value),
+            None => bail!("DNS message is truncated at offset {}", pos),
+        }
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let value = u16::from_be_bytes([self.u8_at(self.pos)?, self.u8_at(self.pos + 1)?]);
+        self.pos += 2;
+        Ok(value)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let high = self.u16()? as u32;
+        Ok(high << 16 | self.u16()? as u32)
+    }
+
+    fn answer(&mut self) -> Result<Answer> {
+        let name = self.name()?;
+        let rtype = self.u16()?;
+        let _class = self.u16()?;
+        let _ttl = self.u32()?;
+        let rdlength = self.u16()? as usize;
+        let end = self.pos + rdlength;
+        if end > self.data.len() {
+            bail!("DNS record data at offset {} is truncated", self.pos);
+        }
+        let target = match rtype {
+            TYPE_CNAME => Some(self.name()?),
+            _ => None,
+        };
+        self.pos = end;
+        Ok(Answer {
+            name,
+            rtype,
+            target,
+        })
+    }
+
+    fn question(&mut self) -> Result<Question> {
+        Ok(Question {
+            qname: self.name()?,
+            qtype: self.u16()?,
+            qclass: self.u16()?,
+        })
+    }
+
+    /// Reads a possibly compressed name, leaving the reader just after it.
+    fn name(&mut self) -> Result<String> {
+        let mut name = String::new();
+        let mut wire_len = 0;
+        let mut pos = self.pos;
+        let mut pointers = 0;
+
+        loop {
+            let len = self.u8_at(pos)?;
+            match len & 0xc0 {
+                0x00 if len == 0 => {
+                    if pointers == 0 {
+                        self.pos = pos + 1;
+                    }
+                    break;
+                }
+                0x00 => {
+                    let start = pos + 1;
+                    let end = start + len as usize;
+                    let Some(label) = self.data.get(start..end) else {
+                        bail!("DNS label at offset {} is truncated", pos);
+                    };
+                    wire_len += label.len() + 1;
+                    if wire_len > MAX_NAME_LEN {
+                        bail!("DNS name is longer than {} bytes", MAX_NAME_LEN);
+                    }
+                    if !name.is_empty() {
+                        name.push('.');
+                    }
+                    push_label(&mut name, label);
+                    pos = end;
+                }
+                0xc0 => {
+                    let target = u16::from_be_bytes([len & 0x3f, self.u8_at(pos + 1)?]) as usize;
+                    if pointers == 0 {
+                        self.pos = pos + 2;
+                    }
+                    pointers += 1;
+                    if pointers > MAX_POINTERS {
+                        bail!("DNS name has too many compression pointers");
+                    }
+                    pos = target;
+                }
+                _ => bail!("Unsupported DNS label type {:#04x} at offset {}", len, pos),
+            }
+        }
+
+        if name.is_empty() {
+            name.push('.');
+        }
+        Ok(name)
+    }
+}
+
+/// Puts a name in the form rules are compared in.
+///
+/// The name may be in presentation format as read from the wire, with `\.` and
+/// `\DDD` escapes, or typed by a user with a trailing dot or Unicode labels.
+/// Labels are lowercased, internationalized labels are converted to punycode and
+/// the trailing dot is dropped; the root name becomes the empty string.
+pub fn normalize_name(name: &str) -> String {
+    let mut normalized = String::new();
+    for label in split_labels(name) {
+        if !normalized.is_empty() {
+            normalized.push('.');
+        }
+        match ascii_label(&label) {
+            Some(ascii) => normalized.push_str(&ascii),
+            None => push_label(&mut normalized, &label.to_ascii_lowercase()),
+        }
+    }
+    normalized
+}
+
+/// Splits a presentation-format name into raw labels, resolving escapes.
+fn split_labels(name: &str) -> Vec<Vec<u8>> {
+    let mut labels = vec![];
+    let mut label = vec![];
+    let mut bytes = name.as_bytes().iter().copied().peekable();
+
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'.' => labels.push(std::mem::take(&mut label)),
+            b'\\' => {
+                let digits: Vec<u8> = (0..3)
+                    .map_while(|_| bytes.next_if(u8::is_ascii_digit))
+                    .collect();
+                match digits.len() {
+                    0 => label.extend(bytes.next()),
+                    3 => {
+                        let value = digits.iter().fold(0u32, |n, d| n * 10 + (d - b'0') as u32);
+                        // Out-of-range escapes are kept literally rather than guessed at.
+                        match u8::try_from(value) {
+                            Ok(value) => label.push(value),
+                            Err(_) => label.extend(digits),
+                        }
+                    }
+                    _ => label.extend(digits),
+                }
+            }
+            _ => label.push(byte),
+        }
+    }
+    if !label.is_empty() {
+        labels.push(label);
+    }
+    labels
+}
+
+/// Converts a label to lowercase ASCII, using punycode for Unicode labels.
+///
+/// Returns `None` for labels that are neither ASCII nor valid IDNA, such as raw binary.
+fn ascii_label(label: &[u8]) -> Option<String> {
+    if label.is_ascii() {
+        if label
+            .iter()
+            .all(|b| b.is_ascii_graphic() && *b != b'.' && *b != b'\\')
+        {
+            return Some(String::from_utf8_lossy(label).to_ascii_lowercase());
+        }
+        return None;
+    }
+    let label = std::str::from_utf8(label).ok()?;
+    idna::domain_to_ascii(label)
+        .ok()
+        .filter(|ascii| !ascii.is_empty() && !ascii.contains('.'))
+}
+
+/// Appends a label, escaping bytes that would be ambiguous in presentation format.
+fn push_label(name: &mut String, label: &[u8]) {
+    for &byte in label {
+        match byte {
+            b'.' | b'\\' => {
+                name.push('\\');
+                name.push(byte as char);
+            }
+            0x21..=0x7e => name.push(byte as char),
+            _ => {
+                let _ = write!(name, "\\{:03}", byte);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a query with one uncompressed question per entry.
+    fn query(questions: &[(&str, u16)]) -> Vec<u8> {
+        let mut data = vec![
+            0x12,
+            0x34,
+            0x01,
+            0x00,
+            0,
+            questions.len() as u8,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ];
+        for (name, qtype) in questions {
+            for label in name.split('.') {
+                data.push(label.len() as u8);
+                data.extend(label.as_bytes());
+            }
+            data.push(0);
+            data.extend(qtype.to_be_bytes());
+            data.extend(1u16.to_be_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn test_keeps_unknown_qtypes() {
+        let data = query(&[
+            ("alarm.use.s3.amazonaws.com", 1),
+            ("alarm.use.s3.amazonaws.com", 65),
+            ("_dns.resolver.arpa", 64),
+        ]);
+        let message = Message::parse(&data).unwrap();
+
+        assert_eq!(message.id, 0x1234);
+        let qtypes: Vec<_> = message.questions.iter().map(|q| q.qtype).collect();
+        assert_eq!(qtypes, vec![1, 65, 64]);
+        assert_eq!(message.questions[1].qname, "alarm.use.s3.amazonaws.com");
+        assert_eq!(message.questions[2].qclass, 1);
+    }
+
+    #[test]
+    fn test_response_with_cname_chain() {
+        // doorbell.vendor.example CNAME alarm.use.s3.amazonaws.com, which has an A record.
+        let mut data = query(&[("doorbell.vendor.example", 1)]);
+        data[2] = 0x81;
+        data[3] = 0x80;
+        data[7] = 2;
+        data.extend([0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 28]);
+        let target = data.len();
+        data.extend(b"\x05alarm\x03use\x02s3\x09amazonaws\x03com\x00");
+        data.extend([
+            0xc0,
+            target as u8,
+            0,
+            1,
+            0,
+            1,
+            0,
+            0,
+            0,
+            5,
+            0,
+            4,
+            52,
+            1,
+            2,
+            3,
+        ]);
+
+        let message = Message::parse(&data).unwrap();
+        assert!(message.response);
+        assert_eq!(message.rcode, RCODE_NOERROR);
+        assert_eq!(message.answers.len(), 2);
+        assert_eq!(
+            message.answers[0].target.as_deref(),
+            Some("alarm.use.s3.amazonaws.com")
+        );
+        assert_eq!(message.answers[1].rtype, 1);
+        assert_eq!(
+            message.names(),
+            vec!["doorbell.vendor.example", "alarm.use.s3.amazonaws.com"]
+        );
+    }
+
+    #[test]
+    fn test_rcode() {
+        let mut data = query(&[("doorbell.example", 1)]);
+        data[2] = 0x81;
+        data[3] = 0x83;
+        let message = Message::parse(&data).unwrap();
+        assert_eq!(message.rcode, RCODE_NXDOMAIN);
+        assert_eq!(rcode_name(message.rcode), "NXDOMAIN");
+        assert_eq!(rcode_name(23), "RCODE23");
+
+        assert!(!Message::parse(&query(&[])).unwrap().response);
+    }
+
+    #[test]
+    fn test_compressed_name() {
+        let mut data = query(&[("example.com", 1)]);
+        data[5] = 2;
+        // "www" followed by a pointer to the first question's name at offset 12.
+        data.extend([3, b'w', b'w', b'w', 0xc0, 12, 0, 65, 0, 1]);
+
+        let message = Message::parse(&data).unwrap();
+        assert_eq!(message.questions[1].qname, "www.example.com");
+        assert_eq!(message.questions[1].qtype, 65);
+    }
+
+    #[test]
+    fn test_escapes_labels() {
+        let mut data = query(&[]);
+        data[5] = 1;
+        data.extend([3, b'a', b'.', b'b', 2, b' ', 0xff, 0, 0, 1, 0, 1]);
+
+        let message = Message::parse(&data).unwrap();
+        assert_eq!(message.questions[0].qname, "a\\.b.\\032\\255");
+    }
+
+    #[test]
+    fn test_root_name() {
+        let mut data = query(&[]);
+        data[5] = 1;
+        data.extend([0, 0, 2, 0, 1]);
+        assert_eq!(Message::parse(&data).unwrap().questions[0].qname, ".");
+    }
+
+    #[test]
+    fn test_normalize_name() {
+        let cases = [
+            ("alarm.use.s3.amazonaws.com", "alarm.use.s3.amazonaws.com"),
+            // 0x20 case randomization and a fully-qualified name.
+            ("aLaRm.UsE.s3.AmazonAWS.com.", "alarm.use.s3.amazonaws.com"),
+            ("ALARM.USE.S3.AMAZONAWS.COM", "alarm.use.s3.amazonaws.com"),
+            // Decimal and character escapes of ordinary letters.
+            (
+                "\\097larm.use.s3.amazonaws.com",
+                "alarm.use.s3.amazonaws.com",
+            ),
+            (
+                "\\065\\Larm.use.s3.amazonaws.com",
+                "alarm.use.s3.amazonaws.com",
+            ),
+            // An escaped dot is part of the label, not a separator.
+            (
+                "alarm\\.use.s3.amazonaws.com",
+                "alarm\\.use.s3.amazonaws.com",
+            ),
+            (
+                "Alarm\\046use.s3.amazonaws.com",
+                "alarm\\.use.s3.amazonaws.com",
+            ),
+            // Bytes that need escaping come out in one canonical form.
+            ("\\032Door.example", "\\032door.example"),
+            ("\\255.example", "\\255.example"),
+            // Internationalized names compare in punycode whatever form they arrive in.
+            ("bücher.example", "xn--bcher-kva.example"),
+            ("BÜCHER.example.", "xn--bcher-kva.example"),
+            ("XN--BCHER-KVA.Example", "xn--bcher-kva.example"),
+            ("b\\195\\188cher.example", "xn--bcher-kva.example"),
+            ("ｅｘａｍｐｌｅ.com", "example.com"),
+            // Underscores are common in service names and must survive.
+            ("_dns.Resolver.arpa", "_dns.resolver.arpa"),
+            (".", ""),
+            ("", ""),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(normalize_name(name), expected, "normalizing {:?}", name);
+        }
+    }
+
+    #[test]
+    fn test_normalizes_parsed_names() {
+        let mut data = query(&[]);
+        data[5] = 1;
+        data.extend([
+            5, b'A', b'l', b'a', b'R', b'm', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e',
+        ]);
+        data.extend([0, 0, 1, 0, 1]);
+
+        let message = Message::parse(&data).unwrap();
+        assert_eq!(normalize_name(&message.questions[0].qname), "alarm.example");
+    }
+
+    #[test]
+    fn test_rejects_malformed_messages() {
+        assert!(Message::parse(&[0; 4]).is_err());
+
+        let data = query(&[("example.com", 1)]);
+        assert!(Message::parse(&data[..data.len() - 1]).is_err());
+
+        let mut looped = query(&[]);
+        looped[5] = 1;
+        looped.extend([0xc0, 12, 0, 1, 0, 1]);
+        assert!(Message::parse(&looped).is_err());
+
+        let mut reserved = query(&[]);
+        reserved[5] = 1;
+        reserved.extend([0x40, 0, 0, 1, 0, 1]);
+        assert!(Message::parse(&reserved).is_err());
+    }
+
+    #[test]
+    fn test_keeps_question_of_malformed_answers() {
+        let mut data = query(&[("doorbell.vendor.example", 1)]);
+        data[2] = 0x81;
+        data[3] = 0x83;
+        data[7] = 2;
+        data.extend([0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 52, 1, 2, 3]);
+        // The second answer's data runs past the end of the message.
+        data.extend([0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 40]);
+
+        let message = Message::parse(&data).unwrap();
+        assert_eq!(message.rcode, RCODE_NXDOMAIN);
+        assert_eq!(message.questions[0].qname, "doorbell.vendor.example");
+        assert_eq!(message.answers.len(), 1);
+    }
+}
@@ -18,49 +18,254 @@ use anyhow::Context;
 use async_trait::async_trait;
 use log::{info, warn};
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
+    future,
+    net::Shutdown,
+    os::unix::net::UnixStream,
     path::PathBuf,
     sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    net::UnixListener,
+    runtime::Handle,
+    sync::mpsc::Sender,
+    task::{self, JoinSet},
 };
-use tokio::{net::UnixListener, sync::mpsc::Sender, task};
 
-use crate::{dns::DnsSocket, listener::DnsListener, mqtt::MqttMessage};
+use crate::{
+    availability::Availability,
+    detect::Detector,
+    devices::DeviceMap,
+    dns::DnsSocket,
+    listener::DnsListener,
+    metrics::Metrics,
+    mqtt::MqttMessage,
+    presses::PressCounters,
+    ringing::Ringing,
+    socket::{SocketFile, SocketOptions},
+    source::SourceState,
+};
+
+/// Connections being served, closed together when the listener stops.
+#[derive(Default)]
+struct Connections {
+    tasks: JoinSet<()>,
+    /// A second handle on each connection, used to interrupt its blocking reads.
+    streams: HashMap<task::Id, UnixStream>,
+}
+
+impl Drop for Connections {
+    fn drop(&mut self) {
+        // FINISH only answers a STOP, which the reader already handles, so a
+        // sender that has not stopped just sees the connection close.
+        for stream in self.streams.values() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        // The reads now fail, so the blocking tasks end on their own.
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct DnsService {
     socket_path: PathBuf,
     doorbells: Arc<Mutex<HashSet<String>>>,
+    /// Kept for the life of the service so a restarted listener can reuse it.
+    inherited: Option<Arc<std::os::unix::net::UnixListener>>,
+    socket_options: SocketOptions,
+    detector: Arc<Detector>,
+    metrics: Arc<Metrics>,
+    availability: Arc<Availability>,
+    source: Arc<SourceState>,
+    ringing: Option<Arc<Ringing>>,
+    presses: Option<Arc<PressCounters>>,
+    devices: Arc<DeviceMap>,
 }
 
 impl DnsService {
     pub fn new(socket_path: PathBuf) -> Self {
         Self {
+            source: Arc::new(SourceState::new(socket_path.display().to_string())),
             socket_path,
             doorbells: Arc::new(Mutex::new(HashSet::new())),
+            inherited: None,
+            socket_options: SocketOptions::default(),
+            detector: Arc::default(),
+            metrics: Arc::default(),
+            availability: Arc::default(),
+            ringing: None,
+            presses: None,
+            devices: Arc::default(),
+        }
+    }
+
+    /// Sets the rules and client filter deciding which queries are doorbell presses.
+    pub fn with_detector(mut self, detector: Arc<Detector>) -> Self {
+        self.detector = detector;
+        self
+    }
+
+    /// Sets where connection, frame and match counts are recorded.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Sets how long a doorbell may go without any DNS query before it is reported offline.
+    pub fn with_device_timeout(mut self, timeout: Duration) -> Self {
+        self.availability = Arc::new(Availability::new(timeout));
+        self
+    }
+
+    /// Shares last-seen times with other listeners, including doorbells known
+    /// from the configuration before they are seen.
+    pub fn with_availability(mut self, availability: Arc<Availability>) -> Self {
+        self.availability = availability;
+        self
+    }
+
+    /// Turns on a ringing binary sensor for each press, and off once the hold
+    /// passes without one. Listeners should share it so each doorbell has one timer.
+    pub fn with_ringing(mut self, ringing: Arc<Ringing>) -> Self {
+        self.ringing = Some(ringing);
+        self
+    }
+
+    /// Counts presses per doorbell and publishes the counts.
+    pub fn with_press_counters(mut self, presses: Arc<PressCounters>) -> Self {
+        self.presses = Some(presses);
+        self
+    }
+
+    /// Sets the doorbells already announced, which listeners should share so
+    /// each doorbell is announced once.
+    pub fn with_doorbells(mut self, doorbells: Arc<Mutex<HashSet<String>>>) -> Self {
+        self.doorbells = doorbells;
+        self
+    }
+
+    /// Names doorbells and places them in areas.
+    pub fn with_devices(mut self, devices: Arc<DeviceMap>) -> Self {
+        self.devices = devices;
+        self
+    }
+
+    /// Sets the mode and ownership of the socket once it is bound.
+    pub fn with_socket_options(mut self, socket_options: SocketOptions) -> Self {
+        self.socket_options = socket_options;
+        self
+    }
+
+    /// Serves a socket that is already bound, such as one passed by systemd socket activation.
+    pub fn with_listener(socket_path: PathBuf, listener: std::os::unix::net::UnixListener) -> Self {
+        let mut service = Self::new(socket_path);
+        service.inherited = Some(Arc::new(listener));
+        service
+    }
+
+    /// Binds the socket, also returning a guard that removes the socket file if we created it.
+    fn listener(&self) -> anyhow::Result<(UnixListener, Option<SocketFile>)> {
+        if let Some(ref inherited) = self.inherited {
+            // The service manager owns the socket file of an inherited listener.
+            let listener = inherited
+                .try_clone()
+                .and_then(UnixListener::from_std)
+                .context("Cannot use inherited DNS listener")?;
+            return Ok((listener, None));
         }
+        let listener = self.socket_options.bind(&self.socket_path)?;
+        let socket_file = SocketFile::new(&self.socket_path);
+        Ok((UnixListener::from_std(listener)?, socket_file))
     }
 }
 
 #[async_trait]
 impl DnsListener for DnsService {
     async fn start_listening(&self, message_sender: Sender<MqttMessage>) -> anyhow::Result<()> {
-        let listener = UnixListener::bind(&self.socket_path).with_context(|| {
-            format!("Cannot bind to DNS listener {}", self.socket_path.display())
-        })?;
+        // Declared before the connections so the socket file is removed last.
+        let (listener, _socket_file) = self.listener()?;
+        let _bound = self.metrics.listener_bound();
+        let mut connections = Connections::default();
+        let mut availability_check = tokio::time::interval(self.availability.check_interval());
+        let mut ringing_check = self
+            .ringing
+            .as_ref()
+            .map(|ringing| tokio::time::interval(ringing.check_interval()));
         info!("listening on {}", self.socket_path.display());
 
         loop {
-            let (stream, _) = listener.accept().await?;
-            let sender = message_sender.clone();
-            let doorbells = Arc::clone(&self.doorbells);
-
-            task::spawn(async move {
-                let dns_socket = DnsSocket::new(stream.into_std().unwrap(), sender, doorbells);
-                match dns_socket.handle_stream().await {
-                    Ok(_) => info!("server disconnected"),
-                    Err(err) => warn!("error on thread: {}", err),
-                }
-            });
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, _) = accepted?;
+                    let stream = stream.into_std()?;
+                    let control = stream.try_clone()?;
+                    let sender = message_sender.clone();
+                    let doorbells = Arc::clone(&self.doorbells);
+                    let detector = Arc::clone(&self.detector);
+                    let metrics = Arc::clone(&self.metrics);
+                    let availability = Arc::clone(&self.availability);
+                    let source_state = Arc::clone(&self.source);
+                    let ringing = self.ringing.clone();
+                    let presses = self.presses.clone();
+                    let devices = Arc::clone(&self.devices);
+                    let active = self.metrics.connection_accepted();
+                    let source = self.source.connected();
+
+                    // fstrm reads block, so each connection gets a thread of its own.
+                    let runtime = Handle::current();
+                    let handle = connections.tasks.spawn_blocking(move || {
+                        let _active = active;
+                        let _source = source;
+                        let mut dns_socket = DnsSocket::new(stream, sender, doorbells, detector)
+                            .with_metrics(metrics)
+                            .with_availability(availability)
+                            .with_source(source_state)
+                            .with_devices(devices);
+                        if let Some(ringing) = ringing {
+                            dns_socket = dns_socket.with_ringing(ringing);
+                        }
+                        if let Some(presses) = presses {
+                            dns_socket = dns_socket.with_press_counters(presses);
+                        }
+                        match runtime.block_on(dns_socket.handle_stream()) {
+                            Ok(_) => info!("server disconnected"),
+                            Err(err) => warn!("error on thread: {}", err),
+                        }
+                    });
+                    connections.streams.insert(handle.id(), control);
+                },
+                Some(finished) = connections.tasks.join_next_with_id() => {
+                    let id = match finished {
+                        Ok((id, ())) => id,
+                        Err(e) => {
+                            warn!("connection task failed: {}", e);
+                            e.id()
+                        }
+                    };
+                    connections.streams.remove(&id);
+                },
+                _ = availability_check.tick() => {
+                    let mut messages = self.availability.expire();
+                    if let Some(presses) = &self.presses {
+                        messages.extend(presses.roll_over(SystemTime::now(), &self.devices));
+                        presses.save().await;
+                    }
+                    for message in messages {
+                        message_sender.send(message).await?;
+                    }
+                },
+                _ = async {
+                    match ringing_check.as_mut() {
+                        Some(interval) => interval.tick().await,
+                        None => future::pending().await,
+                    }
+                } => {
+                    let expired = self.ringing.as_ref().map(|r| r.expire()).unwrap_or_default();
+                    for message in expired {
+                        message_sender.send(message).await?;
+                    }
+                },
+            }
         }
     }
 
@@ -68,6 +273,19 @@ impl DnsListener for DnsService {
         Box::new(Self {
             socket_path: self.socket_path.clone(),
             doorbells: Arc::clone(&self.doorbells),
+            inherited: self.inherited.clone(),
+            socket_options: self.socket_options.clone(),
+            detector: Arc::clone(&self.detector),
+            metrics: Arc::clone(&self.metrics),
+            availability: Arc::clone(&self.availability),
+            source: Arc::clone(&self.source),
+            ringing: self.ringing.clone(),
+            presses: self.presses.clone(),
+            devices: Arc::clone(&self.devices),
         })
     }
+
+    fn source(&self) -> Option<Arc<SourceState>> {
+        Some(Arc::clone(&self.source))
+    }
 }
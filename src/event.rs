@@ -0,0 +1,153 @@
+/*
+ * Copyright 2025 Kenny Root
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
+
+/// A doorbell press detected from a DNS query or response.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DoorbellEvent {
+    /// Address of the doorbell that made the query.
+    pub device: String,
+    /// The name that was looked up.
+    pub qname: String,
+    /// Identity reported by the resolver in its dnstap stream, if any.
+    pub resolver: Option<String>,
+    /// Response code such as `NXDOMAIN` when the press was seen in a response.
+    #[serde(default)]
+    pub rcode: Option<String>,
+    pub timestamp: SystemTime,
+    /// Set when the event was held in the outbox and delivered after the fact.
+    #[serde(default)]
+    pub late: bool,
+}
+
+impl DoorbellEvent {
+    pub fn new(device: String, qname: String, resolver: Option<String>) -> Self {
+        Self {
+            device,
+            qname,
+            resolver,
+            rcode: None,
+            timestamp: SystemTime::now(),
+            late: false,
+        }
+    }
+
+    pub fn with_rcode(mut self, rcode: String) -> Self {
+        self.rcode = Some(rcode);
+        self
+    }
+
+    /// The event time as an RFC 3339 (ISO 8601) UTC string.
+    pub fn timestamp_rfc3339(&self) -> String {
+        humantime::format_rfc3339_millis(self.timestamp).to_string()
+    }
+
+    /// The event as a flat JSON object for consumers outside MQTT.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "event": "press",
+            "device": self.device,
+            "qname": self.qname,
+            "resolver": self.resolver,
+            "rcode": self.rcode,
+            "timestamp": self.timestamp_rfc3339(),
+            "late": self.late,
+        })
+    }
+}
+
+/// A dnstap source going down after a silence, or coming back.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SourceEvent {
+    /// The source's socket path.
+    pub source: String,
+    pub up: bool,
+    pub connections: usize,
+    pub silent_for: Duration,
+    pub timestamp: SystemTime,
+}
+
+impl SourceEvent {
+    pub fn state(&self) -> &'static str {
+        if self.up {
+            "up"
+        } else {
+            "down"
+        }
+    }
+
+    pub fn timestamp_rfc3339(&self) -> String {
+        humantime::format_rfc3339_millis(self.timestamp).to_string()
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "event": "source",
+            "source": self.source,
+            "state": self.state(),
+            "connections": self.connections,
+            "silent_seconds": self.silent_for.as_secs(),
+            "timestamp": self.timestamp_rfc3339(),
+        })
+    }
+}
+
+/// Everything the bridge reports besides MQTT housekeeping.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BridgeEvent {
+    Press(DoorbellEvent),
+    Source(SourceEvent),
+}
+
+impl BridgeEvent {
+    /// The event as a flat JSON object whose `event` field tells the kinds apart.
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            BridgeEvent::Press(event) => event.to_json(),
+            BridgeEvent::Source(event) => event.to_json(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_to_json() {
+        let mut event = DoorbellEvent::new(
+            "192.168.1.100".to_string(),
+            "alarm.use.s3.amazonaws.com".to_string(),
+            None,
+        );
+        event.timestamp = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+
+        assert_eq!(
+            event.to_json(),
+            serde_json::json!({
+                "event": "press",
+                "device": "192.168.1.100",
+                "qname": "alarm.use.s3.amazonaws.com",
+                "resolver": null,
+                "rcode": null,
+                "timestamp": "2023-11-14T22:13:20.123Z",
+                "late": false,
+            })
+        );
+    }
+}
@@ -0,0 +1,295 @@
+/*
+ * Copyright 2025 Kenny Root
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::{event::BridgeEvent, messaging::MessagePublisher, mqtt::MqttMessage};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use log::{info, warn};
+use std::{path::PathBuf, process::Stdio, sync::Arc, time::Duration};
+use tokio::{io::AsyncWriteExt, process::Command, sync::Semaphore};
+
+#[derive(Debug, Clone)]
+pub struct ExecConfig {
+    pub program: PathBuf,
+    pub args: Vec<String>,
+    /// Maximum number of commands running at once; further presses wait for a slot.
+    pub concurrency: usize,
+    /// Commands still running after this long are killed.
+    pub timeout: Duration,
+}
+
+impl ExecConfig {
+    pub fn new(program: PathBuf) -> Self {
+        Self {
+            program,
+            args: vec![],
+            concurrency: 4,
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Runs a local command for each doorbell press and dnstap source change.
+///
+/// Event fields are passed as `RING_*` environment variables, with `RING_EVENT`
+/// set to `press` or `source`, and the event JSON is written to the command's
+/// stdin. Commands run in the background, so failures are
+/// logged rather than returned from `publish`.
+#[derive(Debug, Clone)]
+pub struct ExecService {
+    config: ExecConfig,
+    slots: Arc<Semaphore>,
+}
+
+impl ExecService {
+    pub fn new(config: ExecConfig) -> Self {
+        let slots = Arc::new(Semaphore::new(config.concurrency));
+        Self { config, slots }
+    }
+
+    /// Waits until no commands are running.
+    pub async fn wait_idle(&self) {
+        let _ = self
+            .slots
+            .acquire_many(self.config.concurrency as u32)
+            .await;
+    }
+
+    fn command(&self, topic: &str, event: &BridgeEvent) -> Command {
+        let mut command = Command::new(&self.config.program);
+        command.args(&self.config.args);
+        match event {
+            BridgeEvent::Press(event) => command
+                .env("RING_EVENT", "press")
+                .env("RING_DEVICE", &event.device)
+                .env("RING_QNAME", &event.qname)
+                .env(
+                    "RING_RESOLVER",
+                    event.resolver.as_deref().unwrap_or_default(),
+                )
+                .env("RING_RCODE", event.rcode.as_deref().unwrap_or_default())
+                .env("RING_TIMESTAMP", event.timestamp_rfc3339())
+                .env("RING_LATE", if event.late { "1" } else { "0" }),
+            BridgeEvent::Source(event) => command
+                .env("RING_EVENT", "source")
+                .env("RING_SOURCE", &event.source)
+                .env("RING_STATE", event.state())
+                .env("RING_TIMESTAMP", event.timestamp_rfc3339()),
+        };
+        command
+            .env("RING_TOPIC", topic)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        command
+    }
+}
+
+async fn run(mut command: Command, input: Vec<u8>, timeout: Duration) -> Result<()> {
+    let mut child = command.spawn().context("Cannot start command")?;
+    let pid = child.id().unwrap_or_default();
+
+    let stdin = child.stdin.take();
+    let write_input = async move {
+        if let Some(mut stdin) = stdin {
+            // The command may exit without reading its input.
+            let _ = stdin.write_all(&input).await;
+        }
+    };
+    // Input is written while output is read, both under the timeout, so a command
+    // that never reads its input cannot hold a slot forever.
+    let finished = async {
+        let ((), output) = tokio::join!(write_input, child.wait_with_output());
+        output
+    };
+
+    // Dropping the child on timeout kills it.
+    let output = tokio::time::timeout(timeout, finished)
+        .await
+        .with_context(|| {
+            format!(
+                "Command {} timed out after {:?} and was killed",
+                pid, timeout
+            )
+        })?
+        .context("Cannot wait for command")?;
+
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        info!("[exec {}] {}", pid, line);
+    }
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        warn!("[exec {}] {}", pid, line);
+    }
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "Command {} exited with {}",
+            pid,
+            output.status
+        ))
+    }
+}
+
+#[async_trait]
+impl MessagePublisher for ExecService {
+    fn name(&self) -> &str {
+        "exec"
+    }
+
+    async fn publish(&self, message: MqttMessage) -> Result<()> {
+        let Some(event) = message.bridge_event() else {
+            return Ok(());
+        };
+
+        let permit = Arc::clone(&self.slots).acquire_owned().await?;
+        let command = self.command(message.topic(), &event);
+        let input = serde_json::to_vec(&event.to_json())?;
+        let timeout = self.config.timeout;
+        tokio::spawn(async move {
+            if let Err(e) = run(command, input, timeout).await {
+                warn!("Exec publisher: {:#}", e);
+            }
+            drop(permit);
+        });
+        Ok(())
+    }
+
+    async fn send_birth(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn send_death(&self) -> Result<()> {
+        self.wait_idle().await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{event::DoorbellEvent, source::SourceState};
+    use std::time::Instant;
+
+    fn press(device: &str) -> MqttMessage {
+        MqttMessage::Event {
+            topic: format!("ringdet-{}/action", device),
+            payload: b"{action:\"pressed\"}".to_vec(),
+            event: DoorbellEvent::new(
+                device.to_string(),
+                "alarm.use.s3.amazonaws.com".to_string(),
+                Some("unbound".to_string()),
+            ),
+        }
+    }
+
+    fn shell(script: String) -> ExecConfig {
+        let mut config = ExecConfig::new(PathBuf::from("/bin/sh"));
+        config.args = vec!["-c".to_string(), script];
+        config
+    }
+
+    #[tokio::test]
+    async fn test_passes_env_and_stdin() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let service = ExecService::new(shell(format!(
+            "echo \"$RING_DEVICE $RING_RESOLVER $RING_LATE\" > {0}; cat >> {0}",
+            out.display()
+        )));
+
+        service.publish(press("192.168.1.100")).await.unwrap();
+        service.wait_idle().await;
+
+        let written = std::fs::read_to_string(out).unwrap();
+        let (env, stdin) = written.split_once('\n').unwrap();
+        assert_eq!(env, "192.168.1.100 unbound 0");
+        let json: serde_json::Value = serde_json::from_str(stdin).unwrap();
+        assert_eq!(json["device"], "192.168.1.100");
+    }
+
+    #[tokio::test]
+    async fn test_reports_source_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let service = ExecService::new(shell(format!(
+            "echo \"$RING_EVENT $RING_SOURCE $RING_STATE\" > {0}; cat >> {0}",
+            out.display()
+        )));
+
+        let source = SourceState::new("/run/dnstap.sock");
+        service.publish(source.state_message()).await.unwrap();
+        service.wait_idle().await;
+
+        let written = std::fs::read_to_string(out).unwrap();
+        let (env, stdin) = written.split_once('\n').unwrap();
+        assert_eq!(env, "source /run/dnstap.sock up");
+        let json: serde_json::Value = serde_json::from_str(stdin).unwrap();
+        assert_eq!(json["event"], "source");
+        assert_eq!(json["state"], "up");
+    }
+
+    #[tokio::test]
+    async fn test_kills_after_timeout() {
+        let mut config = shell("sleep 10".to_string());
+        config.timeout = Duration::from_millis(100);
+        let service = ExecService::new(config);
+
+        let start = Instant::now();
+        service.publish(press("192.168.1.100")).await.unwrap();
+        service.wait_idle().await;
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_timeout_covers_unread_input() {
+        let config = shell("sleep 10".to_string());
+        let message = press("192.168.1.100");
+        let event = message.bridge_event().unwrap();
+        let command = ExecService::new(config).command("topic", &event);
+
+        // Far more than a pipe buffer, so writing blocks until the command is killed.
+        let start = Instant::now();
+        let result = run(command, vec![b'x'; 1 << 20], Duration::from_millis(100)).await;
+        assert!(result.unwrap_err().to_string().contains("timed out"));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limit() {
+        let mut config = shell("sleep 0.2".to_string());
+        config.concurrency = 1;
+        let service = ExecService::new(config);
+
+        let start = Instant::now();
+        service.publish(press("192.168.1.1")).await.unwrap();
+        service.publish(press("192.168.1.2")).await.unwrap();
+        // The second press had to wait for the first command to finish.
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        service.wait_idle().await;
+    }
+
+    #[tokio::test]
+    async fn test_ignores_non_event_messages() {
+        let service = ExecService::new(ExecConfig::new(PathBuf::from("/nonexistent")));
+        let message = MqttMessage::Publish {
+            topic: "ringdet-192.168.1.100/config".to_string(),
+            payload: b"{}".to_vec(),
+        };
+        service.publish(message).await.unwrap();
+    }
+}
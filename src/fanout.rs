@@ -0,0 +1,298 @@
+/*
+ * Copyright 2025 Kenny Root
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::{
+    messaging::MessagePublisher, mqtt::MqttMessage, outbox::is_queued, resilience::CircuitBreaker,
+};
+use log::{error, warn};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError, Sender},
+    task::JoinHandle,
+};
+
+pub const DEFAULT_QUEUE_SIZE: usize = 100;
+
+/// Delivery counters for one publisher.
+#[derive(Debug, Default)]
+pub struct PublisherStats {
+    name: String,
+    published: AtomicU64,
+    failed: AtomicU64,
+    spooled: AtomicU64,
+    dropped: AtomicU64,
+    queued: AtomicU64,
+}
+
+impl PublisherStats {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Messages the publisher accepted.
+    pub fn published(&self) -> u64 {
+        self.published.load(Ordering::Relaxed)
+    }
+
+    /// Messages the publisher returned an error for.
+    pub fn failed(&self) -> u64 {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    /// Messages an outbox kept to deliver later instead of delivering them.
+    pub fn spooled(&self) -> u64 {
+        self.spooled.load(Ordering::Relaxed)
+    }
+
+    /// Messages discarded because the publisher's queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Messages waiting in the publisher's queue.
+    pub fn queued(&self) -> u64 {
+        self.queued.load(Ordering::Relaxed)
+    }
+}
+
+/// A publisher together with the counters describing its deliveries.
+#[derive(Debug, Clone)]
+pub struct PublisherSlot {
+    pub publisher: Arc<dyn MessagePublisher>,
+    pub stats: Arc<PublisherStats>,
+    /// Present once the publisher is wrapped in a [`crate::resilience::Resilient`].
+    pub breaker: Option<Arc<CircuitBreaker>>,
+}
+
+impl PublisherSlot {
+    pub fn new(publisher: Box<dyn MessagePublisher>) -> Self {
+        let stats = Arc::new(PublisherStats::new(publisher.name()));
+        Self {
+            publisher: Arc::from(publisher),
+            stats,
+            breaker: None,
+        }
+    }
+}
+
+/// Gives every publisher its own queue and task so a slow one cannot hold up the rest.
+pub struct FanOut {
+    queues: Vec<(Sender<MqttMessage>, Arc<PublisherStats>)>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl FanOut {
+    pub fn start(slots: &[PublisherSlot], queue_size: usize) -> Self {
+        let mut queues = vec![];
+        let mut tasks = vec![];
+
+        for slot in slots {
+            let (tx, mut rx) = mpsc::channel::<MqttMessage>(queue_size);
+            let publisher = Arc::clone(&slot.publisher);
+            let stats = Arc::clone(&slot.stats);
+            queues.push((tx, Arc::clone(&stats)));
+
+            tasks.push(tokio::spawn(async move {
+                while let Some(message) = rx.recv().await {
+                    stats.queued.fetch_sub(1, Ordering::Relaxed);
+                    match publisher.publish(message).await {
+                        Ok(_) => {
+                            stats.published.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Err(e) if is_queued(&e) => {
+                            stats.spooled.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Err(e) => {
+                            stats.failed.fetch_add(1, Ordering::Relaxed);
+                            error!("Failed to publish message via {}: {}", stats.name, e);
+                        }
+                    }
+                }
+            }));
+        }
+
+        Self { queues, tasks }
+    }
+
+    /// Queues `message` for every publisher without waiting for any of them.
+    pub fn dispatch(&self, message: MqttMessage) {
+        for (queue, stats) in &self.queues {
+            stats.queued.fetch_add(1, Ordering::Relaxed);
+            match queue.try_send(message.clone()) {
+                Ok(_) => {}
+                Err(TrySendError::Full(_)) => {
+                    stats.queued.fetch_sub(1, Ordering::Relaxed);
+                    stats.dropped.fetch_add(1, Ordering::Relaxed);
+                    warn!("Queue for {} is full; dropping message", stats.name);
+                }
+                Err(TrySendError::Closed(_)) => {
+                    stats.queued.fetch_sub(1, Ordering::Relaxed);
+                    stats.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    /// Lets publishers drain their queues for up to `grace`, then stops them.
+    pub async fn shutdown(self, grace: Duration) {
+        drop(self.queues);
+
+        let deadline = tokio::time::Instant::now() + grace;
+        for mut task in self.tasks {
+            if tokio::time::timeout_at(deadline, &mut task).await.is_err() {
+                warn!("Publisher did not drain its queue in time");
+                task.abort();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use std::sync::Mutex;
+
+    #[derive(Debug, Default)]
+    struct RecordingPublisher {
+        delay: Duration,
+        fail: bool,
+        /// Fails as an outbox does when it keeps the message for later.
+        spool: bool,
+        published: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl MessagePublisher for RecordingPublisher {
+        async fn publish(&self, message: MqttMessage) -> anyhow::Result<()> {
+            tokio::time::sleep(self.delay).await;
+            if self.fail {
+                anyhow::bail!("backend down");
+            }
+            if self.spool {
+                return Err(anyhow::anyhow!(crate::outbox::Queued(anyhow::anyhow!(
+                    "backend down"
+                ))));
+            }
+            self.published
+                .lock()
+                .unwrap()
+                .push(message.topic().to_string());
+            Ok(())
+        }
+
+        async fn send_birth(&self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn send_death(&self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn message(topic: &str) -> MqttMessage {
+        MqttMessage::Publish {
+            topic: topic.to_string(),
+            payload: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_publisher_does_not_delay_others() {
+        let fast = RecordingPublisher::default();
+        let fast_published = Arc::clone(&fast.published);
+        let slow = RecordingPublisher {
+            delay: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let slots = vec![
+            PublisherSlot::new(Box::new(slow)),
+            PublisherSlot::new(Box::new(fast)),
+        ];
+
+        let fanout = FanOut::start(&slots, 10);
+        fanout.dispatch(message("a"));
+        fanout.dispatch(message("b"));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(*fast_published.lock().unwrap(), ["a", "b"]);
+        assert_eq!(slots[1].stats.published(), 2);
+        assert_eq!(slots[0].stats.published(), 0);
+
+        fanout.shutdown(Duration::from_millis(10)).await;
+    }
+
+    #[tokio::test]
+    async fn test_counts_failures_and_drops() {
+        let failing = RecordingPublisher {
+            fail: true,
+            ..Default::default()
+        };
+        let stuck = RecordingPublisher {
+            delay: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let slots = vec![
+            PublisherSlot::new(Box::new(failing)),
+            PublisherSlot::new(Box::new(stuck)),
+        ];
+
+        let fanout = FanOut::start(&slots, 1);
+        fanout.dispatch(message("a"));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        // The stuck publisher is busy with "a", so "b" fills its queue and "c" is dropped.
+        fanout.dispatch(message("b"));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        fanout.dispatch(message("c"));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        assert_eq!(slots[0].stats.failed(), 3);
+        assert_eq!(slots[0].stats.dropped(), 0);
+        assert_eq!(slots[1].stats.dropped(), 1);
+        assert_eq!(slots[1].stats.queued(), 1);
+
+        fanout.shutdown(Duration::from_millis(10)).await;
+    }
+
+    #[tokio::test]
+    async fn test_counts_spooled_messages_apart() {
+        let spooling = RecordingPublisher {
+            spool: true,
+            ..Default::default()
+        };
+        let slots = vec![PublisherSlot::new(Box::new(spooling))];
+
+        let fanout = FanOut::start(&slots, 10);
+        fanout.dispatch(message("a"));
+        fanout.shutdown(Duration::from_secs(1)).await;
+
+        assert_eq!(slots[0].stats.spooled(), 1);
+        assert_eq!(slots[0].stats.published(), 0);
+        assert_eq!(slots[0].stats.failed(), 0);
+    }
+}
@@ -0,0 +1,257 @@
+/*
+ * Copyright 2025 Kenny Root
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use anyhow::{bail, Context, Result};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    fanout::PublisherSlot,
+    http::{self, Handler, Response},
+    metrics::Metrics,
+    source::SourceState,
+};
+
+/// Where the health check is served, next to the metrics.
+pub const PATH: &str = "/healthz";
+
+/// When the running bridge counts as unhealthy.
+#[derive(Debug, Clone)]
+pub struct HealthConfig {
+    /// How long an MQTT session may stay down; `None` ignores the session.
+    pub mqtt_down: Option<Duration>,
+    /// How long the bridge may go without a dnstap connection; `None` ignores it.
+    pub dnstap_idle: Option<Duration>,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            mqtt_down: Some(Duration::from_secs(300)),
+            dnstap_idle: Some(Duration::from_secs(600)),
+        }
+    }
+}
+
+/// Judges the health of a running bridge from its metrics and publishers.
+pub struct HealthCheck {
+    config: HealthConfig,
+    listeners: usize,
+    metrics: Arc<Metrics>,
+    publishers: Vec<PublisherSlot>,
+    sources: Vec<Arc<SourceState>>,
+    started: Instant,
+}
+
+impl HealthCheck {
+    pub fn new(
+        config: HealthConfig,
+        listeners: usize,
+        metrics: Arc<Metrics>,
+        publishers: &[PublisherSlot],
+        sources: Vec<Arc<SourceState>>,
+    ) -> Self {
+        Self {
+            config,
+            listeners,
+            metrics,
+            publishers: publishers.to_vec(),
+            sources,
+            started: Instant::now(),
+        }
+    }
+
+    /// Why the bridge is unhealthy; empty when it is healthy.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        let bound = self.metrics.listeners_bound() as usize;
+        if bound < self.listeners {
+            problems.push(format!(
+                "{} of {} dnstap listeners not bound",
+                self.listeners - bound,
+                self.listeners
+            ));
+        }
+
+        if let Some(limit) = self.config.mqtt_down {
+            for slot in &self.publishers {
+                let down = slot.publisher.connection().and_then(|c| c.down_for());
+                if let Some(down) = down.filter(|down| *down > limit) {
+                    problems.push(format!(
+                        "{} disconnected for {}s",
+                        slot.stats.name(),
+                        down.as_secs()
+                    ));
+                }
+            }
+        }
+
+        if let Some(limit) = self.config.dnstap_idle {
+            // Before the first connection, count from startup.
+            let idle = self
+                .metrics
+                .since_last_connection()
+                .unwrap_or_else(|| self.started.elapsed());
+            if idle > limit {
+                problems.push(format!("no dnstap connection for {}s", idle.as_secs()));
+            }
+        }
+
+        for source in self.sources.iter().filter(|source| source.is_down()) {
+            problems.push(format!(
+                "dnstap source {} down, silent for {}s",
+                source.name(),
+                source.silent_for().as_secs()
+            ));
+        }
+        problems
+    }
+
+    /// Answers `/healthz` with 200 when healthy and 503 listing the problems otherwise.
+    pub fn handler(self: &Arc<Self>) -> Handler {
+        let check = Arc::clone(self);
+        Arc::new(move |path| {
+            (path == PATH).then(|| {
+                let problems = check.problems();
+                if problems.is_empty() {
+                    Response::new(200, "text/plain; charset=utf-8", "ok\n")
+                } else {
+                    let body = format!("{}\n", problems.join("\n"));
+                    Response::new(503, "text/plain; charset=utf-8", body)
+                }
+            })
+        })
+    }
+}
+
+/// Asks the instance serving metrics on `addr` whether it is healthy.
+///
+/// An unspecified address such as `0.0.0.0` is probed on loopback, so the
+/// instance's own listen address can be passed as is.
+pub async fn probe(addr: SocketAddr, timeout: Duration) -> Result<()> {
+    let mut addr = addr;
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr.ip() {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        });
+    }
+
+    let (status, body) = tokio::time::timeout(timeout, http::get(addr, PATH))
+        .await
+        .with_context(|| format!("No answer from {} within {:?}", addr, timeout))??;
+    if status != 200 {
+        bail!(
+            "Unhealthy ({}): {}",
+            status,
+            body.trim().replace('\n', "; ")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mqtt::MqttConfig, mqtt_service::MqttService};
+    use tokio::net::TcpListener;
+
+    fn mqtt_publisher() -> PublisherSlot {
+        let config = MqttConfig::new(
+            "localhost".into(),
+            1883,
+            "user".into(),
+            "pass".into(),
+            "prefix".into(),
+        );
+        PublisherSlot::new(Box::new(MqttService::from_config(&config)))
+    }
+
+    #[test]
+    fn test_problems() {
+        let metrics = Arc::new(Metrics::default());
+        let config = HealthConfig {
+            mqtt_down: Some(Duration::ZERO),
+            dnstap_idle: Some(Duration::ZERO),
+        };
+        let source = Arc::new(SourceState::new("/run/dnstap.sock"));
+        let check = HealthCheck::new(
+            config,
+            1,
+            Arc::clone(&metrics),
+            &[mqtt_publisher()],
+            vec![Arc::clone(&source)],
+        );
+        std::thread::sleep(Duration::from_millis(5));
+        source.update(Duration::ZERO);
+
+        let problems = check.problems();
+        assert_eq!(problems.len(), 4, "{:?}", problems);
+        assert_eq!(problems[0], "1 of 1 dnstap listeners not bound");
+        assert!(problems[1].starts_with("mqtt disconnected for "));
+        assert!(problems[2].starts_with("no dnstap connection for "));
+        assert!(problems[3].starts_with("dnstap source /run/dnstap.sock down, silent for "));
+
+        let _bound = metrics.listener_bound();
+        let _connection = metrics.connection_accepted();
+        source.frame_read();
+        source.update(Duration::from_secs(60));
+        assert_eq!(check.problems().len(), 1);
+    }
+
+    #[test]
+    fn test_disabled_checks() {
+        let metrics = Arc::new(Metrics::default());
+        let config = HealthConfig {
+            mqtt_down: None,
+            dnstap_idle: None,
+        };
+        let check = HealthCheck::new(config, 1, Arc::clone(&metrics), &[mqtt_publisher()], vec![]);
+        let _bound = metrics.listener_bound();
+        assert!(check.problems().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let metrics = Arc::new(Metrics::default());
+        let check = Arc::new(HealthCheck::new(
+            HealthConfig::default(),
+            1,
+            Arc::clone(&metrics),
+            &[],
+            vec![],
+        ));
+        let server = tokio::spawn(http::serve(listener, check.handler()));
+
+        let error = probe(addr, Duration::from_secs(5)).await.unwrap_err();
+        assert!(
+            error.to_string().contains("listeners not bound"),
+            "{}",
+            error
+        );
+
+        let _bound = metrics.listener_bound();
+        let unspecified = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), addr.port());
+        probe(unspecified, Duration::from_secs(5)).await.unwrap();
+
+        server.abort();
+    }
+}
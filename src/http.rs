@@ -0,0 +1,193 @@
+/*
+ * Copyright 2025 Kenny Root
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use anyhow::{bail, Context, Result};
+use log::debug;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_HEADER_LEN: usize = 8192;
+
+/// A complete response to a GET request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &'static str, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type,
+            body: body.into(),
+        }
+    }
+
+    fn text(status: u16, body: &str) -> Self {
+        Self::new(status, "text/plain; charset=utf-8", format!("{}\n", body))
+    }
+}
+
+/// Maps a request path to a response, or `None` if nothing is served there.
+pub type Handler = Arc<dyn Fn(&str) -> Option<Response> + Send + Sync>;
+
+/// Combines handlers; the first one serving a path answers it.
+pub fn routes(handlers: Vec<Handler>) -> Handler {
+    Arc::new(move |path| handlers.iter().find_map(|handler| handler(path)))
+}
+
+/// Answers GET requests on `listener` until the task is dropped.
+///
+/// This is only meant for scrapers and probes, so each connection carries a single
+/// request and is closed after the response.
+pub async fn serve(listener: TcpListener, handler: Handler) -> Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let handler = Arc::clone(&handler);
+        tokio::spawn(async move {
+            match tokio::time::timeout(REQUEST_TIMEOUT, respond(stream, &handler)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => debug!("HTTP request from {} failed: {:#}", peer, e),
+                Err(_) => debug!("HTTP request from {} timed out", peer),
+            }
+        });
+    }
+}
+
+async fn respond(stream: TcpStream, handler: &Handler) -> Result<()> {
+    let mut stream = BufReader::new(stream);
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await?;
+
+    // Read the headers so closing the socket does not reset the response.
+    let mut header_len = request_line.len();
+    loop {
+        let mut header = String::new();
+        let read = stream.read_line(&mut header).await?;
+        header_len += read;
+        if header_len > MAX_HEADER_LEN {
+            bail!("Request headers are too long");
+        }
+        if read == 0 || header.trim_end().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let path = target.split('?').next().unwrap_or("");
+    let response = match handler(path) {
+        Some(response) if method == "GET" || method == "HEAD" => response,
+        Some(_) => Response::text(405, "Method Not Allowed"),
+        None => Response::text(404, "Not Found"),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len()
+    );
+    let stream = stream.get_mut();
+    stream.write_all(head.as_bytes()).await?;
+    if method != "HEAD" {
+        stream.write_all(response.body.as_bytes()).await?;
+    }
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Fetches `path` from a server such as [`serve`], returning the status and body.
+pub async fn get(addr: SocketAddr, path: &str) -> Result<(u16, String)> {
+    let mut stream = TcpStream::connect(addr)
+        .await
+        .with_context(|| format!("Cannot connect to {}", addr))?;
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, addr
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+
+    let status = response
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .with_context(|| format!("Malformed HTTP response from {}", addr))?;
+    let body = response
+        .split_once("\r\n\r\n")
+        .map(|(_, body)| body.to_string())
+        .unwrap_or_default();
+    Ok((status, body))
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn request(addr: std::net::SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_serves_routes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handler: Handler =
+            Arc::new(|path| (path == "/hello").then(|| Response::new(200, "text/plain", "hello")));
+        let server = tokio::spawn(serve(listener, handler));
+
+        let response = request(addr, "GET /hello?x=1 HTTP/1.1\r\nHost: test\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("Content-Length: 5\r\n"));
+        assert!(response.ends_with("\r\n\r\nhello"));
+
+        let response = request(addr, "GET /missing HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        let response = request(addr, "POST /hello HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+
+        let response = request(addr, "HEAD /hello HTTP/1.1\r\n\r\n").await;
+        assert!(response.ends_with("\r\n\r\n"));
+
+        assert_eq!(get(addr, "/hello").await.unwrap(), (200, "hello".into()));
+        assert_eq!(get(addr, "/missing").await.unwrap().0, 404);
+
+        server.abort();
+    }
+}
@@ -0,0 +1,250 @@
+/*
+ * Copyright 2025 Kenny Root
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::{messaging::MessagePublisher, mqtt::MqttMessage};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+#[derive(Debug, Clone)]
+pub enum JsonLinesTarget {
+    Stdout,
+    File {
+        path: PathBuf,
+        /// Rotate once the file reaches this many bytes.
+        max_bytes: Option<u64>,
+        /// Number of rotated files (`path.1`, `path.2`, ...) to keep.
+        max_files: usize,
+    },
+}
+
+#[derive(Debug)]
+enum Writer {
+    Stdout,
+    File {
+        path: PathBuf,
+        file: File,
+        size: u64,
+        max_bytes: Option<u64>,
+        max_files: usize,
+    },
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Cannot open {}", path.display()))
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+impl Writer {
+    fn open(target: JsonLinesTarget) -> Result<Self> {
+        Ok(match target {
+            JsonLinesTarget::Stdout => Writer::Stdout,
+            JsonLinesTarget::File {
+                path,
+                max_bytes,
+                max_files,
+            } => {
+                let file = open_append(&path)?;
+                let size = file.metadata()?.len();
+                Writer::File {
+                    path,
+                    file,
+                    size,
+                    max_bytes,
+                    max_files,
+                }
+            }
+        })
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> Result<()> {
+        match self {
+            Writer::Stdout => {
+                let mut stdout = io::stdout().lock();
+                stdout.write_all(line)?;
+                stdout.flush()?;
+            }
+            Writer::File {
+                path,
+                file,
+                size,
+                max_bytes,
+                max_files,
+            } => {
+                if max_bytes.is_some_and(|max| *size > 0 && *size + line.len() as u64 > max) {
+                    for index in (1..*max_files).rev() {
+                        let from = rotated_path(path, index);
+                        if from.exists() {
+                            fs::rename(&from, rotated_path(path, index + 1))?;
+                        }
+                    }
+                    if *max_files > 0 {
+                        fs::rename(&path, rotated_path(path, 1))?;
+                    } else {
+                        fs::remove_file(&path)?;
+                    }
+                    *file = open_append(path)?;
+                    *size = 0;
+                }
+
+                file.write_all(line)
+                    .with_context(|| format!("Cannot write to {}", path.display()))?;
+                *size += line.len() as u64;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes each doorbell press and dnstap source change as one JSON object per line,
+/// for `jq`, Vector or Fluent Bit. The `event` field tells the two apart.
+#[derive(Debug)]
+pub struct JsonLinesService {
+    /// Shared with the blocking thread that writes and rotates the file.
+    writer: Arc<Mutex<Writer>>,
+}
+
+impl JsonLinesService {
+    pub fn new(target: JsonLinesTarget) -> Result<Self> {
+        Ok(Self {
+            writer: Arc::new(Mutex::new(Writer::open(target)?)),
+        })
+    }
+}
+
+#[async_trait]
+impl MessagePublisher for JsonLinesService {
+    fn name(&self) -> &str {
+        "json"
+    }
+
+    async fn publish(&self, message: MqttMessage) -> Result<()> {
+        let Some(event) = message.bridge_event() else {
+            return Ok(());
+        };
+
+        let mut json = event.to_json();
+        json["topic"] = message.topic().into();
+        let mut line = serde_json::to_vec(&json)?;
+        line.push(b'\n');
+
+        let writer = self.writer.clone();
+        tokio::task::spawn_blocking(move || writer.lock().unwrap().write_line(&line)).await?
+    }
+
+    async fn send_birth(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn send_death(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::DoorbellEvent;
+
+    fn press(device: &str) -> MqttMessage {
+        MqttMessage::Event {
+            topic: format!("ringdet-{}/action", device),
+            payload: b"{action:\"pressed\"}".to_vec(),
+            event: DoorbellEvent::new(
+                device.to_string(),
+                "alarm.use.s3.amazonaws.com".to_string(),
+                None,
+            ),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_writes_one_object_per_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.jsonl");
+        let service = JsonLinesService::new(JsonLinesTarget::File {
+            path: path.clone(),
+            max_bytes: None,
+            max_files: 0,
+        })
+        .unwrap();
+
+        service.publish(press("192.168.1.1")).await.unwrap();
+        service
+            .publish(MqttMessage::Publish {
+                topic: "ringdet-192.168.1.1/config".to_string(),
+                payload: b"{}".to_vec(),
+            })
+            .await
+            .unwrap();
+        service.publish(press("192.168.1.2")).await.unwrap();
+        let source = crate::source::SourceState::new("/run/dnstap.sock");
+        service.publish(source.state_message()).await.unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["event"], "press");
+        assert_eq!(lines[0]["device"], "192.168.1.1");
+        assert_eq!(lines[0]["topic"], "ringdet-192.168.1.1/action");
+        assert_eq!(lines[1]["device"], "192.168.1.2");
+        assert_eq!(lines[2]["event"], "source");
+        assert_eq!(lines[2]["state"], "up");
+        assert_eq!(lines[2]["topic"], "dnstap-run_dnstap_sock/state");
+    }
+
+    #[tokio::test]
+    async fn test_rotates_by_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.jsonl");
+        let service = JsonLinesService::new(JsonLinesTarget::File {
+            path: path.clone(),
+            max_bytes: Some(1),
+            max_files: 2,
+        })
+        .unwrap();
+
+        for device in ["192.168.1.1", "192.168.1.2", "192.168.1.3", "192.168.1.4"] {
+            service.publish(press(device)).await.unwrap();
+        }
+
+        let device_in = |path: PathBuf| {
+            let line = fs::read_to_string(path).unwrap();
+            let json: serde_json::Value = serde_json::from_str(line.trim()).unwrap();
+            json["device"].as_str().unwrap().to_string()
+        };
+        assert_eq!(device_in(path.clone()), "192.168.1.4");
+        assert_eq!(device_in(rotated_path(&path, 1)), "192.168.1.3");
+        assert_eq!(device_in(rotated_path(&path, 2)), "192.168.1.2");
+        assert!(!rotated_path(&path, 3).exists());
+    }
+}
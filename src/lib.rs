@@ -1,11 +1,34 @@
 pub mod dnstap {
     include!(concat!(env!("OUT_DIR"), "/dnstap.rs"));
 }
+pub mod availability;
 pub mod bridge;
+pub mod builder;
+pub mod detect;
+pub mod devices;
+pub mod discovery;
 pub mod dns;
+pub mod dns_message;
 pub mod dns_service;
+pub mod event;
+pub mod exec;
+pub mod fanout;
+pub mod health;
+pub mod http;
+pub mod json_lines;
 pub mod listener;
 pub mod messaging;
+pub mod metrics;
 pub mod mqtt;
+pub mod mqtt5_service;
 pub mod mqtt_service;
 pub mod net;
+pub mod outbox;
+pub mod presses;
+pub mod resilience;
+pub mod ringing;
+pub mod socket;
+pub mod source;
+pub mod supervisor;
+pub mod systemd;
+pub mod webhook;
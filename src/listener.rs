@@ -14,14 +14,19 @@
  * limitations under the License.
  */
 use async_trait::async_trait;
-use std::fmt::Debug;
+use std::{fmt::Debug, sync::Arc};
 use tokio::sync::mpsc::Sender;
 
-use crate::mqtt::MqttMessage;
+use crate::{mqtt::MqttMessage, source::SourceState};
 
 #[async_trait]
 pub trait DnsListener: Send + Sync + Debug {
     async fn start_listening(&self, message_sender: Sender<MqttMessage>) -> anyhow::Result<()>;
 
     fn box_clone(&self) -> Box<dyn DnsListener + Send + Sync>;
+
+    /// Connection state of the dnstap source feeding this listener, if it tracks one.
+    fn source(&self) -> Option<Arc<SourceState>> {
+        None
+    }
 }
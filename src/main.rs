@@ -14,21 +14,159 @@
  * limitations under the License.
  */
 
-use anyhow::{Context, Result};
-use clap::{Args, Parser};
+use anyhow::{bail, Result};
+use clap::{builder::NonEmptyStringValueParser, ArgAction, Args, Parser, Subcommand, ValueEnum};
+use log::info;
+use std::{net::SocketAddr, os::unix::net::UnixListener, time::Duration};
 
-use ring_detector_lib::bridge::Bridge;
+use ring_detector_lib::{
+    bridge::Bridge,
+    dns_service::DnsService,
+    exec::{ExecConfig, ExecService},
+    health::{self, HealthConfig},
+    json_lines::{JsonLinesService, JsonLinesTarget},
+    mqtt::{MqttConfig, MqttProtocol, RetainFlags},
+    net::IpCidr,
+    outbox::OutboxConfig,
+    resilience::ResilienceConfig,
+    socket::{self, InstanceLock, SocketOptions},
+    supervisor::SupervisorConfig,
+    systemd::{self, Notifier},
+    webhook::{WebhookConfig, WebhookService},
+};
 
 #[derive(Parser)]
-#[command(name = "ring-detector")]
+#[command(name = "ring-detector", subcommand_negates_reqs = true)]
 /// Works with your DNS server to detect when EZVIZ doorbell button is activated.
 struct Cli {
-    #[arg(short = 's', long, env)]
-    /// socket for dnstap listener
-    dns_socket: std::path::PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(short = 's', long, env, required = true)]
+    /// socket for dnstap listener; "@name" for a Linux abstract socket
+    dns_socket: Option<std::path::PathBuf>,
+
+    #[command(flatten)]
+    socket: SocketArgs,
+
+    #[arg(long, env, value_delimiter = ',')]
+    /// Only report doorbells with these addresses or CIDR blocks; may be repeated
+    allow_client: Vec<IpCidr>,
+
+    #[arg(long, env, value_delimiter = ',')]
+    /// Never report doorbells with these addresses or CIDR blocks; may be repeated
+    deny_client: Vec<IpCidr>,
+
+    #[arg(long, env, default_value_t = 900)]
+    /// Seconds a doorbell may make no DNS queries before it is reported offline
+    device_timeout: u64,
+
+    #[arg(long, env)]
+    /// Add a ringing binary sensor that stays on this many seconds after a press
+    ringing_hold: Option<u64>,
+
+    #[arg(long, env)]
+    /// File to keep press counts in so they survive restarts
+    press_counts_path: Option<std::path::PathBuf>,
+
+    #[arg(long, env)]
+    /// JSON file naming doorbells and giving their slug, area, manufacturer and model
+    device_map: Option<std::path::PathBuf>,
+
+    #[arg(long, env, default_value_t = 300)]
+    /// Seconds the resolver may send no dnstap frames before it is reported down
+    dns_source_timeout: u64,
 
     #[command(flatten)]
     mqtt: MqttArgs,
+
+    #[command(flatten)]
+    resilience: ResilienceArgs,
+
+    #[command(flatten)]
+    supervisor: SupervisorArgs,
+
+    #[arg(long, env)]
+    /// Address to serve Prometheus metrics and /healthz on, such as 0.0.0.0:9100
+    metrics_listen: Option<SocketAddr>,
+
+    #[command(flatten)]
+    health: HealthArgs,
+
+    #[command(flatten)]
+    outbox: OutboxArgs,
+
+    #[command(flatten)]
+    webhook: WebhookArgs,
+
+    #[command(flatten)]
+    exec: ExecArgs,
+
+    #[command(flatten)]
+    json: JsonArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Exit with an error unless the running instance reports itself healthy
+    Healthcheck(HealthcheckArgs),
+}
+
+#[derive(Args)]
+struct HealthcheckArgs {
+    #[arg(long, env)]
+    /// Address the running instance serves metrics on
+    metrics_listen: Option<SocketAddr>,
+
+    #[arg(long, default_value_t = 5)]
+    /// Seconds to wait for the running instance to answer
+    timeout: u64,
+}
+
+#[derive(Args)]
+struct HealthArgs {
+    #[arg(long, env, default_value_t = 300)]
+    /// Seconds MQTT may stay disconnected before the health check fails; 0 to ignore
+    health_mqtt_down: u64,
+
+    #[arg(long, env, default_value_t = 600)]
+    /// Seconds without a dnstap connection before the health check fails; 0 to ignore
+    health_dnstap_idle: u64,
+}
+
+impl HealthArgs {
+    fn config(self) -> HealthConfig {
+        let limit = |secs| (secs > 0).then(|| Duration::from_secs(secs));
+        HealthConfig {
+            mqtt_down: limit(self.health_mqtt_down),
+            dnstap_idle: limit(self.health_dnstap_idle),
+        }
+    }
+}
+
+#[derive(Args)]
+struct SocketArgs {
+    #[arg(long, env, value_parser = socket::parse_mode)]
+    /// Octal permissions for the dnstap socket, such as 660
+    dns_socket_mode: Option<u32>,
+
+    #[arg(long, env, value_parser = socket::parse_user)]
+    /// User name or ID that should own the dnstap socket
+    dns_socket_owner: Option<u32>,
+
+    #[arg(long, env, value_parser = socket::parse_group)]
+    /// Group name or ID that should own the dnstap socket
+    dns_socket_group: Option<u32>,
+}
+
+impl SocketArgs {
+    fn options(self) -> SocketOptions {
+        SocketOptions {
+            mode: self.dns_socket_mode,
+            owner: self.dns_socket_owner,
+            group: self.dns_socket_group,
+        }
+    }
 }
 
 #[derive(Args)]
@@ -53,33 +191,400 @@ struct MqttArgs {
     #[arg(long, env, default_value = "homeassistant/button/ring-detector")]
     /// MQTT topic prefix including autodiscovery
     mqtt_topic_prefix: Option<String>,
+
+    #[arg(long, env, default_value = "ring-detector", value_parser = NonEmptyStringValueParser::new())]
+    /// MQTT client ID
+    mqtt_client_id: String,
+
+    #[arg(long, env)]
+    /// Append a random suffix to the MQTT client ID
+    mqtt_client_id_random_suffix: bool,
+
+    #[arg(long, env, default_value_t = 30)]
+    /// MQTT keep-alive interval in seconds
+    mqtt_keep_alive: u64,
+
+    #[arg(long, env, default_value_t = true, action = ArgAction::Set)]
+    /// Start a clean MQTT session on every connection
+    mqtt_clean_session: bool,
+
+    #[arg(long, env, default_value_t = 100, value_parser = clap::value_parser!(u16).range(1..))]
+    /// Maximum number of in-flight MQTT messages
+    mqtt_inflight: u16,
+
+    #[arg(long, env, default_value_t = 10 * 1024)]
+    /// Maximum MQTT packet size in bytes
+    mqtt_max_packet_size: usize,
+
+    #[arg(long, env, default_value_t = 1, value_parser = clap::value_parser!(u8).range(0..=2))]
+    /// MQTT QoS level for published messages
+    mqtt_qos: u8,
+
+    #[arg(long, env, default_value_t = false, action = ArgAction::Set)]
+    /// Retain discovery config messages
+    mqtt_retain_config: bool,
+
+    #[arg(long, env, default_value_t = false, action = ArgAction::Set)]
+    /// Retain doorbell action messages
+    mqtt_retain_action: bool,
+
+    #[arg(long, env, default_value_t = true, action = ArgAction::Set)]
+    /// Retain bridge status and doorbell availability messages (sensor states are always retained)
+    mqtt_retain_status: bool,
+
+    #[arg(long, env, value_enum, default_value_t = MqttProtocolArg::V311)]
+    /// MQTT protocol version
+    mqtt_protocol: MqttProtocolArg,
+
+    #[arg(long, env)]
+    /// Seconds the broker may hold a press before discarding it (MQTT v5 only)
+    mqtt_message_expiry: Option<u64>,
+
+    #[arg(long, env)]
+    /// Seconds the broker keeps the session after a disconnect (MQTT v5 only)
+    mqtt_session_expiry: Option<u64>,
+}
+
+#[derive(Args)]
+struct ResilienceArgs {
+    #[arg(long, env, default_value_t = 10)]
+    /// Seconds before a single publish attempt is abandoned
+    publish_timeout: u64,
+
+    #[arg(long, env, default_value_t = 2)]
+    /// Number of retries for publish attempts that failed transiently
+    publish_retries: u32,
+
+    #[arg(long, env, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+    /// Consecutive failures before a publisher's circuit opens
+    circuit_failure_threshold: u32,
+
+    #[arg(long, env, default_value_t = 30)]
+    /// Seconds an open circuit rejects messages before trying the publisher again
+    circuit_open_duration: u64,
+}
+
+impl ResilienceArgs {
+    fn config(self) -> ResilienceConfig {
+        ResilienceConfig {
+            timeout: Duration::from_secs(self.publish_timeout),
+            retries: self.publish_retries,
+            failure_threshold: self.circuit_failure_threshold,
+            open_duration: Duration::from_secs(self.circuit_open_duration),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Args)]
+struct SupervisorArgs {
+    #[arg(long, env, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+    /// Consecutive failures before the DNS listener is reported down
+    listener_failure_threshold: u32,
+
+    #[arg(long, env, default_value_t = 60)]
+    /// Longest delay in seconds between DNS listener restarts
+    listener_max_backoff: u64,
+
+    #[arg(long, env)]
+    /// Exit with an error once the DNS listener is reported down
+    exit_on_listener_failure: bool,
+}
+
+impl SupervisorArgs {
+    fn config(self) -> SupervisorConfig {
+        SupervisorConfig {
+            failure_threshold: self.listener_failure_threshold,
+            max_backoff: Duration::from_secs(self.listener_max_backoff),
+            exit_on_failure: self.exit_on_listener_failure,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Args)]
+struct OutboxArgs {
+    #[arg(long, env)]
+    /// Spool file for messages that could not be delivered
+    outbox_path: Option<std::path::PathBuf>,
+
+    #[arg(long, env, default_value_t = 3600)]
+    /// Seconds to keep undelivered messages before dropping them
+    outbox_max_age: u64,
+
+    #[arg(long, env, default_value_t = 1024 * 1024)]
+    /// Maximum size of the spool file in bytes
+    outbox_max_bytes: u64,
+}
+
+impl OutboxArgs {
+    fn config(self) -> Option<OutboxConfig> {
+        let mut config = OutboxConfig::new(self.outbox_path?);
+        config.max_age = Duration::from_secs(self.outbox_max_age);
+        config.max_bytes = self.outbox_max_bytes;
+        Some(config)
+    }
+}
+
+#[derive(Args)]
+struct WebhookArgs {
+    #[arg(long, env, value_delimiter = ',')]
+    /// URL to call for each doorbell press and dnstap source change; may be repeated
+    webhook_url: Vec<String>,
+
+    #[arg(long, env, default_value = "POST")]
+    /// HTTP method for webhook requests
+    webhook_method: reqwest::Method,
+
+    #[arg(long, env, value_parser = parse_header)]
+    /// Extra webhook header as "Name: value"; may be repeated
+    webhook_header: Vec<(String, String)>,
+
+    #[arg(long, env)]
+    /// JSON body template with {{device}}, {{qname}}, {{resolver}}, {{rcode}},
+    /// {{timestamp}}, {{late}}, {{topic}} and {{payload}} placeholders
+    webhook_body_template: Option<String>,
+
+    #[arg(long, env, default_value_t = 10)]
+    /// Webhook request timeout in seconds
+    webhook_timeout: u64,
+
+    #[arg(long, env, default_value_t = 2)]
+    /// Number of retries for failed webhook requests, per URL
+    webhook_retries: u32,
+
+    #[arg(long, env)]
+    /// Secret for HMAC-SHA256 signing of webhook bodies
+    webhook_hmac_secret: Option<String>,
+}
+
+fn parse_header(value: &str) -> Result<(String, String), String> {
+    match value.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err(format!("expected \"Name: value\", got \"{}\"", value)),
+    }
+}
+
+impl WebhookArgs {
+    fn config(self) -> Option<WebhookConfig> {
+        if self.webhook_url.is_empty() {
+            return None;
+        }
+        let mut config = WebhookConfig::new(self.webhook_url);
+        config.method = self.webhook_method;
+        config.headers = self.webhook_header;
+        if let Some(template) = self.webhook_body_template {
+            config.body_template = template;
+        }
+        config.timeout = Duration::from_secs(self.webhook_timeout);
+        config.retries = self.webhook_retries;
+        config.hmac_secret = self.webhook_hmac_secret;
+        Some(config)
+    }
+}
+
+#[derive(Args)]
+struct ExecArgs {
+    #[arg(long, env)]
+    /// Program to run for each doorbell press and dnstap source change
+    exec_program: Option<std::path::PathBuf>,
+
+    #[arg(long, env, allow_hyphen_values = true)]
+    /// Argument to pass to the program; may be repeated
+    exec_arg: Vec<String>,
+
+    #[arg(long, env, default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..))]
+    /// Maximum number of commands running at once
+    exec_concurrency: u16,
+
+    #[arg(long, env, default_value_t = 30)]
+    /// Seconds before a running command is killed
+    exec_timeout: u64,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+impl ExecArgs {
+    fn config(self) -> Option<ExecConfig> {
+        let mut config = ExecConfig::new(self.exec_program?);
+        config.args = self.exec_arg;
+        config.concurrency = self.exec_concurrency.into();
+        config.timeout = Duration::from_secs(self.exec_timeout);
+        Some(config)
+    }
+}
+
+#[derive(Args)]
+struct JsonArgs {
+    #[arg(long, env)]
+    /// Write each doorbell press and dnstap source change as a JSON line to this file, or "-" for stdout
+    json_output: Option<std::path::PathBuf>,
+
+    #[arg(long, env)]
+    /// Rotate the JSON output file once it reaches this many bytes
+    json_max_bytes: Option<u64>,
+
+    #[arg(long, env, default_value_t = 5)]
+    /// Number of rotated JSON output files to keep
+    json_max_files: usize,
+}
+
+impl JsonArgs {
+    fn target(self) -> Option<JsonLinesTarget> {
+        let path = self.json_output?;
+        if path.as_os_str() == "-" {
+            return Some(JsonLinesTarget::Stdout);
+        }
+        Some(JsonLinesTarget::File {
+            path,
+            max_bytes: self.json_max_bytes,
+            max_files: self.json_max_files,
+        })
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum MqttProtocolArg {
+    #[value(name = "3.1.1")]
+    V311,
+    #[value(name = "5")]
+    V5,
+}
+
+impl From<MqttProtocolArg> for MqttProtocol {
+    fn from(value: MqttProtocolArg) -> Self {
+        match value {
+            MqttProtocolArg::V311 => MqttProtocol::V311,
+            MqttProtocolArg::V5 => MqttProtocol::V5,
+        }
+    }
+}
+
+impl MqttArgs {
+    fn config(self) -> Result<Option<MqttConfig>> {
+        // Due to clap requires_all, if one MQTT parameter is there, they all are.
+        let Some(host) = self.mqtt_host else {
+            return Ok(None);
+        };
+        let mut config = MqttConfig::new(
+            host,
+            self.mqtt_port.unwrap(),
+            self.mqtt_username.unwrap(),
+            self.mqtt_password.unwrap(),
+            self.mqtt_topic_prefix.unwrap(),
+        );
+        config.client_id = self.mqtt_client_id;
+        config.client_id_random_suffix = self.mqtt_client_id_random_suffix;
+        config.keep_alive = Duration::from_secs(self.mqtt_keep_alive);
+        config.clean_session = self.mqtt_clean_session;
+        config.inflight = self.mqtt_inflight;
+        config.max_packet_size = self.mqtt_max_packet_size;
+        config.qos = rumqttc::qos(self.mqtt_qos).unwrap();
+        config.retain = RetainFlags {
+            config: self.mqtt_retain_config,
+            action: self.mqtt_retain_action,
+            status: self.mqtt_retain_status,
+        };
+        config.protocol = self.mqtt_protocol.into();
+        config.message_expiry = self.mqtt_message_expiry.map(Duration::from_secs);
+        config.session_expiry = self.mqtt_session_expiry.map(Duration::from_secs);
+        config.validate()?;
+        Ok(Some(config))
+    }
+}
+
+fn main() -> Result<()> {
     env_logger::builder()
         .format_timestamp(Some(env_logger::TimestampPrecision::Millis))
         .init();
 
     let cli = Cli::parse();
 
-    if cli.dns_socket.exists() {
-        std::fs::remove_file(&cli.dns_socket)
-            .with_context(|| format!("Cannot remove file {}", &cli.dns_socket.display()))?;
+    // These clear their environment variables, which is only sound before the
+    // runtime starts its worker threads.
+    let activated = systemd::activated_listener()?;
+    let notifier = Notifier::from_env()?;
+
+    tokio::runtime::Runtime::new()?.block_on(run(cli, activated, notifier))
+}
+
+async fn run(cli: Cli, activated: Option<UnixListener>, notifier: Notifier) -> Result<()> {
+    if let Some(Command::Healthcheck(args)) = cli.command {
+        let Some(addr) = args.metrics_listen.or(cli.metrics_listen) else {
+            bail!("The health check needs --metrics-listen");
+        };
+        return health::probe(addr, Duration::from_secs(args.timeout)).await;
     }
+    // Only optional so that subcommands can run without it.
+    let dns_socket = cli.dns_socket.expect("clap requires --dns-socket");
 
-    // Due to clap requires_all, if one MQTT parameter is there, they all are.
-    let bridge = match cli.mqtt.mqtt_host {
-        Some(host) => Bridge::with_mqtt(
-            cli.dns_socket,
-            host,
-            cli.mqtt.mqtt_port.unwrap(),
-            cli.mqtt.mqtt_username.unwrap(),
-            cli.mqtt.mqtt_password.unwrap(),
-            cli.mqtt.mqtt_topic_prefix.unwrap(),
-        ),
-        None => Bridge::new(cli.dns_socket),
+    // Held until exit so a second instance cannot remove our socket. Abstract
+    // sockets need no lock because binding the same name twice fails.
+    let is_abstract = socket::abstract_name(&dns_socket).is_some();
+    let _lock = if is_abstract {
+        None
+    } else {
+        Some(InstanceLock::for_socket(&dns_socket)?)
     };
 
-    bridge.start().await
+    let dns_service = match activated {
+        Some(listener) => {
+            info!("Using dnstap socket passed by systemd");
+            DnsService::with_listener(dns_socket.clone(), listener)
+        }
+        None => {
+            let options = cli.socket.options();
+            if is_abstract && options != SocketOptions::default() {
+                bail!("Abstract sockets have no file mode or owner to set");
+            }
+            if !is_abstract {
+                socket::remove_stale_socket(&dns_socket)?;
+            }
+            DnsService::new(dns_socket.clone()).with_socket_options(options)
+        }
+    };
+
+    let mut builder = Bridge::builder()
+        .dns_service(dns_service)
+        .notifier(notifier)
+        .resilience(cli.resilience.config())
+        .supervisor(cli.supervisor.config())
+        .health(cli.health.config())
+        .device_timeout(Duration::from_secs(cli.device_timeout))
+        .source_timeout(Duration::from_secs(cli.dns_source_timeout));
+    if let Some(addr) = cli.metrics_listen {
+        builder = builder.metrics_listener(addr);
+    }
+    if let Some(path) = cli.device_map {
+        builder = builder.device_map(path);
+    }
+    if let Some(path) = cli.press_counts_path {
+        builder = builder.press_counts(path);
+    }
+    if let Some(hold) = cli.ringing_hold {
+        builder = builder.ringing_hold(Duration::from_secs(hold));
+    }
+    for cidr in cli.allow_client {
+        builder = builder.allow_clients(cidr);
+    }
+    for cidr in cli.deny_client {
+        builder = builder.deny_clients(cidr);
+    }
+    if let Some(config) = cli.mqtt.config()? {
+        builder = builder.mqtt(config);
+    }
+    if let Some(config) = cli.webhook.config() {
+        builder = builder.publisher(Box::new(WebhookService::new(config)?));
+    }
+    if let Some(config) = cli.exec.config() {
+        builder = builder.publisher(Box::new(ExecService::new(config)));
+    }
+    if let Some(target) = cli.json.target() {
+        builder = builder.publisher(Box::new(JsonLinesService::new(target)?));
+    }
+    if let Some(config) = cli.outbox.config() {
+        builder = builder.outbox(config);
+    }
+
+    builder.build()?.start().await
 }
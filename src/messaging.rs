@@ -13,13 +13,29 @@
  * See the License for the specific language governing permissions and
  * limitations under the License.
  */
-use crate::mqtt::MqttMessage;
+use crate::mqtt::{ConnectionState, MqttMessage};
 use async_trait::async_trait;
-use std::fmt::Debug;
+use std::{fmt::Debug, sync::Arc};
 
 #[async_trait]
 pub trait MessagePublisher: Send + Sync + Debug {
+    /// Short label used in logs and delivery counters.
+    fn name(&self) -> &str {
+        "publisher"
+    }
+    /// Starts any background work the publisher needs, such as driving its connection.
+    async fn connect(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
     async fn publish(&self, message: MqttMessage) -> anyhow::Result<()>;
     async fn send_birth(&self) -> anyhow::Result<()>;
     async fn send_death(&self) -> anyhow::Result<()>;
+    /// Publishes the bridge's availability, such as `offline` while no DNS listener is up.
+    async fn send_status(&self, _status: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+    /// The broker connection this publisher depends on, if it has one.
+    fn connection(&self) -> Option<Arc<ConnectionState>> {
+        None
+    }
 }
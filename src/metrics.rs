@@ -0,0 +1,450 @@
+/*
+ * Copyright 2025 Kenny Root
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::{
+    fanout::PublisherSlot,
+    http::{Handler, Response},
+    mqtt::MqttMessage,
+};
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::{
+    mpsc::{Sender, WeakSender},
+    watch,
+};
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Why a dnstap frame could not be turned into a DNS message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeFailure {
+    /// The frame is not a dnstap protobuf.
+    Protobuf,
+    /// The dnstap frame has no message.
+    MissingMessage,
+    /// The query address is missing or not an IP address.
+    ClientAddress,
+    /// The message carries neither a query nor a response.
+    MissingDnsMessage,
+    /// The DNS message itself is malformed.
+    Dns,
+}
+
+impl DecodeFailure {
+    pub const ALL: [DecodeFailure; 5] = [
+        DecodeFailure::Protobuf,
+        DecodeFailure::MissingMessage,
+        DecodeFailure::ClientAddress,
+        DecodeFailure::MissingDnsMessage,
+        DecodeFailure::Dns,
+    ];
+
+    /// The `reason` label value.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DecodeFailure::Protobuf => "protobuf",
+            DecodeFailure::MissingMessage => "missing_message",
+            DecodeFailure::ClientAddress => "client_address",
+            DecodeFailure::MissingDnsMessage => "missing_dns_message",
+            DecodeFailure::Dns => "dns",
+        }
+    }
+}
+
+impl fmt::Display for DecodeFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Counters describing the bridge, rendered in the Prometheus text format.
+///
+/// Metric names are part of the interface; add new ones rather than renaming.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Watched so readiness can wait for every listener to bind.
+    listeners_bound: watch::Sender<u64>,
+    connections_accepted: AtomicU64,
+    connections_active: AtomicU64,
+    frames: AtomicU64,
+    decode_failures: [AtomicU64; DecodeFailure::ALL.len()],
+    matches: Mutex<BTreeMap<(String, String), u64>>,
+    /// Read when scraped, so the depth is current even when nothing is received.
+    event_queue: Mutex<Option<WeakSender<MqttMessage>>>,
+    publishers: Mutex<Vec<PublisherSlot>>,
+    /// When a dnstap connection last opened or closed.
+    last_connection: Mutex<Option<Instant>>,
+}
+
+/// Counts a dnstap listener as bound until dropped.
+#[derive(Debug)]
+pub struct BoundListener(Arc<Metrics>);
+
+impl Drop for BoundListener {
+    fn drop(&mut self) {
+        self.0.listeners_bound.send_modify(|bound| *bound -= 1);
+    }
+}
+
+/// Counts a dnstap connection as active until dropped.
+#[derive(Debug)]
+pub struct ActiveConnection(Arc<Metrics>);
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.connections_active.fetch_sub(1, Ordering::Relaxed);
+        self.0.connection_seen();
+    }
+}
+
+impl Metrics {
+    pub fn listener_bound(self: &Arc<Self>) -> BoundListener {
+        self.listeners_bound.send_modify(|bound| *bound += 1);
+        BoundListener(Arc::clone(self))
+    }
+
+    pub fn listeners_bound(&self) -> u64 {
+        *self.listeners_bound.borrow()
+    }
+
+    /// Waits until at least `count` listeners are bound at once.
+    pub async fn wait_bound(&self, count: u64) {
+        let mut bound = self.listeners_bound.subscribe();
+        // The sender lives as long as `self`, so this cannot fail.
+        let _ = bound.wait_for(|bound| *bound >= count).await;
+    }
+
+    pub fn connection_accepted(self: &Arc<Self>) -> ActiveConnection {
+        self.connections_accepted.fetch_add(1, Ordering::Relaxed);
+        self.connections_active.fetch_add(1, Ordering::Relaxed);
+        self.connection_seen();
+        ActiveConnection(Arc::clone(self))
+    }
+
+    fn connection_seen(&self) {
+        *self.last_connection.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// Time since a dnstap connection was last open, or `None` if there never was one.
+    pub fn since_last_connection(&self) -> Option<Duration> {
+        if self.connections_active() > 0 {
+            return Some(Duration::ZERO);
+        }
+        self.last_connection.lock().unwrap().map(|at| at.elapsed())
+    }
+
+    pub fn connections_accepted(&self) -> u64 {
+        self.connections_accepted.load(Ordering::Relaxed)
+    }
+
+    pub fn connections_active(&self) -> u64 {
+        self.connections_active.load(Ordering::Relaxed)
+    }
+
+    pub fn frame_read(&self) {
+        self.frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames.load(Ordering::Relaxed)
+    }
+
+    pub fn decode_failed(&self, reason: DecodeFailure) {
+        self.decode_failures[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn decode_failures(&self, reason: DecodeFailure) -> u64 {
+        self.decode_failures[reason as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn matched(&self, rule: &str, device: &str) {
+        let mut matches = self.matches.lock().unwrap();
+        *matches
+            .entry((rule.to_owned(), device.to_owned()))
+            .or_default() += 1;
+    }
+
+    /// Reports how many messages wait in the queue `sender` feeds.
+    pub fn set_event_queue(&self, sender: &Sender<MqttMessage>) {
+        *self.event_queue.lock().unwrap() = Some(sender.downgrade());
+    }
+
+    pub fn event_queue_depth(&self) -> u64 {
+        let sender = self
+            .event_queue
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(WeakSender::upgrade);
+        sender.map_or(0, |sender| {
+            (sender.max_capacity() - sender.capacity()) as u64
+        })
+    }
+
+    /// Reports delivery counters and connection state for these publishers.
+    pub fn set_publishers(&self, publishers: &[PublisherSlot]) {
+        *self.publishers.lock().unwrap() = publishers.to_vec();
+    }
+
+    /// Serves the metrics at `/metrics`.
+    pub fn handler(self: &Arc<Self>) -> Handler {
+        let metrics = Arc::clone(self);
+        Arc::new(move |path| {
+            (path == "/metrics").then(|| Response::new(200, CONTENT_TYPE, metrics.render()))
+        })
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counter = |value: &AtomicU64| vec![(String::new(), value.load(Ordering::Relaxed))];
+
+        family(
+            &mut out,
+            "ring_detector_dnstap_listeners_bound",
+            "gauge",
+            "dnstap listener sockets currently bound.",
+            vec![(String::new(), self.listeners_bound())],
+        );
+        family(
+            &mut out,
+            "ring_detector_dnstap_connections_accepted_total",
+            "counter",
+            "dnstap connections accepted.",
+            counter(&self.connections_accepted),
+        );
+        family(
+            &mut out,
+            "ring_detector_dnstap_connections_active",
+            "gauge",
+            "dnstap connections currently open.",
+            counter(&self.connections_active),
+        );
+        family(
+            &mut out,
+            "ring_detector_dnstap_frames_total",
+            "counter",
+            "dnstap data frames read.",
+            counter(&self.frames),
+        );
+        family(
+            &mut out,
+            "ring_detector_dnstap_decode_failures_total",
+            "counter",
+            "dnstap frames that could not be decoded, by reason.",
+            DecodeFailure::ALL
+                .iter()
+                .map(|reason| {
+                    let labels = labels(&[("reason", reason.as_str())]);
+                    (labels, self.decode_failures(*reason))
+                })
+                .collect(),
+        );
+        family(
+            &mut out,
+            "ring_detector_matches_total",
+            "counter",
+            "Queries matching a detection rule, by rule and device.",
+            self.matches
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|((rule, device), count)| {
+                    (labels(&[("rule", rule), ("device", device)]), *count)
+                })
+                .collect(),
+        );
+        family(
+            &mut out,
+            "ring_detector_event_queue_depth",
+            "gauge",
+            "Messages waiting between the DNS listeners and the publishers.",
+            vec![(String::new(), self.event_queue_depth())],
+        );
+
+        let publishers = self.publishers.lock().unwrap();
+        let per_publisher = |value: &dyn Fn(&PublisherSlot) -> u64| {
+            publishers
+                .iter()
+                .map(|slot| (labels(&[("publisher", slot.stats.name())]), value(slot)))
+                .collect()
+        };
+        family(
+            &mut out,
+            "ring_detector_events_published_total",
+            "counter",
+            "Messages delivered, by publisher.",
+            per_publisher(&|slot| slot.stats.published()),
+        );
+        family(
+            &mut out,
+            "ring_detector_events_failed_total",
+            "counter",
+            "Messages a publisher failed to deliver.",
+            per_publisher(&|slot| slot.stats.failed()),
+        );
+        family(
+            &mut out,
+            "ring_detector_events_spooled_total",
+            "counter",
+            "Messages an outbox kept to deliver later.",
+            per_publisher(&|slot| slot.stats.spooled()),
+        );
+        family(
+            &mut out,
+            "ring_detector_events_dropped_total",
+            "counter",
+            "Messages dropped because a publisher's queue was full.",
+            per_publisher(&|slot| slot.stats.dropped()),
+        );
+        family(
+            &mut out,
+            "ring_detector_publisher_queue_depth",
+            "gauge",
+            "Messages waiting for each publisher.",
+            per_publisher(&|slot| slot.stats.queued()),
+        );
+        family(
+            &mut out,
+            "ring_detector_mqtt_connected",
+            "gauge",
+            "Whether each MQTT publisher is connected to its broker.",
+            publishers
+                .iter()
+                .filter_map(|slot| {
+                    let connection = slot.publisher.connection()?;
+                    let labels = labels(&[("publisher", slot.stats.name())]);
+                    Some((labels, connection.is_connected() as u64))
+                })
+                .collect(),
+        );
+        out
+    }
+}
+
+/// Writes one metric family; families without samples still get their metadata.
+fn family(out: &mut String, name: &str, kind: &str, help: &str, samples: Vec<(String, u64)>) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{}{} {}", name, labels, value);
+    }
+}
+
+fn labels(pairs: &[(&str, &str)]) -> String {
+    let pairs: Vec<String> = pairs
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape_label(value)))
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{json_lines::JsonLinesService, json_lines::JsonLinesTarget};
+
+    #[test]
+    fn test_render() {
+        let metrics = Arc::new(Metrics::default());
+        let connection = metrics.connection_accepted();
+        metrics.connection_accepted();
+        let _bound = metrics.listener_bound();
+        metrics.frame_read();
+        metrics.decode_failed(DecodeFailure::Dns);
+        metrics.matched("ezviz-us", "192.168.1.100");
+        metrics.matched("ezviz-us", "192.168.1.100");
+        metrics.matched("odd\"rule", "192.168.1.101");
+        metrics.set_publishers(&[PublisherSlot::new(Box::new(
+            JsonLinesService::new(JsonLinesTarget::Stdout).unwrap(),
+        ))]);
+
+        let text = metrics.render();
+        for line in [
+            "ring_detector_dnstap_listeners_bound 1",
+            "# TYPE ring_detector_dnstap_connections_accepted_total counter",
+            "ring_detector_dnstap_connections_accepted_total 2",
+            "ring_detector_dnstap_connections_active 1",
+            "ring_detector_dnstap_frames_total 1",
+            "ring_detector_dnstap_decode_failures_total{reason=\"dns\"} 1",
+            "ring_detector_dnstap_decode_failures_total{reason=\"protobuf\"} 0",
+            "ring_detector_matches_total{rule=\"ezviz-us\",device=\"192.168.1.100\"} 2",
+            "ring_detector_matches_total{rule=\"odd\\\"rule\",device=\"192.168.1.101\"} 1",
+            "ring_detector_events_published_total{publisher=\"json\"} 0",
+            "ring_detector_publisher_queue_depth{publisher=\"json\"} 0",
+            "# TYPE ring_detector_mqtt_connected gauge",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {:?} in\n{}",
+                line,
+                text
+            );
+        }
+        // Only MQTT publishers report a connection.
+        assert!(!text.contains("ring_detector_mqtt_connected{"));
+
+        assert_eq!(metrics.since_last_connection(), Some(Duration::ZERO));
+        drop(connection);
+        assert_eq!(metrics.connections_active(), 0);
+        assert!(metrics.since_last_connection().is_some());
+    }
+
+    #[test]
+    fn test_event_queue_depth_is_read_when_scraped() {
+        let metrics = Metrics::default();
+        assert_eq!(metrics.event_queue_depth(), 0);
+
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        metrics.set_event_queue(&tx);
+        for _ in 0..3 {
+            tx.try_send(MqttMessage::Publish {
+                topic: "status".to_string(),
+                payload: vec![],
+            })
+            .unwrap();
+        }
+        assert!(metrics
+            .render()
+            .lines()
+            .any(|l| l == "ring_detector_event_queue_depth 3"));
+
+        drop((tx, rx));
+        assert_eq!(metrics.event_queue_depth(), 0);
+    }
+
+    #[test]
+    fn test_handler() {
+        let metrics = Arc::new(Metrics::default());
+        let handler = metrics.handler();
+        let response = handler("/metrics").unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.content_type, CONTENT_TYPE);
+        assert!(handler("/").is_none());
+    }
+}
@@ -14,12 +14,293 @@
  * limitations under the License.
  */
 
-use rumqttc::{AsyncClient, EventLoop, MqttOptions};
-use std::time::Duration;
+use crate::event::{BridgeEvent, DoorbellEvent, SourceEvent};
+use log::{info, warn};
+use rumqttc::{v5, AsyncClient, ConnectionError, Event, EventLoop, MqttOptions, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
-#[derive(Debug)]
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum MqttMessage {
-    Publish { topic: String, payload: Vec<u8> },
+    Publish {
+        topic: String,
+        payload: Vec<u8>,
+    },
+    /// A doorbell press, carrying the event it was rendered from.
+    Event {
+        topic: String,
+        payload: Vec<u8>,
+        event: DoorbellEvent,
+    },
+    /// A dnstap source going down or coming back, carrying the event.
+    Source {
+        topic: String,
+        payload: Vec<u8>,
+        event: SourceEvent,
+    },
+    /// Home Assistant discovery for an entity of another `component`, such as a
+    /// `binary_sensor`, next to the doorbell buttons.
+    Discovery {
+        component: String,
+        topic: String,
+        payload: Vec<u8>,
+    },
+}
+
+impl MqttMessage {
+    pub fn topic(&self) -> &str {
+        match self {
+            MqttMessage::Publish { topic, .. }
+            | MqttMessage::Event { topic, .. }
+            | MqttMessage::Source { topic, .. }
+            | MqttMessage::Discovery { topic, .. } => topic,
+        }
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        match self {
+            MqttMessage::Publish { payload, .. }
+            | MqttMessage::Event { payload, .. }
+            | MqttMessage::Source { payload, .. }
+            | MqttMessage::Discovery { payload, .. } => payload,
+        }
+    }
+
+    /// The Home Assistant component this message is discovery for, if it is not
+    /// the one in the topic prefix.
+    pub fn component(&self) -> Option<&str> {
+        match self {
+            MqttMessage::Discovery { component, .. } => Some(component),
+            _ => None,
+        }
+    }
+
+    /// The doorbell press this message announces, if it is one.
+    pub fn event(&self) -> Option<&DoorbellEvent> {
+        match self {
+            MqttMessage::Event { event, .. } => Some(event),
+            _ => None,
+        }
+    }
+
+    /// The press or source event this message announces, for publishers and
+    /// subscribers outside MQTT.
+    pub fn bridge_event(&self) -> Option<BridgeEvent> {
+        match self {
+            MqttMessage::Publish { .. } | MqttMessage::Discovery { .. } => None,
+            MqttMessage::Event { event, .. } => Some(BridgeEvent::Press(event.clone())),
+            MqttMessage::Source { event, .. } => Some(BridgeEvent::Source(event.clone())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MqttProtocol {
+    V311,
+    V5,
+}
+
+/// Whether the broker should retain messages sent to each kind of topic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetainFlags {
+    pub config: bool,
+    pub action: bool,
+    pub status: bool,
+}
+
+impl RetainFlags {
+    /// Returns whether a message sent to `topic_suffix` should be retained.
+    ///
+    /// Sensor states are always retained: they change rarely, and a dashboard
+    /// opened or restarted later would otherwise show nothing until the next one.
+    pub fn for_topic(&self, topic_suffix: &str) -> bool {
+        if topic_suffix.ends_with("/config") {
+            self.config
+        } else if topic_suffix.ends_with("/action") {
+            self.action
+        } else if topic_suffix.ends_with("/availability") {
+            self.status
+        } else {
+            topic_suffix.ends_with("/state")
+        }
+    }
+}
+
+impl Default for RetainFlags {
+    fn default() -> Self {
+        Self {
+            config: false,
+            action: false,
+            status: true,
+        }
+    }
+}
+
+/// An expiry interval in the seconds MQTT 5 carries, which
+/// [`MqttConfig::validate`] has checked will fit.
+pub fn expiry_seconds(expiry: Duration) -> u32 {
+    u32::try_from(expiry.as_secs()).unwrap_or(u32::MAX)
+}
+
+/// Where an MQTT publisher sends a message with this topic suffix.
+///
+/// The topic prefix is expected to look like the default,
+/// `<discovery prefix>/<component>/<node id>`. Discovery for another `component`
+/// swaps it in so Home Assistant finds it; all others go under the prefix.
+pub fn publish_topic(topic_prefix: &str, component: Option<&str>, topic_suffix: &str) -> String {
+    let Some(component) = component else {
+        return format!("{}/{}", topic_prefix, topic_suffix);
+    };
+    match topic_prefix.rsplitn(3, '/').collect::<Vec<_>>()[..] {
+        [node, _, root] => format!("{}/{}/{}/{}", root, component, node, topic_suffix),
+        [node, ..] => format!("homeassistant/{}/{}/{}", component, node, topic_suffix),
+        [] => unreachable!("rsplitn always yields at least one item"),
+    }
+}
+
+/// Sets the `~` base of a Home Assistant discovery payload to `topic_prefix`.
+///
+/// Discovery payloads are built without knowing where they will be published, so
+/// their topics are written relative to `~` and the publisher fills it in.
+/// Anything else is returned as is.
+pub fn discovery_payload(topic_suffix: &str, topic_prefix: &str, payload: Vec<u8>) -> Vec<u8> {
+    if !topic_suffix.ends_with("/config") {
+        return payload;
+    }
+    match serde_json::from_slice::<serde_json::Value>(&payload) {
+        Ok(serde_json::Value::Object(mut config)) if config.contains_key("~") => {
+            config.insert("~".to_string(), topic_prefix.into());
+            serde_json::to_vec(&config).unwrap_or(payload)
+        }
+        _ => payload,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    pub password: String,
+    pub topic_prefix: String,
+    pub client_id: String,
+    /// Append a random suffix to `client_id` so several instances can share a broker.
+    pub client_id_random_suffix: bool,
+    pub keep_alive: Duration,
+    pub clean_session: bool,
+    pub inflight: u16,
+    pub max_packet_size: usize,
+    pub qos: QoS,
+    pub retain: RetainFlags,
+    pub protocol: MqttProtocol,
+    /// How long the broker may hold a press before discarding it (MQTT v5 only).
+    pub message_expiry: Option<Duration>,
+    /// How long the broker keeps the session after a disconnect (MQTT v5 only).
+    pub session_expiry: Option<Duration>,
+}
+
+impl MqttConfig {
+    pub fn new(
+        host: String,
+        port: u16,
+        username: String,
+        password: String,
+        topic_prefix: String,
+    ) -> Self {
+        Self {
+            host,
+            port,
+            username,
+            password,
+            topic_prefix,
+            client_id: "ring-detector".to_owned(),
+            client_id_random_suffix: false,
+            keep_alive: Duration::from_secs(30),
+            clean_session: true,
+            inflight: 100,
+            max_packet_size: 10 * 1024,
+            qos: QoS::AtLeastOnce,
+            retain: RetainFlags::default(),
+            protocol: MqttProtocol::V311,
+            message_expiry: None,
+            session_expiry: None,
+        }
+    }
+
+    /// Returns the client ID to present to the broker, with the random suffix if requested.
+    pub fn effective_client_id(&self) -> String {
+        if self.client_id_random_suffix {
+            let suffix = RandomState::new().build_hasher().finish() as u32;
+            format!("{}-{:08x}", self.client_id, suffix)
+        } else {
+            self.client_id.clone()
+        }
+    }
+
+    /// Rejects options the protocol cannot carry, rather than dropping them.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.protocol == MqttProtocol::V311 {
+            if self.message_expiry.is_some() {
+                anyhow::bail!("Message expiry needs MQTT 5");
+            }
+            if self.session_expiry.is_some() {
+                anyhow::bail!("Session expiry needs MQTT 5");
+            }
+        }
+        for (name, expiry) in [
+            ("Message expiry", self.message_expiry),
+            ("Session expiry", self.session_expiry),
+        ] {
+            if let Some(expiry) = expiry {
+                u32::try_from(expiry.as_secs()).map_err(|_| {
+                    anyhow::anyhow!("{} of {:?} is longer than MQTT allows", name, expiry)
+                })?;
+            }
+        }
+        u32::try_from(self.max_packet_size).map_err(|_| {
+            anyhow::anyhow!(
+                "Maximum packet size {} is larger than MQTT allows",
+                self.max_packet_size
+            )
+        })?;
+        Ok(())
+    }
+
+    pub fn options(&self) -> MqttOptions {
+        let mut options = MqttOptions::new(self.effective_client_id(), &self.host, self.port);
+        options
+            .set_credentials(&self.username, &self.password)
+            .set_keep_alive(self.keep_alive)
+            .set_clean_session(self.clean_session)
+            .set_inflight(self.inflight)
+            .set_max_packet_size(self.max_packet_size, self.max_packet_size);
+        options
+    }
+
+    pub fn v5_options(&self) -> v5::MqttOptions {
+        let mut options = v5::MqttOptions::new(self.effective_client_id(), &self.host, self.port);
+        options
+            .set_credentials(&self.username, &self.password)
+            .set_keep_alive(self.keep_alive)
+            .set_clean_start(self.clean_session)
+            .set_outgoing_inflight_upper_limit(self.inflight)
+            .set_max_packet_size(Some(
+                u32::try_from(self.max_packet_size).unwrap_or(u32::MAX),
+            ))
+            .set_session_expiry_interval(self.session_expiry.map(expiry_seconds));
+        options
+    }
 }
 
 pub struct MqttClient {
@@ -28,15 +309,24 @@ pub struct MqttClient {
 }
 
 impl MqttClient {
-    pub fn new(host: &str, port: u16, username: &str, password: &str) -> MqttClient {
-        let client_id = "ring-detector".to_owned();
+    pub fn new(config: &MqttConfig) -> MqttClient {
+        let (client, eventloop) = AsyncClient::new(config.options(), 10);
 
-        let mut options = MqttOptions::new(client_id, host, port);
-        options
-            .set_credentials(username, password)
-            .set_keep_alive(Duration::from_secs(30));
+        Self {
+            client: Box::new(client),
+            eventloop: Box::new(eventloop),
+        }
+    }
+}
+
+pub struct Mqtt5Client {
+    pub client: Box<v5::AsyncClient>,
+    pub eventloop: Box<v5::EventLoop>,
+}
 
-        let (client, eventloop) = AsyncClient::new(options, 10);
+impl Mqtt5Client {
+    pub fn new(config: &MqttConfig) -> Mqtt5Client {
+        let (client, eventloop) = v5::AsyncClient::new(config.v5_options(), 10);
 
         Self {
             client: Box::new(client),
@@ -45,6 +335,113 @@ impl MqttClient {
     }
 }
 
+/// An event loop waiting to be driven, shared between clones of a publisher so only
+/// the first `connect` spawns it.
+pub struct PendingEventLoop<T>(Arc<Mutex<Option<T>>>);
+
+impl<T> PendingEventLoop<T> {
+    pub fn new(eventloop: T) -> Self {
+        Self(Arc::new(Mutex::new(Some(eventloop))))
+    }
+
+    pub fn empty() -> Self {
+        Self(Arc::new(Mutex::new(None)))
+    }
+
+    pub fn take(&self) -> Option<T> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+impl<T> Clone for PendingEventLoop<T> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<T> fmt::Debug for PendingEventLoop<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pending = self.0.lock().unwrap().is_some();
+        f.debug_struct("PendingEventLoop")
+            .field("pending", &pending)
+            .finish()
+    }
+}
+
+/// Whether a broker connection is up, and since when it has been in that state.
+#[derive(Debug)]
+pub struct ConnectionState {
+    connected: AtomicBool,
+    changed: Mutex<Instant>,
+}
+
+impl Default for ConnectionState {
+    fn default() -> Self {
+        Self {
+            connected: AtomicBool::new(false),
+            changed: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl ConnectionState {
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// How long the connection has been down, or `None` while it is up.
+    pub fn down_for(&self) -> Option<Duration> {
+        let changed = self.changed.lock().unwrap();
+        (!self.is_connected()).then(|| changed.elapsed())
+    }
+
+    pub fn set_connected(&self, connected: bool) {
+        let mut changed = self.changed.lock().unwrap();
+        if self.connected.swap(connected, Ordering::Relaxed) != connected {
+            *changed = Instant::now();
+        }
+    }
+}
+
+/// Polls the connection until the client is dropped; rumqttc reconnects on the next poll.
+pub async fn run_eventloop(mut eventloop: EventLoop, state: Arc<ConnectionState>) {
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("MQTT connected");
+                state.set_connected(true);
+            }
+            Ok(_) => {}
+            Err(ConnectionError::RequestsDone) => break,
+            Err(e) => {
+                warn!("MQTT connection error: {}", e);
+                state.set_connected(false);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+    state.set_connected(false);
+}
+
+pub async fn run_v5_eventloop(mut eventloop: v5::EventLoop, state: Arc<ConnectionState>) {
+    loop {
+        match eventloop.poll().await {
+            Ok(v5::Event::Incoming(v5::mqttbytes::v5::Packet::ConnAck(_))) => {
+                info!("MQTT v5 connected");
+                state.set_connected(true);
+            }
+            Ok(_) => {}
+            Err(v5::ConnectionError::RequestsDone) => break,
+            Err(e) => {
+                warn!("MQTT connection error: {}", e);
+                state.set_connected(false);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+    state.set_connected(false);
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -62,8 +459,165 @@ mod tests {
         let MqttMessage::Publish {
             topic: msg_topic,
             payload: msg_payload,
-        } = message;
+        } = message
+        else {
+            panic!("expected a plain publish");
+        };
         assert_eq!(topic, msg_topic);
         assert_eq!(payload, msg_payload);
     }
+
+    #[test]
+    fn test_mqtt_message_event_accessors() {
+        let event = DoorbellEvent::new(
+            "192.168.1.100".to_string(),
+            "alarm.use.s3.amazonaws.com".to_string(),
+            None,
+        );
+        let message = MqttMessage::Event {
+            topic: "test/topic".to_string(),
+            payload: vec![1, 2, 3, 4],
+            event: event.clone(),
+        };
+
+        assert_eq!(message.topic(), "test/topic");
+        assert_eq!(message.payload(), &[1, 2, 3, 4]);
+        assert_eq!(message.event(), Some(&event));
+    }
+
+    fn test_config() -> MqttConfig {
+        MqttConfig::new(
+            "localhost".to_string(),
+            1883,
+            "user".to_string(),
+            "pass".to_string(),
+            "prefix".to_string(),
+        )
+    }
+
+    #[test]
+    fn test_config_defaults() {
+        let options = test_config().options();
+        assert_eq!(options.client_id(), "ring-detector");
+        assert_eq!(options.keep_alive(), Duration::from_secs(30));
+        assert!(options.clean_session());
+        assert_eq!(options.inflight(), 100);
+        assert_eq!(options.max_packet_size(), 10 * 1024);
+    }
+
+    #[test]
+    fn test_config_overrides() {
+        let mut config = test_config();
+        config.client_id = "front-door".to_string();
+        config.keep_alive = Duration::from_secs(5);
+        config.clean_session = false;
+        config.inflight = 3;
+        config.max_packet_size = 4096;
+
+        let options = config.options();
+        assert_eq!(options.client_id(), "front-door");
+        assert_eq!(options.keep_alive(), Duration::from_secs(5));
+        assert!(!options.clean_session());
+        assert_eq!(options.inflight(), 3);
+        assert_eq!(options.max_packet_size(), 4096);
+    }
+
+    #[test]
+    fn test_v5_config() {
+        let mut config = test_config();
+        config.protocol = MqttProtocol::V5;
+        config.clean_session = false;
+        config.session_expiry = Some(Duration::from_secs(3600));
+
+        let options = config.v5_options();
+        assert_eq!(options.client_id(), "ring-detector");
+        assert!(!options.clean_start());
+        assert_eq!(options.session_expiry_interval(), Some(3600));
+        assert_eq!(options.max_packet_size(), Some(10 * 1024));
+        assert_eq!(options.get_outgoing_inflight_upper_limit(), Some(100));
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut config = test_config();
+        assert!(config.validate().is_ok());
+
+        config.message_expiry = Some(Duration::from_secs(30));
+        assert!(config.validate().is_err(), "expiry needs MQTT 5");
+        config.protocol = MqttProtocol::V5;
+        assert!(config.validate().is_ok());
+
+        config.session_expiry = Some(Duration::from_secs(u64::from(u32::MAX) + 1));
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_client_id_random_suffix() {
+        let mut config = test_config();
+        config.client_id_random_suffix = true;
+
+        let first = config.effective_client_id();
+        let second = config.effective_client_id();
+        assert!(first.starts_with("ring-detector-"));
+        assert_eq!(first.len(), "ring-detector-".len() + 8);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_discovery_payload() {
+        let config = br#"{"~":"","availability":[{"topic":"~/status"}]}"#.to_vec();
+        let payload = discovery_payload("ringdet-a/config", "home/ring", config.clone());
+        let payload: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(payload["~"], "home/ring");
+        assert_eq!(payload["availability"][0]["topic"], "~/status");
+
+        // Only config topics carry a base, and payloads without one are left alone.
+        assert_eq!(
+            discovery_payload("ringdet-a/action", "home/ring", config.clone()),
+            config
+        );
+        assert_eq!(
+            discovery_payload("ringdet-a/config", "home/ring", b"{}".to_vec()),
+            b"{}"
+        );
+    }
+
+    #[test]
+    fn test_publish_topic() {
+        let prefix = "homeassistant/button/ring-detector";
+        assert_eq!(
+            publish_topic(prefix, None, "ringdet-a/action"),
+            "homeassistant/button/ring-detector/ringdet-a/action"
+        );
+        let topic = "ringdet-a-ringing/config";
+        assert_eq!(
+            publish_topic(prefix, Some("binary_sensor"), topic),
+            "homeassistant/binary_sensor/ring-detector/ringdet-a-ringing/config"
+        );
+        assert_eq!(
+            publish_topic("ring", Some("binary_sensor"), topic),
+            "homeassistant/binary_sensor/ring/ringdet-a-ringing/config"
+        );
+    }
+
+    #[test]
+    fn test_retain_flags_for_topic() {
+        let retain = RetainFlags {
+            config: true,
+            action: false,
+            status: true,
+        };
+        assert!(retain.for_topic("ringdet-a/config"));
+        assert!(!retain.for_topic("ringdet-a/action"));
+        assert!(retain.for_topic("ringdet-a/availability"));
+        assert!(retain.for_topic("dnstap-run_dnstap_sock/state"));
+        assert!(!retain.for_topic("ringdet-a/other"));
+
+        let retain = RetainFlags {
+            status: false,
+            ..retain
+        };
+        assert!(!retain.for_topic("ringdet-a/availability"));
+        assert!(retain.for_topic("ringdet-a/presses/state"));
+    }
 }
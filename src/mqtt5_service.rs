@@ -0,0 +1,218 @@
+/*
+ * Copyright 2025 Kenny Root
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::messaging::MessagePublisher;
+use crate::mqtt::{
+    discovery_payload, expiry_seconds, publish_topic, run_v5_eventloop, ConnectionState,
+    Mqtt5Client, MqttConfig, MqttMessage, PendingEventLoop, RetainFlags,
+};
+use async_trait::async_trait;
+use rumqttc::v5::{
+    mqttbytes::{v5::PublishProperties, QoS},
+    AsyncClient, EventLoop,
+};
+use std::{sync::Arc, time::Duration};
+
+/// Publishes over MQTT v5 so presses can carry an expiry and user properties.
+#[derive(Debug, Clone)]
+pub struct Mqtt5Service {
+    client: AsyncClient,
+    eventloop: PendingEventLoop<EventLoop>,
+    state: Arc<ConnectionState>,
+    topic_prefix: String,
+    qos: QoS,
+    retain: RetainFlags,
+    message_expiry: Option<Duration>,
+}
+
+impl Mqtt5Service {
+    pub fn from_config(config: &MqttConfig) -> Self {
+        let mqtt_client = Mqtt5Client::new(config);
+        Self {
+            client: *mqtt_client.client,
+            eventloop: PendingEventLoop::new(*mqtt_client.eventloop),
+            state: Arc::default(),
+            topic_prefix: config.topic_prefix.clone(),
+            qos: v5_qos(config.qos),
+            retain: config.retain,
+            message_expiry: config.message_expiry,
+        }
+    }
+
+    fn publish_properties(&self, message: &MqttMessage) -> Option<PublishProperties> {
+        let event = message.event()?;
+
+        let mut user_properties = vec![("device".to_string(), event.device.clone())];
+        if let Some(ref resolver) = event.resolver {
+            user_properties.push(("resolver".to_string(), resolver.clone()));
+        }
+        if let Some(ref rcode) = event.rcode {
+            user_properties.push(("rcode".to_string(), rcode.clone()));
+        }
+
+        Some(PublishProperties {
+            message_expiry_interval: self.message_expiry.map(expiry_seconds),
+            user_properties,
+            ..Default::default()
+        })
+    }
+
+    async fn publish_status(&self, status: &str) -> anyhow::Result<()> {
+        self.client
+            .publish(
+                format!("{}/status", self.topic_prefix),
+                self.qos,
+                self.retain.status,
+                status.to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+fn v5_qos(qos: rumqttc::QoS) -> QoS {
+    match qos {
+        rumqttc::QoS::AtMostOnce => QoS::AtMostOnce,
+        rumqttc::QoS::AtLeastOnce => QoS::AtLeastOnce,
+        rumqttc::QoS::ExactlyOnce => QoS::ExactlyOnce,
+    }
+}
+
+#[async_trait]
+impl MessagePublisher for Mqtt5Service {
+    fn name(&self) -> &str {
+        "mqtt5"
+    }
+
+    async fn connect(&self) -> anyhow::Result<()> {
+        if let Some(eventloop) = self.eventloop.take() {
+            tokio::spawn(run_v5_eventloop(eventloop, Arc::clone(&self.state)));
+        }
+        Ok(())
+    }
+
+    fn connection(&self) -> Option<Arc<ConnectionState>> {
+        Some(Arc::clone(&self.state))
+    }
+
+    async fn publish(&self, message: MqttMessage) -> anyhow::Result<()> {
+        let retain = self.retain.for_topic(message.topic());
+        let topic = publish_topic(&self.topic_prefix, message.component(), message.topic());
+        let payload = discovery_payload(
+            message.topic(),
+            &self.topic_prefix,
+            message.payload().to_vec(),
+        );
+
+        match self.publish_properties(&message) {
+            Some(properties) => {
+                self.client
+                    .publish_with_properties(topic, self.qos, retain, payload, properties)
+                    .await?
+            }
+            None => {
+                self.client
+                    .publish(topic, self.qos, retain, payload)
+                    .await?
+            }
+        }
+        Ok(())
+    }
+
+    async fn send_birth(&self) -> anyhow::Result<()> {
+        self.publish_status("online").await
+    }
+
+    async fn send_death(&self) -> anyhow::Result<()> {
+        self.publish_status("offline").await?;
+        self.client.disconnect().await?;
+        Ok(())
+    }
+
+    async fn send_status(&self, status: &str) -> anyhow::Result<()> {
+        self.publish_status(status).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::DoorbellEvent;
+
+    fn test_service(message_expiry: Option<Duration>) -> Mqtt5Service {
+        let mut config = MqttConfig::new(
+            "localhost".to_string(),
+            1883,
+            "user".to_string(),
+            "pass".to_string(),
+            "prefix".to_string(),
+        );
+        config.message_expiry = message_expiry;
+        Mqtt5Service::from_config(&config)
+    }
+
+    #[test]
+    fn test_event_properties() {
+        let service = test_service(Some(Duration::from_secs(30)));
+        let message = MqttMessage::Event {
+            topic: "ringdet-192.168.1.100/action".to_string(),
+            payload: vec![],
+            event: DoorbellEvent::new(
+                "192.168.1.100".to_string(),
+                "alarm.use.s3.amazonaws.com".to_string(),
+                Some("unbound".to_string()),
+            ),
+        };
+
+        let properties = service.publish_properties(&message).unwrap();
+        assert_eq!(properties.message_expiry_interval, Some(30));
+        assert_eq!(
+            properties.user_properties,
+            vec![
+                ("device".to_string(), "192.168.1.100".to_string()),
+                ("resolver".to_string(), "unbound".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_event_properties_without_resolver() {
+        let service = test_service(None);
+        let message = MqttMessage::Event {
+            topic: "ringdet-192.168.1.100/action".to_string(),
+            payload: vec![],
+            event: DoorbellEvent::new(
+                "192.168.1.100".to_string(),
+                "alarm.use.s3.amazonaws.com".to_string(),
+                None,
+            ),
+        };
+
+        let properties = service.publish_properties(&message).unwrap();
+        assert_eq!(properties.message_expiry_interval, None);
+        assert_eq!(properties.user_properties.len(), 1);
+    }
+
+    #[test]
+    fn test_plain_publish_has_no_properties() {
+        let service = test_service(Some(Duration::from_secs(30)));
+        let message = MqttMessage::Publish {
+            topic: "ringdet-192.168.1.100/config".to_string(),
+            payload: vec![],
+        };
+
+        assert!(service.publish_properties(&message).is_none());
+    }
+}
@@ -14,72 +14,123 @@
  * limitations under the License.
  */
 use crate::messaging::MessagePublisher;
-use crate::mqtt::MqttMessage;
+use crate::mqtt::{
+    discovery_payload, publish_topic, run_eventloop, ConnectionState, MqttClient, MqttConfig,
+    MqttMessage, PendingEventLoop, RetainFlags,
+};
 use async_trait::async_trait;
-use rumqttc::{AsyncClient, QoS};
+use rumqttc::{AsyncClient, EventLoop, QoS};
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub struct MqttService {
     client: AsyncClient,
+    eventloop: PendingEventLoop<EventLoop>,
+    state: Arc<ConnectionState>,
     topic_prefix: String,
+    qos: QoS,
+    retain: RetainFlags,
 }
 
 impl Clone for MqttService {
     fn clone(&self) -> Self {
         Self {
             client: self.client.clone(),
+            eventloop: self.eventloop.clone(),
+            state: Arc::clone(&self.state),
             topic_prefix: self.topic_prefix.clone(),
+            qos: self.qos,
+            retain: self.retain,
         }
     }
 }
 impl MqttService {
     pub fn new(client: AsyncClient, topic_prefix: String) -> Self {
+        Self::with_options(
+            client,
+            topic_prefix,
+            QoS::AtLeastOnce,
+            RetainFlags::default(),
+        )
+    }
+
+    pub fn with_options(
+        client: AsyncClient,
+        topic_prefix: String,
+        qos: QoS,
+        retain: RetainFlags,
+    ) -> Self {
         Self {
             client,
+            eventloop: PendingEventLoop::empty(),
+            state: Arc::default(),
             topic_prefix,
+            qos,
+            retain,
         }
     }
+
+    pub fn from_config(config: &MqttConfig) -> Self {
+        let mqtt_client = MqttClient::new(config);
+        let mut service = Self::with_options(
+            *mqtt_client.client,
+            config.topic_prefix.clone(),
+            config.qos,
+            config.retain,
+        );
+        service.eventloop = PendingEventLoop::new(*mqtt_client.eventloop);
+        service
+    }
 }
 
 #[async_trait]
 impl MessagePublisher for MqttService {
-    async fn publish(&self, message: MqttMessage) -> anyhow::Result<()> {
-        match message {
-            MqttMessage::Publish {
-                topic: topic_suffix,
-                payload,
-            } => {
-                let topic = format!("{}/{}", self.topic_prefix, topic_suffix);
-                self.client
-                    .publish(topic, QoS::AtLeastOnce, false, payload)
-                    .await?;
-                Ok(())
-            }
+    fn name(&self) -> &str {
+        "mqtt"
+    }
+
+    async fn connect(&self) -> anyhow::Result<()> {
+        if let Some(eventloop) = self.eventloop.take() {
+            tokio::spawn(run_eventloop(eventloop, Arc::clone(&self.state)));
         }
+        Ok(())
     }
 
-    async fn send_birth(&self) -> anyhow::Result<()> {
+    fn connection(&self) -> Option<Arc<ConnectionState>> {
+        Some(Arc::clone(&self.state))
+    }
+
+    async fn publish(&self, message: MqttMessage) -> anyhow::Result<()> {
+        let topic_suffix = message.topic();
+        let retain = self.retain.for_topic(topic_suffix);
+        let topic = publish_topic(&self.topic_prefix, message.component(), topic_suffix);
+        let payload =
+            discovery_payload(topic_suffix, &self.topic_prefix, message.payload().to_vec());
         self.client
-            .publish(
-                format!("{}/status", self.topic_prefix),
-                QoS::AtLeastOnce,
-                true,
-                "online",
-            )
+            .publish(topic, self.qos, retain, payload)
             .await?;
         Ok(())
     }
 
+    async fn send_birth(&self) -> anyhow::Result<()> {
+        self.send_status("online").await
+    }
+
     async fn send_death(&self) -> anyhow::Result<()> {
+        self.send_status("offline").await?;
+        self.client.disconnect().await?;
+        Ok(())
+    }
+
+    async fn send_status(&self, status: &str) -> anyhow::Result<()> {
         self.client
             .publish(
                 format!("{}/status", self.topic_prefix),
-                QoS::AtLeastOnce,
-                true,
-                "offline",
+                self.qos,
+                self.retain.status,
+                status,
             )
             .await?;
-        self.client.disconnect().await?;
         Ok(())
     }
 }
@@ -14,10 +14,12 @@
  * limitations under the License.
  */
 
-use anyhow::{anyhow, Error};
+use anyhow::{anyhow, Context, Error};
 use std::{
+    fmt,
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
     result::Result,
+    str::FromStr,
 };
 
 type IpError = Error;
@@ -36,6 +38,75 @@ pub fn parse_octets(value: &[u8]) -> Result<IpAddr, IpError> {
     }
 }
 
+/// An address block such as `192.168.1.0/24`; a bare address matches only itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpCidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpCidr {
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self, IpError> {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix > max {
+            return Err(anyhow!("prefix /{} is too long for {}", prefix, addr));
+        }
+        Ok(Self { addr, prefix })
+    }
+
+    /// Whether the block holds a single address.
+    pub fn is_host(&self) -> bool {
+        self.prefix == if self.addr.is_ipv4() { 32 } else { 128 }
+    }
+
+    /// The address, if the block holds only that one.
+    pub fn host(&self) -> Option<IpAddr> {
+        self.is_host().then_some(self.addr)
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpCidr {
+    type Err = IpError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .with_context(|| format!("invalid address in {}", value))?;
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .with_context(|| format!("invalid prefix in {}", value))?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        Self::new(addr, prefix)
+    }
+}
+
+impl fmt::Display for IpCidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -63,4 +134,27 @@ mod tests {
         let addr = parse_octets(&[127, 0, 0, 0, 0, 0, 1]);
         assert!(addr.is_err(), "6 octets should not be parseable");
     }
+
+    #[test]
+    fn cidr_contains_pass() {
+        let net: IpCidr = "192.168.1.0/24".parse().unwrap();
+        assert!(net.contains(&"192.168.1.77".parse().unwrap()));
+        assert!(net.contains(&"::ffff:192.168.1.77".parse().unwrap()));
+        assert!(!net.contains(&"192.168.2.1".parse().unwrap()));
+        assert!(!net.contains(&"fd00::1".parse().unwrap()));
+
+        let host: IpCidr = "fd00::1".parse().unwrap();
+        assert!(host.contains(&"fd00::1".parse().unwrap()));
+        assert!(!host.contains(&"fd00::2".parse().unwrap()));
+
+        let any: IpCidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(&"10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn cidr_parse_failure() {
+        assert!("192.168.1.0/33".parse::<IpCidr>().is_err());
+        assert!("doorbell".parse::<IpCidr>().is_err());
+        assert!("10.0.0.0/x".parse::<IpCidr>().is_err());
+    }
 }
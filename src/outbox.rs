@@ -0,0 +1,692 @@
+/*
+ * Copyright 2025 Kenny Root
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::{
+    messaging::MessagePublisher,
+    mqtt::{ConnectionState, MqttMessage},
+};
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    sync::{Mutex as AsyncMutex, MutexGuard},
+    task,
+};
+
+/// Marks a message the outbox kept to deliver later, so callers can tell it
+/// apart from one that was delivered or lost.
+#[derive(Debug)]
+pub struct Queued(pub anyhow::Error);
+
+impl fmt::Display for Queued {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "queued in the outbox: {:#}", self.0)
+    }
+}
+
+impl std::error::Error for Queued {}
+
+pub fn is_queued(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.is::<Queued>())
+}
+
+#[derive(Debug, Clone)]
+pub struct OutboxConfig {
+    pub path: PathBuf,
+    /// Queued messages older than this are dropped instead of replayed.
+    pub max_age: Duration,
+    /// Oldest messages are dropped to keep the spool file under this size.
+    pub max_bytes: u64,
+    /// How often to retry delivery while messages are queued.
+    pub retry_interval: Duration,
+}
+
+impl OutboxConfig {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            max_age: Duration::from_secs(60 * 60),
+            max_bytes: 1024 * 1024,
+            retry_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SpoolEntry {
+    queued_at: SystemTime,
+    message: MqttMessage,
+}
+
+/// A line of the spool file: a queued message, or a note that the oldest
+/// `delivered` messages have gone out since the file was last rewritten.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum SpoolLine {
+    Delivered { delivered: u64 },
+    Entry(SpoolEntry),
+}
+
+/// Messages that could not be delivered, mirrored to a JSON Lines file.
+#[derive(Debug)]
+struct Spool {
+    config: OutboxConfig,
+    /// Entries with their ID and size in the file.
+    entries: VecDeque<(u64, SpoolEntry, u64)>,
+    bytes: u64,
+    next_id: u64,
+}
+
+impl Spool {
+    fn open(config: OutboxConfig) -> Result<Self> {
+        let mut spool = Self {
+            config,
+            entries: VecDeque::new(),
+            bytes: 0,
+            next_id: 0,
+        };
+
+        let file = match File::open(&spool.config.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(spool),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Cannot open outbox {}", spool.config.path.display()))
+            }
+        };
+
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            match serde_json::from_str::<SpoolLine>(&line) {
+                Ok(SpoolLine::Entry(entry)) => {
+                    let len = line.len() as u64 + 1;
+                    spool.append(entry, len);
+                }
+                Ok(SpoolLine::Delivered { delivered }) => {
+                    for _ in 0..delivered {
+                        spool.pop_front();
+                    }
+                }
+                Err(e) => warn!("Skipping unreadable outbox entry: {}", e),
+            }
+        }
+
+        if !spool.entries.is_empty() {
+            info!(
+                "Loaded {} queued messages from {}",
+                spool.entries.len(),
+                spool.config.path.display()
+            );
+        }
+        spool.expire(SystemTime::now())?;
+        Ok(spool)
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn push(&mut self, message: MqttMessage) -> Result<()> {
+        let entry = SpoolEntry {
+            queued_at: SystemTime::now(),
+            message,
+        };
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        let len = line.len() as u64;
+
+        if len > self.config.max_bytes {
+            warn!("Dropping message larger than the outbox");
+            return Ok(());
+        }
+
+        let mut evicted = false;
+        while !self.entries.is_empty() && self.bytes + len > self.config.max_bytes {
+            self.pop_front();
+            evicted = true;
+        }
+        if evicted {
+            warn!("Outbox full; dropped oldest queued messages");
+        }
+
+        self.append(entry, len);
+
+        if evicted {
+            self.rewrite()
+        } else {
+            self.append_line(&line)
+        }
+    }
+
+    fn append_line(&self, line: &str) -> Result<()> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.config.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .with_context(|| format!("Cannot append to outbox {}", self.config.path.display()))
+    }
+
+    fn append(&mut self, entry: SpoolEntry, len: u64) {
+        self.entries.push_back((self.next_id, entry, len));
+        self.next_id += 1;
+        self.bytes += len;
+    }
+
+    fn front(&self) -> Option<(u64, &SpoolEntry)> {
+        self.entries.front().map(|(id, entry, _)| (*id, entry))
+    }
+
+    fn pop_front(&mut self) {
+        if let Some((_, _, len)) = self.entries.pop_front() {
+            self.bytes -= len;
+        }
+    }
+
+    /// Removes a delivered entry, unless it was already evicted meanwhile, and
+    /// notes it in the file so a restart before the next rewrite does not send
+    /// it again.
+    fn delivered(&mut self, id: u64) -> Result<()> {
+        if self.front().is_none_or(|(front, _)| front != id) {
+            return Ok(());
+        }
+        self.pop_front();
+        let mut line = serde_json::to_string(&SpoolLine::Delivered { delivered: 1 })?;
+        line.push('\n');
+        self.append_line(&line)
+    }
+
+    /// Drops entries older than the configured age, rewriting the file if any were removed.
+    fn expire(&mut self, now: SystemTime) -> Result<()> {
+        let before = self.entries.len();
+        while let Some((_, entry)) = self.front() {
+            let age = now.duration_since(entry.queued_at).unwrap_or_default();
+            if age <= self.config.max_age {
+                break;
+            }
+            self.pop_front();
+        }
+
+        let expired = before - self.entries.len();
+        if expired > 0 {
+            warn!("Dropped {} expired messages from the outbox", expired);
+            self.rewrite()?;
+        }
+        Ok(())
+    }
+
+    fn rewrite(&self) -> Result<()> {
+        if self.entries.is_empty() {
+            return match fs::remove_file(&self.config.path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+
+        let tmp_path = self.config.path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)
+            .with_context(|| format!("Cannot create {}", tmp_path.display()))?;
+        for (_, entry, _) in &self.entries {
+            serde_json::to_writer(&mut file, entry)?;
+            file.write_all(b"\n")?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.config.path)
+            .with_context(|| format!("Cannot replace outbox {}", self.config.path.display()))
+    }
+}
+
+/// Adds the `late` marker to a rendered payload such as `{action:"pressed"}`.
+fn late_payload(payload: &[u8]) -> Vec<u8> {
+    match payload.strip_suffix(b"}") {
+        Some(body) if body.len() > 1 => [body, b",late:true}"].concat(),
+        Some(_) => b"{late:true}".to_vec(),
+        None => payload.to_vec(),
+    }
+}
+
+fn mark_late(message: MqttMessage) -> MqttMessage {
+    match message {
+        MqttMessage::Event {
+            topic,
+            payload,
+            mut event,
+        } => {
+            event.late = true;
+            MqttMessage::Event {
+                topic,
+                payload: late_payload(&payload),
+                event,
+            }
+        }
+        message => message,
+    }
+}
+
+/// The wrapped publisher and its spool, shared with the retry task.
+#[derive(Debug)]
+struct Shared {
+    inner: Arc<dyn MessagePublisher>,
+    spool: Mutex<Spool>,
+    /// Held while replaying so queued messages go out once and in order.
+    replaying: AsyncMutex<()>,
+}
+
+impl Shared {
+    /// Runs `f` on the spool on a blocking thread, since it may write the file.
+    async fn with_spool<T: Send + 'static>(
+        self: &Arc<Self>,
+        f: impl FnOnce(&mut Spool) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let shared = Arc::clone(self);
+        task::spawn_blocking(move || f(&mut shared.spool.lock().unwrap()))
+            .await
+            .context("Outbox task failed")?
+    }
+
+    /// Publishes a message, failing instead of handing it to a client that would
+    /// only queue it in memory while the broker is unreachable.
+    async fn deliver(&self, message: MqttMessage) -> Result<()> {
+        if let Some(connection) = self.inner.connection() {
+            if !connection.is_connected() {
+                bail!("Not connected to the broker");
+            }
+        }
+        self.inner.publish(message).await
+    }
+
+    /// Replays queued messages in order, stopping at the first failure.
+    ///
+    /// The spool is only locked between deliveries, so publishers can keep
+    /// queueing behind a slow replay. Each delivery is noted in the file as it
+    /// happens; the file is only compacted once the replay ends.
+    async fn replay(self: &Arc<Self>, _replaying: MutexGuard<'_, ()>) -> Result<()> {
+        self.with_spool(|spool| spool.expire(SystemTime::now()))
+            .await?;
+
+        let mut replayed = 0;
+        let mut result = Ok(());
+        loop {
+            let Some((id, message)) = self
+                .spool
+                .lock()
+                .unwrap()
+                .front()
+                .map(|(id, entry)| (id, entry.message.clone()))
+            else {
+                break;
+            };
+            if let Err(e) = self.deliver(mark_late(message)).await {
+                result = Err(e);
+                break;
+            }
+            self.with_spool(move |spool| spool.delivered(id)).await?;
+            replayed += 1;
+        }
+
+        if replayed > 0 {
+            info!("Replayed {} queued messages", replayed);
+            self.with_spool(|spool| spool.rewrite()).await?;
+        }
+        result
+    }
+}
+
+/// Queues messages on disk while the wrapped publisher is failing or its broker is
+/// disconnected, and replays them once it recovers.
+#[derive(Debug)]
+pub struct Outbox {
+    shared: Arc<Shared>,
+    retry_interval: Duration,
+}
+
+impl Outbox {
+    pub fn new(inner: Box<dyn MessagePublisher>, config: OutboxConfig) -> Result<Self> {
+        Self::shared(Arc::from(inner), config)
+    }
+
+    /// Wraps a publisher that is already shared, such as one held by a [`crate::fanout::PublisherSlot`].
+    pub fn shared(inner: Arc<dyn MessagePublisher>, config: OutboxConfig) -> Result<Self> {
+        let retry_interval = config.retry_interval;
+        Ok(Self {
+            shared: Arc::new(Shared {
+                inner,
+                spool: Mutex::new(Spool::open(config)?),
+                replaying: AsyncMutex::new(()),
+            }),
+            retry_interval,
+        })
+    }
+
+    /// Number of messages waiting to be delivered.
+    pub fn len(&self) -> usize {
+        self.shared.spool.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shared.spool.lock().unwrap().is_empty()
+    }
+
+    /// Attempts to deliver everything queued so far.
+    pub async fn flush(&self) -> Result<()> {
+        self.shared.replay(self.shared.replaying.lock().await).await
+    }
+
+    /// Spools a message that could not be delivered, returning a [`Queued`] error
+    /// for it.
+    async fn queue(&self, message: MqttMessage, error: anyhow::Error) -> Result<()> {
+        self.shared
+            .with_spool(move |spool| spool.push(message))
+            .await?;
+        Err(anyhow!(Queued(error)))
+    }
+}
+
+#[async_trait]
+impl MessagePublisher for Outbox {
+    fn name(&self) -> &str {
+        self.shared.inner.name()
+    }
+
+    async fn connect(&self) -> Result<()> {
+        self.shared.inner.connect().await?;
+
+        let shared = Arc::downgrade(&self.shared);
+        let retry_interval = self.retry_interval;
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(retry_interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                let Some(shared) = shared.upgrade() else {
+                    break;
+                };
+                if shared.spool.lock().unwrap().is_empty() {
+                    continue;
+                }
+                if let Err(e) = shared.replay(shared.replaying.lock().await).await {
+                    warn!("Outbox still cannot deliver: {}", e);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    async fn publish(&self, message: MqttMessage) -> Result<()> {
+        if !self.is_empty() {
+            // Queue behind a replay already under way rather than waiting for it.
+            let Ok(replaying) = self.shared.replaying.try_lock() else {
+                return self
+                    .queue(message, anyhow!("Earlier messages are being replayed"))
+                    .await;
+            };
+            if let Err(e) = self.shared.replay(replaying).await {
+                warn!("Outbox still cannot deliver, queueing message: {}", e);
+                return self.queue(message, e).await;
+            }
+        }
+
+        if let Err(e) = self.shared.deliver(message.clone()).await {
+            warn!("Failed to publish message, queueing in outbox: {}", e);
+            return self.queue(message, e).await;
+        }
+        Ok(())
+    }
+
+    async fn send_birth(&self) -> Result<()> {
+        self.shared.inner.send_birth().await
+    }
+
+    async fn send_death(&self) -> Result<()> {
+        self.shared.inner.send_death().await
+    }
+
+    async fn send_status(&self, status: &str) -> Result<()> {
+        self.shared.inner.send_status(status).await
+    }
+
+    fn connection(&self) -> Option<Arc<ConnectionState>> {
+        self.shared.inner.connection()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::DoorbellEvent;
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex as StdMutex,
+    };
+
+    #[derive(Debug, Default)]
+    struct FlakyPublisher {
+        failing: Arc<AtomicBool>,
+        published: Arc<StdMutex<Vec<MqttMessage>>>,
+    }
+
+    #[async_trait]
+    impl MessagePublisher for FlakyPublisher {
+        async fn publish(&self, message: MqttMessage) -> Result<()> {
+            if self.failing.load(Ordering::SeqCst) {
+                anyhow::bail!("broker unreachable");
+            }
+            self.published.lock().unwrap().push(message);
+            Ok(())
+        }
+
+        async fn send_birth(&self) -> Result<()> {
+            Ok(())
+        }
+
+        async fn send_death(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn press(device: &str) -> MqttMessage {
+        MqttMessage::Event {
+            topic: format!("ringdet-{}/action", device),
+            payload: b"{action:\"pressed\"}".to_vec(),
+            event: DoorbellEvent::new(
+                device.to_string(),
+                "alarm.use.s3.amazonaws.com".to_string(),
+                None,
+            ),
+        }
+    }
+
+    fn outbox(config: OutboxConfig) -> (Outbox, Arc<AtomicBool>, Arc<StdMutex<Vec<MqttMessage>>>) {
+        let publisher = FlakyPublisher::default();
+        let failing = Arc::clone(&publisher.failing);
+        let published = Arc::clone(&publisher.published);
+        (
+            Outbox::new(Box::new(publisher), config).unwrap(),
+            failing,
+            published,
+        )
+    }
+
+    #[test]
+    fn test_late_payload() {
+        assert_eq!(
+            late_payload(b"{action:\"pressed\"}"),
+            b"{action:\"pressed\",late:true}".to_vec()
+        );
+        assert_eq!(late_payload(b"{}"), b"{late:true}".to_vec());
+        assert_eq!(late_payload(b"online"), b"online".to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_replays_in_order_after_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let (outbox, failing, published) = outbox(OutboxConfig::new(dir.path().join("spool")));
+
+        failing.store(true, Ordering::SeqCst);
+        assert!(is_queued(
+            &outbox.publish(press("192.168.1.1")).await.unwrap_err()
+        ));
+        assert!(is_queued(
+            &outbox.publish(press("192.168.1.2")).await.unwrap_err()
+        ));
+        assert_eq!(outbox.len(), 2);
+        assert!(published.lock().unwrap().is_empty());
+
+        failing.store(false, Ordering::SeqCst);
+        outbox.publish(press("192.168.1.3")).await.unwrap();
+        assert!(outbox.is_empty());
+
+        let published = published.lock().unwrap();
+        let devices: Vec<_> = published
+            .iter()
+            .map(|m| m.event().unwrap().device.clone())
+            .collect();
+        assert_eq!(devices, ["192.168.1.1", "192.168.1.2", "192.168.1.3"]);
+        assert!(published[0].event().unwrap().late);
+        assert_eq!(
+            published[0].payload(),
+            b"{action:\"pressed\",late:true}".as_slice()
+        );
+        assert!(!published[2].event().unwrap().late);
+        assert!(!dir.path().join("spool").exists());
+    }
+
+    #[test]
+    fn test_deliveries_survive_restart_before_rewrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = OutboxConfig::new(dir.path().join("spool"));
+
+        let mut spool = Spool::open(config.clone()).unwrap();
+        spool.push(press("192.168.1.1")).unwrap();
+        spool.push(press("192.168.1.2")).unwrap();
+        let (id, _) = spool.front().unwrap();
+        spool.delivered(id).unwrap();
+        drop(spool);
+
+        let spool = Spool::open(config).unwrap();
+        assert_eq!(spool.len(), 1);
+        let (_, entry) = spool.front().unwrap();
+        assert_eq!(entry.message.event().unwrap().device, "192.168.1.2");
+    }
+
+    #[tokio::test]
+    async fn test_queues_while_broker_is_unreachable() {
+        use crate::{mqtt::MqttConfig, mqtt_service::MqttService};
+
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config = MqttConfig::new(
+            "127.0.0.1".to_string(),
+            port,
+            String::new(),
+            String::new(),
+            "ring".to_string(),
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = Outbox::new(
+            Box::new(MqttService::from_config(&config)),
+            OutboxConfig::new(dir.path().join("spool")),
+        )
+        .unwrap();
+        outbox.connect().await.unwrap();
+
+        // The client would accept the publish and hold it in memory; the outbox must not.
+        assert!(is_queued(
+            &outbox.publish(press("192.168.1.1")).await.unwrap_err()
+        ));
+        assert_eq!(outbox.len(), 1);
+        assert!(outbox.flush().await.is_err());
+        assert_eq!(outbox.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = OutboxConfig::new(dir.path().join("spool"));
+
+        let (first, failing, _) = outbox(config.clone());
+        failing.store(true, Ordering::SeqCst);
+        assert!(is_queued(
+            &first.publish(press("192.168.1.1")).await.unwrap_err()
+        ));
+        drop(first);
+
+        let (second, _, published) = outbox(config);
+        assert_eq!(second.len(), 1);
+        second.flush().await.unwrap();
+        assert_eq!(published.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_size_cap_drops_oldest() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = OutboxConfig::new(dir.path().join("spool"));
+        let entry_len = serde_json::to_string(&SpoolEntry {
+            queued_at: SystemTime::now(),
+            message: press("192.168.1.1"),
+        })
+        .unwrap()
+        .len() as u64;
+        config.max_bytes = entry_len * 2 + 10;
+
+        let (outbox, failing, published) = outbox(config);
+        failing.store(true, Ordering::SeqCst);
+        for device in ["192.168.1.1", "192.168.1.2", "192.168.1.3"] {
+            assert!(is_queued(&outbox.publish(press(device)).await.unwrap_err()));
+        }
+        assert_eq!(outbox.len(), 2);
+
+        failing.store(false, Ordering::SeqCst);
+        outbox.flush().await.unwrap();
+        let published = published.lock().unwrap();
+        assert_eq!(published[0].event().unwrap().device, "192.168.1.2");
+    }
+
+    #[tokio::test]
+    async fn test_age_cap_drops_stale() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = OutboxConfig::new(dir.path().join("spool"));
+        config.max_age = Duration::ZERO;
+
+        let (outbox, failing, published) = outbox(config);
+        failing.store(true, Ordering::SeqCst);
+        assert!(is_queued(
+            &outbox.publish(press("192.168.1.1")).await.unwrap_err()
+        ));
+
+        failing.store(false, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        outbox.flush().await.unwrap();
+        assert!(published.lock().unwrap().is_empty());
+        assert!(outbox.is_empty());
+    }
+}
@@ -0,0 +1,338 @@
+/*
+ * Copyright 2025 Kenny Root
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::Write,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+use tokio::task;
+
+use crate::{
+    devices::{Device, DeviceMap},
+    discovery::entity_config,
+    mqtt::MqttMessage,
+};
+
+/// Press history of one doorbell.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Counts {
+    total: u64,
+    /// The UTC date `today` counts presses for, as `YYYY-MM-DD`.
+    day: String,
+    today: u64,
+    /// RFC 3339 (ISO 8601) UTC time of the last press.
+    last_pressed: Option<String>,
+    /// Client address of the last press, when the doorbell is counted by MAC.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    address: Option<String>,
+}
+
+fn utc_day(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time).to_string()[..10].to_string()
+}
+
+fn state_topic(device: &Device, sensor: &str) -> String {
+    device.topic(&format!("{}/state", sensor))
+}
+
+fn state_message(device: &Device, sensor: &str, value: String) -> MqttMessage {
+    MqttMessage::Publish {
+        topic: state_topic(device, sensor),
+        payload: value.into_bytes(),
+    }
+}
+
+/// Discovery for the last-pressed, total and daily press sensors of a doorbell.
+pub fn press_configs(device: &Device) -> Vec<MqttMessage> {
+    let sensor = |object: &str, config: serde_json::Value| {
+        let mut config = config;
+        config["state_topic"] = format!("~/{}", state_topic(device, object)).into();
+        entity_config(device, "sensor", object, config)
+    };
+    vec![
+        sensor(
+            "last_pressed",
+            json!({ "name": "Last pressed", "device_class": "timestamp" }),
+        ),
+        sensor(
+            "presses",
+            json!({ "name": "Presses", "state_class": "total_increasing" }),
+        ),
+        sensor(
+            "presses_today",
+            json!({ "name": "Presses today", "state_class": "total_increasing" }),
+        ),
+    ]
+}
+
+/// Counts presses per doorbell and publishes them on retained state topics, so a
+/// dashboard opened after a press still has something to show.
+///
+/// Days are UTC days. Counts are kept by device key, so they carry over when a
+/// doorbell is renamed, or readdressed if the device map matches its MAC. With a
+/// path, counts are saved by [`PressCounters::save`] after changes and survive
+/// restarts.
+#[derive(Debug, Default)]
+pub struct PressCounters {
+    path: Option<PathBuf>,
+    devices: Mutex<BTreeMap<String, Counts>>,
+    /// Whether the counts changed since they were last saved.
+    dirty: AtomicBool,
+    /// Held while writing, so an older snapshot cannot replace a newer one.
+    writing: Mutex<()>,
+}
+
+impl PressCounters {
+    /// Loads counts saved at `path`, starting from zero if there are none yet.
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let devices = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .with_context(|| format!("Cannot read press counts from {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Cannot open press counts {}", path.display()))
+            }
+        };
+        info!(
+            "Loaded press counts for {} doorbells from {}",
+            devices.len(),
+            path.display()
+        );
+        Ok(Self {
+            path: Some(path),
+            devices: Mutex::new(devices),
+            ..Default::default()
+        })
+    }
+
+    /// Addresses of the doorbells with counts, which may not have been seen yet.
+    pub fn addresses(&self) -> Vec<String> {
+        let devices = self.devices.lock().unwrap();
+        devices
+            .iter()
+            .map(|(key, counts)| counts.address.as_ref().unwrap_or(key).clone())
+            .collect()
+    }
+
+    /// Counts a press at `at`, returning the updated state messages.
+    pub fn pressed(&self, device: &Device, at: SystemTime) -> Vec<MqttMessage> {
+        let mut devices = self.devices.lock().unwrap();
+        if device.key != device.address && !devices.contains_key(&device.key) {
+            // Counted by address before its MAC was in the device map.
+            if let Some(counts) = devices.remove(&device.address) {
+                devices.insert(device.key.clone(), counts);
+            }
+        }
+        let counts = devices.entry(device.key.clone()).or_default();
+        counts.address = (device.key != device.address).then(|| device.address.clone());
+        let day = utc_day(at);
+        if counts.day != day {
+            counts.day = day;
+            counts.today = 0;
+        }
+        counts.total += 1;
+        counts.today += 1;
+        let last_pressed = humantime::format_rfc3339_seconds(at).to_string();
+        counts.last_pressed = Some(last_pressed.clone());
+
+        let messages = vec![
+            state_message(device, "last_pressed", last_pressed),
+            state_message(device, "presses", counts.total.to_string()),
+            state_message(device, "presses_today", counts.today.to_string()),
+        ];
+        self.dirty.store(true, Ordering::Release);
+        messages
+    }
+
+    /// Resets the daily counts of doorbells last pressed before `now`'s day,
+    /// returning a message for each so the daily sensor drops back to zero.
+    ///
+    /// Doorbells are looked up in `names` since some may not have been seen since
+    /// the counts were loaded.
+    pub fn roll_over(&self, now: SystemTime, names: &DeviceMap) -> Vec<MqttMessage> {
+        let day = utc_day(now);
+        let mut devices = self.devices.lock().unwrap();
+        let messages: Vec<_> = devices
+            .iter_mut()
+            .filter(|(_, counts)| counts.day != day)
+            .map(|(key, counts)| {
+                counts.day = day.clone();
+                counts.today = 0;
+                let device = names.resolve(counts.address.as_ref().unwrap_or(key));
+                state_message(&device, "presses_today", "0".to_string())
+            })
+            .collect();
+        if !messages.is_empty() {
+            self.dirty.store(true, Ordering::Release);
+        }
+        messages
+    }
+
+    /// Saves the counts if they changed, on a blocking thread so the file I/O
+    /// holds up neither the caller's runtime nor the counts.
+    pub async fn save(self: &Arc<Self>) {
+        if self.path.is_none() || !self.dirty.swap(false, Ordering::AcqRel) {
+            return;
+        }
+        let counters = Arc::clone(self);
+        if let Err(e) = task::spawn_blocking(move || counters.write()).await {
+            warn!("Cannot save press counts: {}", e);
+        }
+    }
+
+    /// Writes the current counts, keeping them in memory if that fails.
+    fn write(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let _writing = self.writing.lock().unwrap();
+        let devices = self.devices.lock().unwrap().clone();
+        let write = || -> Result<()> {
+            let tmp_path = path.with_extension("tmp");
+            let mut file = File::create(&tmp_path)
+                .with_context(|| format!("Cannot create {}", tmp_path.display()))?;
+            serde_json::to_writer_pretty(&mut file, &devices)?;
+            file.write_all(b"\n")?;
+            file.sync_all()?;
+            fs::rename(&tmp_path, path)
+                .with_context(|| format!("Cannot replace {}", path.display()))
+        };
+        if let Err(e) = write() {
+            warn!("Cannot save press counts: {:#}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn payloads(messages: &[MqttMessage]) -> Vec<(String, String)> {
+        messages
+            .iter()
+            .map(|m| {
+                let payload = String::from_utf8(m.payload().to_vec()).unwrap();
+                (m.topic().to_string(), payload)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_counts_survive_restart_and_reset_daily() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("presses.json");
+        // 2023-11-14T22:13:20Z
+        let evening = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        let device = Device::new("192.168.1.100");
+        let names = DeviceMap::default();
+
+        let counters = Arc::new(PressCounters::open(path.clone()).unwrap());
+        counters.pressed(&device, evening);
+        let messages = counters.pressed(&device, evening + Duration::from_secs(60));
+        assert_eq!(
+            payloads(&messages),
+            vec![
+                (
+                    "ringdet-192.168.1.100/last_pressed/state".to_string(),
+                    "2023-11-14T22:14:20Z".to_string()
+                ),
+                (
+                    "ringdet-192.168.1.100/presses/state".to_string(),
+                    "2".to_string()
+                ),
+                (
+                    "ringdet-192.168.1.100/presses_today/state".to_string(),
+                    "2".to_string()
+                ),
+            ]
+        );
+        assert!(!path.exists(), "presses do not write the file themselves");
+        counters.save().await;
+        drop(counters);
+
+        let counters = PressCounters::open(path).unwrap();
+        let next_day = evening + Duration::from_secs(2 * 60 * 60);
+        assert!(counters.roll_over(evening, &names).is_empty());
+        assert_eq!(
+            payloads(&counters.roll_over(next_day, &names)),
+            vec![(
+                "ringdet-192.168.1.100/presses_today/state".to_string(),
+                "0".to_string()
+            )]
+        );
+        assert!(counters.roll_over(next_day, &names).is_empty());
+
+        let messages = counters.pressed(&device, next_day);
+        assert_eq!(messages[1].payload(), b"3");
+        assert_eq!(messages[2].payload(), b"1");
+    }
+
+    #[test]
+    fn test_counts_follow_mac_across_addresses() {
+        let at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let counters = PressCounters::default();
+        counters.pressed(&Device::new("192.168.1.40"), at);
+
+        let mut device = Device::new("192.168.1.40");
+        device.key = "a4cf12000001".to_string();
+        assert_eq!(counters.pressed(&device, at)[1].payload(), b"2");
+
+        device.address = "192.168.1.41".to_string();
+        assert_eq!(counters.pressed(&device, at)[1].payload(), b"3");
+        assert_eq!(counters.addresses(), vec!["192.168.1.41".to_string()]);
+    }
+
+    #[test]
+    fn test_unreadable_counts_are_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("presses.json");
+        fs::write(&path, "not json").unwrap();
+        assert!(PressCounters::open(path).is_err());
+    }
+
+    #[test]
+    fn test_press_configs() {
+        let configs = press_configs(&Device::new("192.168.1.100"));
+        let topics: Vec<_> = configs.iter().map(|m| m.topic()).collect();
+        assert_eq!(
+            topics,
+            vec![
+                "ringdet-192.168.1.100-last_pressed/config",
+                "ringdet-192.168.1.100-presses/config",
+                "ringdet-192.168.1.100-presses_today/config",
+            ]
+        );
+        let config: serde_json::Value = serde_json::from_slice(configs[0].payload()).unwrap();
+        assert_eq!(config["device_class"], "timestamp");
+        assert_eq!(
+            config["state_topic"],
+            "~/ringdet-192.168.1.100/last_pressed/state"
+        );
+    }
+}
@@ -0,0 +1,464 @@
+/*
+ * Copyright 2025 Kenny Root
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::{
+    messaging::MessagePublisher,
+    mqtt::{ConnectionState, MqttMessage},
+};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::{info, warn};
+use std::{
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Marks an error as transient, so [`Resilient`] may try the call again.
+#[derive(Debug)]
+pub struct Retryable(pub anyhow::Error);
+
+impl fmt::Display for Retryable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#}", self.0)
+    }
+}
+
+impl std::error::Error for Retryable {}
+
+pub fn retryable(error: impl Into<anyhow::Error>) -> anyhow::Error {
+    anyhow!(Retryable(error.into()))
+}
+
+pub fn is_retryable(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.is::<Retryable>())
+}
+
+#[derive(Debug, Clone)]
+pub struct ResilienceConfig {
+    /// Deadline for each individual call, including birth and death messages.
+    pub timeout: Duration,
+    /// Additional attempts for retryable failures and timeouts.
+    pub retries: u32,
+    /// Delay before the first retry; doubled on each further attempt.
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// Consecutive failures that open the circuit.
+    pub failure_threshold: u32,
+    /// How long an open circuit fails fast before letting a probe through.
+    pub open_duration: Duration,
+}
+
+impl Default for ResilienceConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            retries: 2,
+            backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+            failure_threshold: 5,
+            open_duration: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ResilienceConfig {
+    fn backoff_for(&self, attempt: u32) -> Duration {
+        self.backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Calls go through normally.
+    Closed,
+    /// The backend is considered down and calls fail immediately.
+    Open,
+    /// A single probe call is allowed to find out whether the backend recovered.
+    HalfOpen,
+}
+
+impl fmt::Display for CircuitState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half-open",
+        })
+    }
+}
+
+#[derive(Debug)]
+struct BreakerState {
+    state: CircuitState,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    probing: bool,
+    trips: u64,
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    name: String,
+    failure_threshold: u32,
+    open_duration: Duration,
+    inner: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &str, failure_threshold: u32, open_duration: Duration) -> Self {
+        Self {
+            name: name.to_owned(),
+            failure_threshold: failure_threshold.max(1),
+            open_duration,
+            inner: Mutex::new(BreakerState {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                opened_at: None,
+                probing: false,
+                trips: 0,
+            }),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn state(&self) -> CircuitState {
+        let inner = self.inner.lock().unwrap();
+        match inner.state {
+            CircuitState::Open if self.open_elapsed(&inner) => CircuitState::HalfOpen,
+            state => state,
+        }
+    }
+
+    /// Number of times the circuit has opened.
+    pub fn trips(&self) -> u64 {
+        self.inner.lock().unwrap().trips
+    }
+
+    fn open_elapsed(&self, inner: &BreakerState) -> bool {
+        inner
+            .opened_at
+            .is_some_and(|at| at.elapsed() >= self.open_duration)
+    }
+
+    /// Returns whether a call may proceed.
+    fn allow(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            CircuitState::Closed => true,
+            CircuitState::Open if self.open_elapsed(&inner) => {
+                inner.state = CircuitState::HalfOpen;
+                inner.probing = true;
+                true
+            }
+            CircuitState::Open => false,
+            CircuitState::HalfOpen if !inner.probing => {
+                inner.probing = true;
+                true
+            }
+            CircuitState::HalfOpen => false,
+        }
+    }
+
+    fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.state != CircuitState::Closed {
+            info!("Circuit for {} closed", self.name);
+        }
+        inner.state = CircuitState::Closed;
+        inner.consecutive_failures = 0;
+        inner.opened_at = None;
+        inner.probing = false;
+    }
+
+    fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures += 1;
+        inner.probing = false;
+        let trip = match inner.state {
+            CircuitState::Closed => inner.consecutive_failures >= self.failure_threshold,
+            CircuitState::HalfOpen => true,
+            CircuitState::Open => false,
+        };
+        if trip {
+            warn!(
+                "Circuit for {} opened after {} consecutive failures",
+                self.name, inner.consecutive_failures
+            );
+            inner.state = CircuitState::Open;
+            inner.opened_at = Some(Instant::now());
+            inner.trips += 1;
+        }
+    }
+}
+
+/// Applies timeouts, retries and a circuit breaker to every call on another publisher.
+#[derive(Debug)]
+pub struct Resilient {
+    inner: Arc<dyn MessagePublisher>,
+    config: ResilienceConfig,
+    breaker: Arc<CircuitBreaker>,
+}
+
+impl Resilient {
+    pub fn new(inner: Arc<dyn MessagePublisher>, config: ResilienceConfig) -> Self {
+        let breaker = Arc::new(CircuitBreaker::new(
+            inner.name(),
+            config.failure_threshold,
+            config.open_duration,
+        ));
+        Self {
+            inner,
+            config,
+            breaker,
+        }
+    }
+
+    pub fn breaker(&self) -> Arc<CircuitBreaker> {
+        Arc::clone(&self.breaker)
+    }
+
+    async fn with_timeout<F>(&self, what: &str, call: F) -> Result<()>
+    where
+        F: Future<Output = Result<()>>,
+    {
+        match tokio::time::timeout(self.config.timeout, call).await {
+            Ok(result) => result,
+            Err(_) => Err(retryable(anyhow!(
+                "{} via {} timed out after {:?}",
+                what,
+                self.inner.name(),
+                self.config.timeout
+            ))),
+        }
+    }
+}
+
+#[async_trait]
+impl MessagePublisher for Resilient {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    async fn connect(&self) -> Result<()> {
+        self.with_timeout("Connect", self.inner.connect()).await
+    }
+
+    async fn publish(&self, message: MqttMessage) -> Result<()> {
+        if !self.breaker.allow() {
+            return Err(anyhow!("Circuit for {} is open", self.inner.name()));
+        }
+
+        // The breaker counts whole publishes, not each retry within one.
+        let mut attempt = 0;
+        loop {
+            let error = match self
+                .with_timeout("Publish", self.inner.publish(message.clone()))
+                .await
+            {
+                Ok(()) => {
+                    self.breaker.record_success();
+                    return Ok(());
+                }
+                Err(e) => e,
+            };
+
+            if !is_retryable(&error) || attempt >= self.config.retries {
+                self.breaker.record_failure();
+                return Err(error);
+            }
+            attempt += 1;
+            warn!(
+                "{:#}; retrying ({}/{})",
+                error, attempt, self.config.retries
+            );
+            tokio::time::sleep(self.config.backoff_for(attempt)).await;
+        }
+    }
+
+    async fn send_birth(&self) -> Result<()> {
+        self.with_timeout("Birth", self.inner.send_birth()).await
+    }
+
+    async fn send_death(&self) -> Result<()> {
+        self.with_timeout("Death", self.inner.send_death()).await
+    }
+
+    async fn send_status(&self, status: &str) -> Result<()> {
+        self.with_timeout("Status", self.inner.send_status(status))
+            .await
+    }
+
+    fn connection(&self) -> Option<Arc<ConnectionState>> {
+        self.inner.connection()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[derive(Debug, Default)]
+    struct ScriptedPublisher {
+        /// Number of calls that fail before the publisher starts succeeding.
+        failures: u32,
+        retryable: bool,
+        hang: bool,
+        calls: AtomicU32,
+    }
+
+    #[async_trait]
+    impl MessagePublisher for ScriptedPublisher {
+        async fn publish(&self, _message: MqttMessage) -> Result<()> {
+            let call = self.calls.fetch_add(1, Ordering::SeqCst);
+            if self.hang {
+                std::future::pending::<()>().await;
+            }
+            if call < self.failures {
+                let error = anyhow!("backend down");
+                return Err(if self.retryable {
+                    retryable(error)
+                } else {
+                    error
+                });
+            }
+            Ok(())
+        }
+
+        async fn send_birth(&self) -> Result<()> {
+            Ok(())
+        }
+
+        async fn send_death(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn message() -> MqttMessage {
+        MqttMessage::Publish {
+            topic: "ringdet-192.168.1.100/config".to_string(),
+            payload: b"{}".to_vec(),
+        }
+    }
+
+    fn config() -> ResilienceConfig {
+        ResilienceConfig {
+            timeout: Duration::from_millis(50),
+            retries: 2,
+            backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(5),
+            failure_threshold: 10,
+            open_duration: Duration::from_millis(100),
+        }
+    }
+
+    #[test]
+    fn test_backoff_is_capped() {
+        let config = config();
+        assert_eq!(config.backoff_for(1), Duration::from_millis(1));
+        assert_eq!(config.backoff_for(3), Duration::from_millis(4));
+        assert_eq!(config.backoff_for(30), Duration::from_millis(5));
+    }
+
+    #[tokio::test]
+    async fn test_retries_retryable_errors() {
+        let inner = Arc::new(ScriptedPublisher {
+            failures: 2,
+            retryable: true,
+            ..Default::default()
+        });
+        let resilient = Resilient::new(inner.clone(), config());
+
+        resilient.publish(message()).await.unwrap();
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_does_not_retry_permanent_errors() {
+        let inner = Arc::new(ScriptedPublisher {
+            failures: 1,
+            ..Default::default()
+        });
+        let resilient = Resilient::new(inner.clone(), config());
+
+        assert!(resilient.publish(message()).await.is_err());
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_times_out_hung_calls() {
+        let inner = Arc::new(ScriptedPublisher {
+            hang: true,
+            ..Default::default()
+        });
+        let mut config = config();
+        config.retries = 1;
+        let resilient = Resilient::new(inner.clone(), config);
+
+        let error = resilient.publish(message()).await.unwrap_err();
+        assert!(is_retryable(&error));
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_retries_count_once_toward_the_breaker() {
+        let inner = Arc::new(ScriptedPublisher {
+            failures: 3,
+            retryable: true,
+            ..Default::default()
+        });
+        let mut config = config();
+        config.failure_threshold = 2;
+        let resilient = Resilient::new(inner.clone(), config);
+
+        assert!(resilient.publish(message()).await.is_err());
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 3);
+        assert_eq!(resilient.breaker().state(), CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_circuit_opens_and_recovers() {
+        let inner = Arc::new(ScriptedPublisher {
+            failures: 2,
+            ..Default::default()
+        });
+        let mut config = config();
+        config.failure_threshold = 2;
+        let resilient = Resilient::new(inner.clone(), config);
+        let breaker = resilient.breaker();
+
+        assert!(resilient.publish(message()).await.is_err());
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(resilient.publish(message()).await.is_err());
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert_eq!(breaker.trips(), 1);
+
+        // Fails fast without reaching the backend.
+        assert!(resilient.publish(message()).await.is_err());
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 2);
+
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        resilient.publish(message()).await.unwrap();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+}
@@ -0,0 +1,166 @@
+/*
+ * Copyright 2025 Kenny Root
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use serde_json::json;
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+use tokio::time::Instant;
+
+use crate::{devices::Device, discovery::entity_config, mqtt::MqttMessage};
+
+pub const ON: &str = "ON";
+pub const OFF: &str = "OFF";
+
+/// Topic suffix carrying the doorbell's ringing state.
+///
+/// Not retained, so a bridge that dies mid-ring cannot leave the sensor stuck on.
+pub fn ringing_topic(device: &Device) -> String {
+    device.topic("ringing")
+}
+
+fn ringing_message(device: &Device, on: bool) -> MqttMessage {
+    let payload = if on { ON } else { OFF };
+    MqttMessage::Publish {
+        topic: ringing_topic(device),
+        payload: payload.as_bytes().to_vec(),
+    }
+}
+
+/// Discovery for the doorbell's ringing binary sensor.
+pub fn ringing_config(device: &Device) -> MqttMessage {
+    entity_config(
+        device,
+        "binary_sensor",
+        "ringing",
+        json!({
+            "name": "Ringing",
+            "device_class": "sound",
+            "state_topic": format!("~/{}", ringing_topic(device)),
+        }),
+    )
+}
+
+/// Turns a per-doorbell binary sensor on for each press and off again once no
+/// press has arrived for the hold time.
+#[derive(Debug)]
+pub struct Ringing {
+    hold: Duration,
+    /// When each ringing doorbell turns off, keyed by device key.
+    until: Mutex<HashMap<String, (Instant, Device)>>,
+}
+
+impl Ringing {
+    pub fn new(hold: Duration) -> Self {
+        Self {
+            hold,
+            until: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn hold(&self) -> Duration {
+        self.hold
+    }
+
+    /// How often [`Ringing::expire`] should run to turn sensors off close to on time.
+    pub fn check_interval(&self) -> Duration {
+        (self.hold / 10).clamp(Duration::from_millis(100), Duration::from_secs(1))
+    }
+
+    /// Turns the sensor on, returning an `ON` message unless it already was on.
+    ///
+    /// A press while ringing only extends the hold, so the sensor does not flap.
+    pub fn pressed(&self, device: &Device) -> Option<MqttMessage> {
+        let until = Instant::now() + self.hold;
+        let was_ringing = self
+            .until
+            .lock()
+            .unwrap()
+            .insert(device.key.clone(), (until, device.clone()))
+            .is_some();
+        (!was_ringing).then(|| ringing_message(device, true))
+    }
+
+    /// Turns off every sensor whose hold has passed, returning an `OFF` message for each.
+    pub fn expire(&self) -> Vec<MqttMessage> {
+        let now = Instant::now();
+        let mut until = self.until.lock().unwrap();
+        let mut messages = vec![];
+        until.retain(|_, (until, device)| {
+            let ringing = *until > now;
+            if !ringing {
+                messages.push(ringing_message(device, false));
+            }
+            ringing
+        });
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_presses_extend_the_hold() {
+        let ringing = Ringing::new(Duration::from_millis(40));
+        let device = Device::new("192.168.1.100");
+
+        let on = ringing.pressed(&device).unwrap();
+        assert_eq!(on.topic(), "ringdet-192.168.1.100/ringing");
+        assert_eq!(on.payload(), ON.as_bytes());
+
+        tokio::time::advance(Duration::from_millis(25)).await;
+        assert!(ringing.pressed(&device).is_none());
+        tokio::time::advance(Duration::from_millis(25)).await;
+        assert!(ringing.expire().is_empty(), "second press extends the hold");
+
+        tokio::time::advance(Duration::from_millis(25)).await;
+        let off = ringing.expire();
+        assert_eq!(off.len(), 1);
+        assert_eq!(off[0].payload(), OFF.as_bytes());
+        assert!(ringing.expire().is_empty());
+
+        assert!(ringing.pressed(&device).is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mac_rings_once_across_addresses() {
+        let ringing = Ringing::new(Duration::from_millis(40));
+        let mut device = Device::new("192.168.1.40");
+        device.key = "a4cf12000001".to_string();
+
+        assert!(ringing.pressed(&device).is_some());
+        tokio::time::advance(Duration::from_millis(25)).await;
+        device.address = "192.168.1.41".to_string();
+        assert!(ringing.pressed(&device).is_none(), "already ringing");
+
+        tokio::time::advance(Duration::from_millis(25)).await;
+        assert!(
+            ringing.expire().is_empty(),
+            "the new address extends the hold"
+        );
+        tokio::time::advance(Duration::from_millis(25)).await;
+        assert_eq!(ringing.expire().len(), 1);
+    }
+
+    #[test]
+    fn test_ringing_config() {
+        let message = ringing_config(&Device::new("192.168.1.100"));
+        assert_eq!(message.component(), Some("binary_sensor"));
+        assert_eq!(message.topic(), "ringdet-192.168.1.100-ringing/config");
+        let config: serde_json::Value = serde_json::from_slice(message.payload()).unwrap();
+        assert_eq!(config["state_topic"], "~/ringdet-192.168.1.100/ringing");
+        assert_eq!(config["device_class"], "sound");
+    }
+}
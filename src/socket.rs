@@ -0,0 +1,320 @@
+/*
+ * Copyright 2025 Kenny Root
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+use std::{
+    fs::{self, File, OpenOptions, Permissions, TryLockError},
+    io::{ErrorKind, Write},
+    os::unix::{
+        fs::{chown, FileTypeExt, PermissionsExt},
+        net::UnixListener,
+    },
+    path::{Path, PathBuf},
+};
+
+/// Returns the name of a Linux abstract socket address written as `@name`.
+pub fn abstract_name(path: &Path) -> Option<&[u8]> {
+    path.as_os_str().as_encoded_bytes().strip_prefix(b"@")
+}
+
+/// Binds `path`, treating `@name` as an address in the Linux abstract namespace.
+///
+/// Abstract sockets have no file, so nothing is left behind to clean up and two
+/// instances cannot bind the same name.
+pub fn bind(path: &Path) -> Result<UnixListener> {
+    let listener = match abstract_name(path) {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+            UnixListener::bind_addr(&SocketAddr::from_abstract_name(name)?)
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => bail!("Abstract sockets are only supported on Linux"),
+        None => UnixListener::bind(path),
+    }
+    .with_context(|| format!("Cannot bind to DNS listener {}", path.display()))?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Ownership and permissions given to the dnstap socket when it is bound.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    pub mode: Option<u32>,
+    pub owner: Option<u32>,
+    pub group: Option<u32>,
+}
+
+impl SocketOptions {
+    /// Binds `path` with these options already applied.
+    ///
+    /// Changing the socket after binding it in place would leave a window in
+    /// which it has the default permissions, so it is bound in a private
+    /// directory first and then linked into place.
+    pub fn bind(&self, path: &Path) -> Result<UnixListener> {
+        if *self == Self::default() || abstract_name(path).is_some() {
+            return bind(path);
+        }
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            bail!("Invalid socket path {}", path.display());
+        };
+        let private = tempfile::Builder::new()
+            .prefix(".ring-detector-")
+            .tempdir_in(parent)
+            .with_context(|| format!("Cannot create a directory in {}", parent.display()))?;
+        let staged = private.path().join(name);
+        let listener = bind(&staged)?;
+        self.apply(&staged)?;
+        // Unlike a rename, linking fails instead of replacing an existing socket.
+        fs::hard_link(&staged, path)
+            .with_context(|| format!("Cannot bind to DNS listener {}", path.display()))?;
+        Ok(listener)
+    }
+
+    fn apply(&self, path: &Path) -> Result<()> {
+        if self.owner.is_some() || self.group.is_some() {
+            chown(path, self.owner, self.group)
+                .with_context(|| format!("Cannot change owner of {}", path.display()))?;
+        }
+        if let Some(mode) = self.mode {
+            fs::set_permissions(path, Permissions::from_mode(mode))
+                .with_context(|| format!("Cannot change mode of {}", path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses an octal file mode such as `660` or `0o660`.
+pub fn parse_mode(value: &str) -> Result<u32, String> {
+    let digits = value.strip_prefix("0o").unwrap_or(value);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!(
+            "expected an octal mode such as 660, got \"{}\"",
+            value
+        )),
+    }
+}
+
+/// Looks up `name` in a passwd- or group-style database, accepting numeric IDs as-is.
+fn lookup_id(name: &str, database: &Path) -> Result<u32> {
+    if let Ok(id) = name.parse::<u32>() {
+        return Ok(id);
+    }
+    let contents = fs::read_to_string(database)
+        .with_context(|| format!("Cannot read {}", database.display()))?;
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let entry = fields.next()?;
+            let id = fields.nth(1)?;
+            (entry == name).then(|| id.parse().ok()).flatten()
+        })
+        .next()
+        .with_context(|| format!("No entry for {} in {}", name, database.display()))
+}
+
+pub fn parse_user(name: &str) -> Result<u32, String> {
+    lookup_id(name, Path::new("/etc/passwd")).map_err(|e| format!("{:#}", e))
+}
+
+pub fn parse_group(name: &str) -> Result<u32, String> {
+    lookup_id(name, Path::new("/etc/group")).map_err(|e| format!("{:#}", e))
+}
+
+/// Removes a socket left behind by a previous run, refusing to touch anything else.
+pub fn remove_stale_socket(path: &Path) -> Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Cannot inspect {}", path.display())),
+    };
+    if !metadata.file_type().is_socket() {
+        bail!(
+            "{} exists and is not a socket; refusing to remove it",
+            path.display()
+        );
+    }
+    info!("Removing stale socket {}", path.display());
+    fs::remove_file(path).with_context(|| format!("Cannot remove file {}", path.display()))
+}
+
+/// Removes a socket file this process bound once the listener is dropped.
+#[derive(Debug)]
+pub struct SocketFile {
+    path: PathBuf,
+}
+
+impl SocketFile {
+    /// Takes ownership of the file at `path`; abstract sockets have no file to remove.
+    pub fn new(path: &Path) -> Option<Self> {
+        abstract_name(path).is_none().then(|| Self {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        match fs::remove_file(&self.path) {
+            Ok(()) => debug!("Removed socket {}", self.path.display()),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => warn!("Cannot remove socket {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+/// An exclusive lock that keeps two instances from serving the same socket.
+///
+/// The lock is released when this is dropped or the process exits.
+#[derive(Debug)]
+pub struct InstanceLock {
+    _file: File,
+    path: PathBuf,
+}
+
+impl InstanceLock {
+    /// Locks `<socket>.lock` next to the socket path.
+    pub fn for_socket(socket_path: &Path) -> Result<Self> {
+        let mut path = socket_path.as_os_str().to_owned();
+        path.push(".lock");
+        Self::acquire(PathBuf::from(path))
+    }
+
+    pub fn acquire(path: PathBuf) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Cannot open lock file {}", path.display()))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => bail!(
+                "Another instance holds {}; is ring-detector already running?",
+                path.display()
+            ),
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("Cannot lock {}", path.display()))
+            }
+        }
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Self { _file: file, path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("660"), Ok(0o660));
+        assert_eq!(parse_mode("0o770"), Ok(0o770));
+        assert!(parse_mode("9").is_err());
+        assert!(parse_mode("17777").is_err());
+    }
+
+    #[test]
+    fn test_lookup_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let passwd = dir.path().join("passwd");
+        fs::write(
+            &passwd,
+            "root:x:0:0:root:/root:/bin/sh\nunbound:x:101:102::/var/lib/unbound:/sbin/nologin\n",
+        )
+        .unwrap();
+
+        assert_eq!(lookup_id("unbound", &passwd).unwrap(), 101);
+        assert_eq!(lookup_id("1234", &passwd).unwrap(), 1234);
+        assert!(lookup_id("knot", &passwd).is_err());
+    }
+
+    #[test]
+    fn test_removes_only_sockets() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let socket = dir.path().join("dns.sock");
+        drop(UnixListener::bind(&socket).unwrap());
+        remove_stale_socket(&socket).unwrap();
+        assert!(!socket.exists());
+
+        let file = dir.path().join("important.conf");
+        fs::write(&file, "keep me").unwrap();
+        assert!(remove_stale_socket(&file).is_err());
+        assert!(file.exists());
+
+        remove_stale_socket(&dir.path().join("missing")).unwrap();
+    }
+
+    #[test]
+    fn test_applies_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("dns.sock");
+
+        let options = SocketOptions {
+            mode: Some(0o660),
+            ..Default::default()
+        };
+        let _listener = options.bind(&socket).unwrap();
+        let mode = fs::metadata(&socket).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o660);
+        // The private directory is gone and the socket accepts connections.
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        std::os::unix::net::UnixStream::connect(&socket).unwrap();
+
+        assert!(options.bind(&socket).is_err(), "an existing socket is kept");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_binds_abstract_socket() {
+        use std::os::{
+            linux::net::SocketAddrExt,
+            unix::net::{SocketAddr, UnixStream},
+        };
+
+        let name = format!("ring-detector-test-{}", std::process::id());
+        let path = PathBuf::from(format!("@{}", name));
+        assert_eq!(abstract_name(&path), Some(name.as_bytes()));
+        assert_eq!(abstract_name(Path::new("/run/dns.sock")), None);
+
+        let _listener = bind(&path).unwrap();
+        assert!(!path.exists());
+        assert!(bind(&path).is_err(), "abstract names are exclusive");
+
+        let addr = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        UnixStream::connect_addr(&addr).unwrap();
+    }
+
+    #[test]
+    fn test_lock_detects_second_instance() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("dns.sock");
+
+        let lock = InstanceLock::for_socket(&socket).unwrap();
+        assert!(InstanceLock::for_socket(&socket).is_err());
+        drop(lock);
+        InstanceLock::for_socket(&socket).unwrap();
+    }
+}
@@ -0,0 +1,170 @@
+/*
+ * Copyright 2025 Kenny Root
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use tokio::time::Instant;
+
+use crate::{event::SourceEvent, mqtt::MqttMessage};
+
+/// How long a dnstap source may go without sending a frame before it is reported down.
+pub const DEFAULT_SOURCE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Connection state of one dnstap source, such as a resolver writing to our socket.
+///
+/// Any busy network resolves something every few seconds, so a source that sends
+/// no frames for long has either disconnected or stopped logging queries.
+#[derive(Debug)]
+pub struct SourceState {
+    name: String,
+    connections: AtomicUsize,
+    /// When a connection last opened, closed or delivered a frame.
+    last_activity: Mutex<Instant>,
+    down: AtomicBool,
+}
+
+/// Counts a connection to the source as open until dropped.
+#[derive(Debug)]
+pub struct SourceConnection(Arc<SourceState>);
+
+impl Drop for SourceConnection {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+        self.0.active();
+    }
+}
+
+impl SourceState {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            connections: AtomicUsize::new(0),
+            last_activity: Mutex::new(Instant::now()),
+            down: AtomicBool::new(false),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn connected(self: &Arc<Self>) -> SourceConnection {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.active();
+        SourceConnection(Arc::clone(self))
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    pub fn frame_read(&self) {
+        self.active();
+    }
+
+    fn active(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+
+    /// Time since the source last connected, disconnected or sent a frame.
+    pub fn silent_for(&self) -> Duration {
+        self.last_activity.lock().unwrap().elapsed()
+    }
+
+    pub fn is_down(&self) -> bool {
+        self.down.load(Ordering::Relaxed)
+    }
+
+    /// Decides whether the source is down, returning the new state when it changes.
+    pub fn update(&self, timeout: Duration) -> Option<bool> {
+        let down = self.silent_for() > timeout;
+        (self.down.swap(down, Ordering::Relaxed) != down).then_some(down)
+    }
+
+    /// Topic suffix for the source's state, `dnstap-<name>/state` with every
+    /// character of the name that is not a letter, digit, `-` or `_` replaced,
+    /// so it stays the same across restarts and reordered listeners.
+    pub fn topic(&self) -> String {
+        let name: String = self
+            .name
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+                _ => '_',
+            })
+            .collect();
+        format!("dnstap-{}/state", name.trim_matches('_'))
+    }
+
+    /// Announces whether the source is up or down.
+    pub fn state_message(&self) -> MqttMessage {
+        let event = SourceEvent {
+            source: self.name.clone(),
+            up: !self.is_down(),
+            connections: self.connections(),
+            silent_for: self.silent_for(),
+            timestamp: SystemTime::now(),
+        };
+        let payload = serde_json::json!({
+            "state": event.state(),
+            "source": event.source,
+            "connections": event.connections,
+            "silent_seconds": event.silent_for.as_secs(),
+        });
+        MqttMessage::Source {
+            topic: self.topic(),
+            payload: payload.to_string().into_bytes(),
+            event,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_source_goes_down_when_silent() {
+        let source = Arc::new(SourceState::new("/run/dnstap.sock"));
+        let timeout = Duration::from_millis(20);
+        assert_eq!(source.update(timeout), None);
+
+        let connection = source.connected();
+        assert_eq!(source.connections(), 1);
+        tokio::time::advance(Duration::from_millis(30)).await;
+        assert_eq!(source.update(timeout), Some(true));
+        assert_eq!(source.update(timeout), None, "changes are reported once");
+        assert!(source.is_down());
+
+        let message = source.state_message();
+        assert_eq!(message.topic(), "dnstap-run_dnstap_sock/state");
+        let payload: serde_json::Value = serde_json::from_slice(message.payload()).unwrap();
+        assert_eq!(payload["state"], "down");
+        assert_eq!(payload["source"], "/run/dnstap.sock");
+        assert_eq!(payload["connections"], 1);
+
+        source.frame_read();
+        assert_eq!(source.update(timeout), Some(false));
+        drop(connection);
+        assert_eq!(source.connections(), 0);
+        assert_eq!(source.update(timeout), None);
+    }
+}
@@ -0,0 +1,180 @@
+/*
+ * Copyright 2025 Kenny Root
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::{listener::DnsListener, mqtt::MqttMessage};
+use log::{info, warn};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    sync::{mpsc::Sender, oneshot},
+    task::JoinHandle,
+};
+
+#[derive(Debug, Clone)]
+pub struct SupervisorConfig {
+    /// Delay before the first restart; doubled after each further failure.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Consecutive failures after which the listener is reported down.
+    pub failure_threshold: u32,
+    /// A listener that stays up this long is considered healthy again.
+    pub reset_after: Duration,
+    /// Stop the bridge with an error once a listener is reported down.
+    pub exit_on_failure: bool,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            failure_threshold: 5,
+            reset_after: Duration::from_secs(60),
+            exit_on_failure: false,
+        }
+    }
+}
+
+impl SupervisorConfig {
+    fn backoff_for(&self, failures: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+            .min(self.max_backoff)
+    }
+}
+
+/// Changes in a supervised listener's health, reported to the bridge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenerHealth {
+    Down {
+        listener: usize,
+        failures: u32,
+        error: String,
+    },
+    Up {
+        listener: usize,
+    },
+}
+
+/// Aborts the wrapped task when dropped, so cancelling the supervisor stops the listener too.
+struct AbortOnDrop(JoinHandle<anyhow::Result<()>>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// A running supervisor and its listener.
+pub struct Supervised {
+    stop: oneshot::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl Supervised {
+    /// Stops the listener and waits until it has been dropped, so it can clean up after itself.
+    pub async fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.handle.await;
+    }
+}
+
+/// Runs `listener` until stopped, restarting it with backoff.
+///
+/// Listeners are expected to run forever, so returning at all counts as a failure.
+pub fn supervise(
+    index: usize,
+    listener: Box<dyn DnsListener>,
+    messages: Sender<MqttMessage>,
+    health: Sender<ListenerHealth>,
+    config: SupervisorConfig,
+) -> Supervised {
+    let listener: Arc<dyn DnsListener> = Arc::from(listener);
+    let (stop, mut stopped) = oneshot::channel();
+    let handle = tokio::spawn(async move {
+        let mut failures = 0;
+        let mut reported_down = false;
+
+        loop {
+            let attempt = Arc::clone(&listener);
+            let sender = messages.clone();
+            let mut task = AbortOnDrop(tokio::spawn(async move {
+                attempt.start_listening(sender).await
+            }));
+
+            let mut healthy = false;
+            let result = loop {
+                tokio::select! {
+                    result = &mut task.0 => break result,
+                    _ = &mut stopped => {
+                        task.0.abort();
+                        let _ = (&mut task.0).await;
+                        return;
+                    },
+                    _ = tokio::time::sleep(config.reset_after), if !healthy => {
+                        healthy = true;
+                        failures = 0;
+                        if reported_down {
+                            reported_down = false;
+                            info!("DNS listener {} recovered", index);
+                            let _ = health.send(ListenerHealth::Up { listener: index }).await;
+                        }
+                    },
+                }
+            };
+
+            let error = match result {
+                Ok(Ok(())) => "listener stopped".to_string(),
+                Ok(Err(e)) => format!("{:#}", e),
+                Err(e) => format!("listener task failed: {}", e),
+            };
+            failures += 1;
+            let backoff = config.backoff_for(failures);
+            warn!(
+                "DNS listener {} failed ({}); restarting in {:?}",
+                index, error, backoff
+            );
+
+            if failures >= config.failure_threshold && !reported_down {
+                reported_down = true;
+                let down = ListenerHealth::Down {
+                    listener: index,
+                    failures,
+                    error,
+                };
+                let _ = health.send(down).await;
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {},
+                _ = &mut stopped => return,
+            }
+        }
+    });
+    Supervised { stop, handle }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_up_to_max() {
+        let config = SupervisorConfig::default();
+        assert_eq!(config.backoff_for(1), Duration::from_secs(1));
+        assert_eq!(config.backoff_for(2), Duration::from_secs(2));
+        assert_eq!(config.backoff_for(4), Duration::from_secs(8));
+        assert_eq!(config.backoff_for(40), Duration::from_secs(60));
+    }
+}
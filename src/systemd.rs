@@ -0,0 +1,224 @@
+/*
+ * Copyright 2025 Kenny Root
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use anyhow::{bail, Context, Result};
+use log::debug;
+use std::{
+    env,
+    ffi::OsString,
+    os::{
+        fd::{FromRawFd, RawFd},
+        unix::net::{SocketAddr, UnixDatagram, UnixListener},
+    },
+    time::Duration,
+};
+
+/// First file descriptor passed by socket activation.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Sends state changes to the service manager over `$NOTIFY_SOCKET`.
+///
+/// Every method is a no-op when the service was not started by systemd.
+#[derive(Debug, Default)]
+pub struct Notifier {
+    socket: Option<(UnixDatagram, SocketAddr)>,
+    watchdog: Option<Duration>,
+}
+
+fn notify_addr(path: &OsString) -> Result<SocketAddr> {
+    let bytes = path.as_encoded_bytes();
+    if let Some(name) = bytes.strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::linux::net::SocketAddrExt;
+            return Ok(SocketAddr::from_abstract_name(name)?);
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = name;
+            bail!("Abstract notify sockets are only supported on Linux");
+        }
+    }
+    Ok(SocketAddr::from_pathname(path)?)
+}
+
+/// Returns the interval at which the service manager expects `WATCHDOG=1`, if any.
+fn watchdog_interval(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    if pid.is_some_and(|pid| pid.parse::<u32>().ok() != Some(own_pid)) {
+        return None;
+    }
+    match usec?.parse::<u64>().ok()? {
+        0 => None,
+        usec => Some(Duration::from_micros(usec)),
+    }
+}
+
+impl Notifier {
+    /// Connects to the service manager's notify socket, if there is one.
+    ///
+    /// The notify variables are cleared so child processes do not inherit them.
+    /// Changing the environment is only sound while the process has a single
+    /// thread, so call this before starting the runtime.
+    pub fn from_env() -> Result<Self> {
+        let path = env::var_os("NOTIFY_SOCKET");
+        let watchdog = watchdog_interval(
+            env::var("WATCHDOG_USEC").ok().as_deref(),
+            env::var("WATCHDOG_PID").ok().as_deref(),
+            std::process::id(),
+        );
+        env::remove_var("NOTIFY_SOCKET");
+        env::remove_var("WATCHDOG_USEC");
+        env::remove_var("WATCHDOG_PID");
+
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+        let mut notifier = Self::connect(notify_addr(&path)?)
+            .with_context(|| format!("Cannot use notify socket {:?}", path))?;
+        notifier.watchdog = watchdog;
+        Ok(notifier)
+    }
+
+    pub fn connect(addr: SocketAddr) -> Result<Self> {
+        Ok(Self {
+            socket: Some((UnixDatagram::unbound()?, addr)),
+            watchdog: None,
+        })
+    }
+
+    /// How often [`Notifier::watchdog`] should be called; half the manager's timeout.
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog.map(|timeout| timeout / 2)
+    }
+
+    pub fn notify(&self, state: &str) -> Result<()> {
+        if let Some((ref socket, ref addr)) = self.socket {
+            debug!("sd_notify: {}", state);
+            socket
+                .send_to_addr(state.as_bytes(), addr)
+                .context("Cannot send to notify socket")?;
+        }
+        Ok(())
+    }
+
+    pub fn ready(&self, status: &str) -> Result<()> {
+        self.notify(&format!("READY=1\nSTATUS={}", status))
+    }
+
+    pub fn status(&self, status: &str) -> Result<()> {
+        self.notify(&format!("STATUS={}", status))
+    }
+
+    pub fn watchdog(&self) -> Result<()> {
+        self.notify("WATCHDOG=1")
+    }
+
+    pub fn stopping(&self) -> Result<()> {
+        self.notify("STOPPING=1")
+    }
+}
+
+/// Returns the number of sockets passed to this process, if they are meant for it.
+fn listen_fds(pid: Option<&str>, fds: Option<&str>, own_pid: u32) -> Result<usize> {
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(0);
+    };
+    if pid.parse::<u32>().ok() != Some(own_pid) {
+        return Ok(0);
+    }
+    fds.parse::<usize>()
+        .with_context(|| format!("Invalid LISTEN_FDS {:?}", fds))
+}
+
+/// Takes the dnstap socket passed by systemd socket activation, if there is one.
+///
+/// The activation variables are cleared so child processes do not inherit them.
+/// Like [`Notifier::from_env`], call this before starting the runtime.
+pub fn activated_listener() -> Result<Option<UnixListener>> {
+    let count = listen_fds(
+        env::var("LISTEN_PID").ok().as_deref(),
+        env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    )?;
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    match count {
+        0 => Ok(None),
+        1 => {
+            // SAFETY: systemd hands over ownership of this descriptor and nothing else uses it.
+            let listener = unsafe { UnixListener::from_raw_fd(SD_LISTEN_FDS_START) };
+            listener
+                .local_addr()
+                .context("Socket passed by systemd is not a Unix socket")?;
+            listener.set_nonblocking(true)?;
+            Ok(Some(listener))
+        }
+        count => bail!("Expected one socket from systemd, got {}", count),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notifier_sends_states() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify.sock");
+        let server = UnixDatagram::bind(&path).unwrap();
+
+        let notifier = Notifier::connect(SocketAddr::from_pathname(&path).unwrap()).unwrap();
+        notifier.ready("Listening").unwrap();
+        notifier.watchdog().unwrap();
+
+        let mut buffer = [0u8; 256];
+        let n = server.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..n], b"READY=1\nSTATUS=Listening");
+        let n = server.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..n], b"WATCHDOG=1");
+    }
+
+    #[test]
+    fn test_notifier_without_socket_is_silent() {
+        let notifier = Notifier::default();
+        notifier.ready("Listening").unwrap();
+        assert_eq!(notifier.watchdog_interval(), None);
+    }
+
+    #[test]
+    fn test_watchdog_interval() {
+        assert_eq!(
+            watchdog_interval(Some("30000000"), None, 42),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            watchdog_interval(Some("30000000"), Some("42"), 42),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(watchdog_interval(Some("30000000"), Some("7"), 42), None);
+        assert_eq!(watchdog_interval(Some("0"), None, 42), None);
+        assert_eq!(watchdog_interval(None, None, 42), None);
+    }
+
+    #[test]
+    fn test_listen_fds() {
+        assert_eq!(listen_fds(Some("42"), Some("1"), 42).unwrap(), 1);
+        assert_eq!(listen_fds(Some("7"), Some("1"), 42).unwrap(), 0);
+        assert_eq!(listen_fds(None, None, 42).unwrap(), 0);
+        assert!(listen_fds(Some("42"), Some("x"), 42).is_err());
+    }
+}
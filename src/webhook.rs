@@ -0,0 +1,493 @@
+/*
+ * Copyright 2025 Kenny Root
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::{
+    event::{BridgeEvent, DoorbellEvent},
+    messaging::MessagePublisher,
+    mqtt::MqttMessage,
+    resilience,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use log::{debug, warn};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    Client, Method, Url,
+};
+use sha2::Sha256;
+use std::time::Duration;
+use tokio::task::JoinSet;
+
+pub const SIGNATURE_HEADER: &str = "X-Ring-Detector-Signature";
+
+pub const DEFAULT_BODY_TEMPLATE: &str = r#"{"device":"{{device}}","qname":"{{qname}}","resolver":"{{resolver}}","timestamp":"{{timestamp}}","late":{{late}}}"#;
+
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    pub urls: Vec<String>,
+    pub method: Method,
+    pub headers: Vec<(String, String)>,
+    /// JSON body for presses with `{{device}}`, `{{qname}}`, `{{resolver}}`,
+    /// `{{rcode}}`, `{{timestamp}}`, `{{late}}`, `{{topic}}` and `{{payload}}`
+    /// placeholders. Source events are sent as their JSON object instead.
+    pub body_template: String,
+    /// How long each request may take, including reading the response.
+    pub timeout: Duration,
+    /// Additional attempts at a URL after a retryable failure.
+    pub retries: u32,
+    /// Signs each body with HMAC-SHA256 in the `X-Ring-Detector-Signature` header.
+    pub hmac_secret: Option<String>,
+}
+
+impl WebhookConfig {
+    pub fn new(urls: Vec<String>) -> Self {
+        Self {
+            urls,
+            method: Method::POST,
+            headers: vec![],
+            body_template: DEFAULT_BODY_TEMPLATE.to_string(),
+            timeout: Duration::from_secs(10),
+            retries: 2,
+            hmac_secret: None,
+        }
+    }
+}
+
+/// Escapes `value` for use inside a JSON string literal.
+fn json_escape(value: &str) -> String {
+    let quoted = serde_json::to_string(value).unwrap();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+pub fn render_body(template: &str, topic: &str, payload: &[u8], event: &DoorbellEvent) -> String {
+    template
+        .replace("{{device}}", &json_escape(&event.device))
+        .replace("{{qname}}", &json_escape(&event.qname))
+        .replace(
+            "{{resolver}}",
+            &json_escape(event.resolver.as_deref().unwrap_or_default()),
+        )
+        .replace(
+            "{{rcode}}",
+            &json_escape(event.rcode.as_deref().unwrap_or_default()),
+        )
+        .replace("{{timestamp}}", &event.timestamp_rfc3339())
+        .replace("{{late}}", if event.late { "true" } else { "false" })
+        .replace("{{topic}}", &json_escape(topic))
+        .replace(
+            "{{payload}}",
+            &json_escape(&String::from_utf8_lossy(payload)),
+        )
+}
+
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    let digest: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("sha256={}", digest)
+}
+
+/// POSTs each doorbell press and dnstap source change to a list of HTTP endpoints.
+///
+/// Each call makes one request per URL, all at once, and retries each URL on its
+/// own. A publish is only handed back as retryable to the [`resilience`] layer
+/// when every URL failed that way, so no URL is called twice for one event.
+#[derive(Debug, Clone)]
+pub struct WebhookService {
+    client: Client,
+    config: WebhookConfig,
+    urls: Vec<Url>,
+    headers: HeaderMap,
+}
+
+impl WebhookService {
+    /// Checks the URLs and headers up front, so a misconfigured webhook fails at
+    /// startup rather than on every press.
+    pub fn new(config: WebhookConfig) -> Result<Self> {
+        let urls = config
+            .urls
+            .iter()
+            .map(|url| {
+                let parsed =
+                    Url::parse(url).with_context(|| format!("Invalid webhook URL {}", url))?;
+                if !matches!(parsed.scheme(), "http" | "https") {
+                    bail!("Webhook URL {} is not http or https", url);
+                }
+                Ok(parsed)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut headers = HeaderMap::new();
+        for (name, value) in &config.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .with_context(|| format!("Invalid webhook header name {:?}", name))?;
+            let value = HeaderValue::from_str(value)
+                .with_context(|| format!("Invalid value for webhook header {}", name))?;
+            headers.append(name, value);
+        }
+        let client = Client::builder()
+            .timeout(config.timeout)
+            .build()
+            .context("Cannot create HTTP client")?;
+        Ok(Self {
+            client,
+            config,
+            urls,
+            headers,
+        })
+    }
+
+    /// Calls one URL, retrying failures that are worth retrying.
+    async fn send(&self, url: &Url, body: &str) -> Result<()> {
+        let mut attempt = 0;
+        loop {
+            let error = match self.send_once(url, body).await {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+            if !resilience::is_retryable(&error) || attempt >= self.config.retries {
+                return Err(error);
+            }
+            attempt += 1;
+            warn!(
+                "{:#}; retrying ({}/{})",
+                error, attempt, self.config.retries
+            );
+            tokio::time::sleep(RETRY_BASE_DELAY * 2u32.pow(attempt - 1)).await;
+        }
+    }
+
+    /// Makes one request, marking server errors, connection failures and
+    /// timeouts as worth retrying.
+    async fn send_once(&self, url: &Url, body: &str) -> Result<()> {
+        let mut request = self
+            .client
+            .request(self.config.method.clone(), url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .headers(self.headers.clone())
+            .body(body.to_owned());
+        if let Some(ref secret) = self.config.hmac_secret {
+            request = request.header(SIGNATURE_HEADER, sign(secret, body.as_bytes()));
+        }
+
+        match request.send().await {
+            Ok(response) if response.status().is_success() => {
+                debug!("Webhook {} returned {}", url, response.status());
+                Ok(())
+            }
+            Ok(response) if response.status().is_server_error() => Err(resilience::retryable(
+                anyhow!("Webhook {} returned {}", url, response.status()),
+            )),
+            Ok(response) => bail!("Webhook {} returned {}", url, response.status()),
+            Err(e) if e.is_connect() || e.is_timeout() => Err(resilience::retryable(
+                anyhow!(e).context(format!("Webhook {} failed", url)),
+            )),
+            Err(e) => Err(anyhow!(e).context(format!("Webhook {} failed", url))),
+        }
+    }
+}
+
+#[async_trait]
+impl MessagePublisher for WebhookService {
+    fn name(&self) -> &str {
+        "webhook"
+    }
+
+    async fn publish(&self, message: MqttMessage) -> Result<()> {
+        let body = match message.bridge_event() {
+            Some(BridgeEvent::Press(event)) => render_body(
+                &self.config.body_template,
+                message.topic(),
+                message.payload(),
+                &event,
+            ),
+            Some(BridgeEvent::Source(event)) => event.to_json().to_string(),
+            None => return Ok(()),
+        };
+
+        // Requests run together so a slow URL does not use up the others' time.
+        let mut requests = JoinSet::new();
+        for url in &self.urls {
+            let (service, url, body) = (self.clone(), url.clone(), body.clone());
+            requests.spawn(async move { service.send(&url, &body).await });
+        }
+
+        let mut failures = vec![];
+        let mut all_retryable = true;
+        while let Some(result) = requests.join_next().await {
+            if let Err(e) = result.context("Webhook request panicked")? {
+                all_retryable &= resilience::is_retryable(&e);
+                failures.push(format!("{:#}", e));
+            }
+        }
+
+        if failures.is_empty() {
+            return Ok(());
+        }
+        let error = anyhow!(failures.join("; "));
+        // Retrying would repeat the call to URLs that already succeeded.
+        if all_retryable && failures.len() == self.urls.len() {
+            Err(resilience::retryable(error))
+        } else {
+            Err(error)
+        }
+    }
+
+    async fn send_birth(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn send_death(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, UNIX_EPOCH},
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    #[derive(Debug, Clone)]
+    struct Request {
+        head: String,
+        body: String,
+    }
+
+    /// Answers every request with the next status in `statuses`, then 200.
+    async fn stand_in_server(statuses: Vec<u16>) -> (String, Arc<Mutex<Vec<Request>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let recorded = Arc::clone(&requests);
+
+        tokio::spawn(async move {
+            let mut statuses = statuses.into_iter();
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buffer = vec![];
+                let mut chunk = [0u8; 1024];
+                let (head, body) = loop {
+                    let n = stream.read(&mut chunk).await.unwrap();
+                    buffer.extend_from_slice(&chunk[..n]);
+                    let text = String::from_utf8_lossy(&buffer).to_string();
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let length = head
+                            .lines()
+                            .find_map(|l| {
+                                l.to_lowercase()
+                                    .strip_prefix("content-length: ")
+                                    .map(|v| v.parse::<usize>().unwrap())
+                            })
+                            .unwrap_or(0);
+                        if body.len() >= length {
+                            break (head.to_string(), body.to_string());
+                        }
+                    }
+                };
+                recorded.lock().unwrap().push(Request { head, body });
+
+                let status = statuses.next().unwrap_or(200);
+                let response = format!(
+                    "HTTP/1.1 {} X\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        (url, requests)
+    }
+
+    fn press() -> MqttMessage {
+        let mut event = DoorbellEvent::new(
+            "192.168.1.100".to_string(),
+            "alarm.use.s3.amazonaws.com".to_string(),
+            Some("unbound".to_string()),
+        );
+        event.timestamp = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        MqttMessage::Event {
+            topic: "ringdet-192.168.1.100/action".to_string(),
+            payload: b"{action:\"pressed\"}".to_vec(),
+            event,
+        }
+    }
+
+    #[test]
+    fn test_render_default_body() {
+        let message = press();
+        let body = render_body(
+            DEFAULT_BODY_TEMPLATE,
+            message.topic(),
+            message.payload(),
+            message.event().unwrap(),
+        );
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["device"], "192.168.1.100");
+        assert_eq!(json["resolver"], "unbound");
+        assert_eq!(json["timestamp"], "2023-11-14T22:13:20.000Z");
+        assert_eq!(json["late"], false);
+    }
+
+    #[test]
+    fn test_render_escapes_values() {
+        let message = press();
+        let body = render_body(
+            r#"{"payload":"{{payload}}"}"#,
+            message.topic(),
+            message.payload(),
+            message.event().unwrap(),
+        );
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["payload"], "{action:\"pressed\"}");
+    }
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231 test case 2.
+        let mac = sign("Jefe", b"what do ya want for nothing?");
+        assert_eq!(
+            mac,
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_posts_to_every_url() {
+        let (first_url, first) = stand_in_server(vec![]).await;
+        let (second_url, second) = stand_in_server(vec![]).await;
+
+        let mut config = WebhookConfig::new(vec![first_url, second_url]);
+        config.headers = vec![("Authorization".to_string(), "Bearer token".to_string())];
+        config.hmac_secret = Some("secret".to_string());
+        let service = WebhookService::new(config).unwrap();
+
+        service.publish(press()).await.unwrap();
+
+        for requests in [first, second] {
+            let requests = requests.lock().unwrap();
+            assert_eq!(requests.len(), 1);
+            let head = requests[0].head.to_lowercase();
+            assert!(head.starts_with("post /hook "));
+            assert!(head.contains("authorization: bearer token"));
+            let signature = sign("secret", requests[0].body.as_bytes());
+            assert!(head.contains(&format!("x-ring-detector-signature: {}", signature)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retries_server_errors() {
+        let (url, requests) = stand_in_server(vec![503]).await;
+        let mut config = WebhookConfig::new(vec![url]);
+        config.retries = 1;
+        let service = WebhookService::new(config).unwrap();
+
+        service.publish(press()).await.unwrap();
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_server_errors_are_marked_retryable() {
+        let (url, requests) = stand_in_server(vec![503]).await;
+        let mut config = WebhookConfig::new(vec![url]);
+        config.retries = 0;
+        let service = WebhookService::new(config).unwrap();
+
+        let error = service.publish(press()).await.unwrap_err();
+        assert!(resilience::is_retryable(&error));
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_connection_failures_are_marked_retryable() {
+        // Bind and drop a listener to find a port nothing is serving on.
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut config = WebhookConfig::new(vec![format!("http://{}/hook", addr)]);
+        config.retries = 0;
+        let service = WebhookService::new(config).unwrap();
+
+        let error = service.publish(press()).await.unwrap_err();
+        assert!(resilience::is_retryable(&error));
+    }
+
+    #[test]
+    fn test_rejects_invalid_config() {
+        let invalid = |config: WebhookConfig| WebhookService::new(config).is_err();
+        assert!(invalid(WebhookConfig::new(vec!["not a url".to_string()])));
+        assert!(invalid(WebhookConfig::new(vec![
+            "ftp://example.com/hook".to_string()
+        ])));
+
+        let mut config = WebhookConfig::new(vec!["http://example.com/hook".to_string()]);
+        config.headers = vec![("Bad Name".to_string(), "value".to_string())];
+        assert!(invalid(config.clone()));
+        config.headers = vec![("X-Token".to_string(), "bad\nvalue".to_string())];
+        assert!(invalid(config));
+    }
+
+    #[tokio::test]
+    async fn test_client_errors_are_not_retried() {
+        let (url, requests) = stand_in_server(vec![404]).await;
+        let service = WebhookService::new(WebhookConfig::new(vec![url])).unwrap();
+
+        let error = service.publish(press()).await.unwrap_err();
+        assert!(!resilience::is_retryable(&error));
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_posts_source_changes() {
+        let (url, requests) = stand_in_server(vec![]).await;
+        let service = WebhookService::new(WebhookConfig::new(vec![url])).unwrap();
+
+        let source = crate::source::SourceState::new("/run/dnstap.sock");
+        service.publish(source.state_message()).await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        let json: serde_json::Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(json["event"], "source");
+        assert_eq!(json["source"], "/run/dnstap.sock");
+        assert_eq!(json["state"], "up");
+    }
+
+    #[tokio::test]
+    async fn test_ignores_non_event_messages() {
+        let (url, requests) = stand_in_server(vec![]).await;
+        let service = WebhookService::new(WebhookConfig::new(vec![url])).unwrap();
+
+        let message = MqttMessage::Publish {
+            topic: "ringdet-192.168.1.100/config".to_string(),
+            payload: b"{}".to_vec(),
+        };
+        service.publish(message).await.unwrap();
+        assert!(requests.lock().unwrap().is_empty());
+    }
+}
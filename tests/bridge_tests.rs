@@ -61,6 +61,7 @@ mock! {
         async fn publish(&self, message: MqttMessage) -> Result<()>;
         async fn send_birth(&self) -> Result<()>;
         async fn send_death(&self) -> Result<()>;
+        async fn send_status(&self, status: &str) -> Result<()>;
     }
 }
 
@@ -230,3 +231,369 @@ async fn test_bridge_without_mqtt() {
     handle.abort();
     let _ = handle.await;
 }
+
+#[tokio::test]
+async fn test_fan_out_isolates_publisher_failures() {
+    let mut mock_listener = MockDnsListener::new();
+    mock_listener.expect_box_clone().returning(|| {
+        let mut mock = MockDnsListener::new();
+        mock.expect_start_listening().returning(|sender| {
+            tokio::spawn(async move {
+                let message = MqttMessage::Publish {
+                    topic: "test/topic".to_string(),
+                    payload: b"test_payload".to_vec(),
+                };
+                let _ = sender.send(message).await;
+            });
+            Ok(())
+        });
+        Box::new(mock)
+    });
+
+    // Both publishers get a birth message even though one of them is broken.
+    let mut failing = MockMessagePublisher::new();
+    failing.expect_send_birth().times(1).returning(|| Ok(()));
+    failing
+        .expect_publish()
+        .returning(|_| Err(anyhow::anyhow!("broker unavailable")));
+    failing.expect_send_death().returning(|| Ok(()));
+
+    let mut working = MockMessagePublisher::new();
+    working.expect_send_birth().times(1).returning(|| Ok(()));
+    working.expect_publish().returning(|_| Ok(()));
+    working.expect_send_death().returning(|| Ok(()));
+
+    let bridge = Bridge::with_publishers(
+        Box::new(mock_listener),
+        vec![Box::new(failing), Box::new(working)],
+    );
+    let stats = bridge.publisher_stats();
+
+    let handle = tokio::spawn(async move {
+        let _ = bridge.start().await;
+    });
+
+    tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+
+    assert_eq!(stats[0].failed(), 1);
+    assert_eq!(stats[0].published(), 0);
+    assert_eq!(stats[1].failed(), 0);
+    assert_eq!(stats[1].published(), 1);
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_bridge_notifies_readiness() {
+    use ring_detector_lib::systemd::Notifier;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let dir = tempdir().unwrap();
+    let notify_path = dir.path().join("notify.sock");
+    let notify_socket = UnixDatagram::bind(&notify_path).unwrap();
+    notify_socket
+        .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .unwrap();
+
+    let mut mock_listener = MockDnsListener::new();
+    mock_listener.expect_box_clone().returning(|| {
+        let mut mock = MockDnsListener::new();
+        mock.expect_start_listening().returning(|_| Ok(()));
+        Box::new(mock)
+    });
+
+    let notifier = Notifier::connect(SocketAddr::from_pathname(&notify_path).unwrap()).unwrap();
+    let bridge = Bridge::from_components(Box::new(mock_listener), None).with_notifier(notifier);
+
+    let handle = tokio::spawn(async move {
+        let _ = bridge.start().await;
+    });
+
+    let received = tokio::task::spawn_blocking(move || {
+        let mut buffer = [0u8; 256];
+        let n = notify_socket.recv(&mut buffer).unwrap();
+        String::from_utf8_lossy(&buffer[..n]).to_string()
+    })
+    .await
+    .unwrap();
+    assert!(received.starts_with("READY=1\nSTATUS="), "{}", received);
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+/// Starts the bridge from `build` with its dnstap socket in a directory that
+/// does not exist yet, and checks READY waits until the socket is bound.
+async fn assert_ready_once_bound(
+    build: impl FnOnce(
+        std::path::PathBuf,
+        ring_detector_lib::systemd::Notifier,
+        ring_detector_lib::supervisor::SupervisorConfig,
+    ) -> Bridge,
+) {
+    use ring_detector_lib::supervisor::SupervisorConfig;
+    use ring_detector_lib::systemd::Notifier;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+    use std::time::Duration;
+
+    let dir = tempdir().unwrap();
+    let notify_path = dir.path().join("notify.sock");
+    let notify_socket = UnixDatagram::bind(&notify_path).unwrap();
+    notify_socket.set_nonblocking(true).unwrap();
+
+    // The socket directory does not exist yet, so binding fails at first.
+    let socket_dir = dir.path().join("run");
+    let notifier = Notifier::connect(SocketAddr::from_pathname(&notify_path).unwrap()).unwrap();
+    let supervisor = SupervisorConfig {
+        initial_backoff: Duration::from_millis(50),
+        max_backoff: Duration::from_millis(50),
+        ..Default::default()
+    };
+    let bridge = build(socket_dir.join("dns.sock"), notifier, supervisor);
+    let handle = tokio::spawn(async move {
+        let _ = bridge.start().await;
+    });
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let mut buffer = [0u8; 256];
+    assert!(
+        notify_socket.recv(&mut buffer).is_err(),
+        "READY sent before the listener was bound"
+    );
+
+    std::fs::create_dir(&socket_dir).unwrap();
+    notify_socket.set_nonblocking(false).unwrap();
+    notify_socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let received = tokio::task::spawn_blocking(move || {
+        let n = notify_socket.recv(&mut buffer).unwrap();
+        String::from_utf8_lossy(&buffer[..n]).to_string()
+    })
+    .await
+    .unwrap();
+    assert!(received.starts_with("READY=1\nSTATUS="), "{}", received);
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_bridge_notifies_readiness_once_bound() {
+    assert_ready_once_bound(|socket_path, notifier, supervisor| {
+        Bridge::builder()
+            .dns_socket(socket_path)
+            .supervisor(supervisor)
+            .notifier(notifier)
+            .build()
+            .unwrap()
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_bridge_without_builder_notifies_readiness_once_bound() {
+    assert_ready_once_bound(|socket_path, notifier, supervisor| {
+        Bridge::new(socket_path)
+            .with_supervisor(supervisor)
+            .with_notifier(notifier)
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_run_until_with_subscription() {
+    use ring_detector_lib::event::{BridgeEvent, DoorbellEvent};
+    use tokio::sync::oneshot;
+
+    let mut mock_listener = MockDnsListener::new();
+    mock_listener.expect_box_clone().returning(|| {
+        let mut mock = MockDnsListener::new();
+        mock.expect_start_listening().returning(|sender| {
+            tokio::spawn(async move {
+                let event = DoorbellEvent::new(
+                    "192.168.1.100".to_string(),
+                    "alarm.use.s3.amazonaws.com".to_string(),
+                    None,
+                );
+                let message = MqttMessage::Event {
+                    topic: "ringdet-192.168.1.100/action".to_string(),
+                    payload: b"{action:\"pressed\"}".to_vec(),
+                    event,
+                };
+                let _ = sender.send(message).await;
+            });
+            Ok(())
+        });
+        Box::new(mock)
+    });
+
+    let mut mock_publisher = MockMessagePublisher::new();
+    mock_publisher.expect_send_birth().returning(|| Ok(()));
+    mock_publisher.expect_publish().returning(|_| Ok(()));
+    // The external shutdown still sends the death message.
+    mock_publisher
+        .expect_send_death()
+        .times(1)
+        .returning(|| Ok(()));
+
+    let bridge = Bridge::from_components(Box::new(mock_listener), Some(Box::new(mock_publisher)));
+    let mut events = bridge.subscribe();
+    let (stop, stopped) = oneshot::channel::<()>();
+
+    let handle = tokio::spawn(async move {
+        bridge
+            .run_until(async {
+                let _ = stopped.await;
+            })
+            .await
+    });
+
+    let event = tokio::time::timeout(tokio::time::Duration::from_secs(5), events.recv())
+        .await
+        .expect("event should arrive")
+        .unwrap();
+    let BridgeEvent::Press(event) = event else {
+        panic!("expected a press, got {:?}", event);
+    };
+    assert_eq!(event.device, "192.168.1.100");
+
+    stop.send(()).unwrap();
+    let result = tokio::time::timeout(tokio::time::Duration::from_secs(10), handle)
+        .await
+        .expect("bridge should stop")
+        .unwrap();
+    assert!(result.is_ok());
+}
+
+#[tokio::test]
+async fn test_failing_listener_is_restarted_and_reported() {
+    use ring_detector_lib::supervisor::SupervisorConfig;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::time::Duration;
+
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let attempts_clone = Arc::clone(&attempts);
+    let mut mock_listener = MockDnsListener::new();
+    mock_listener.expect_box_clone().returning(move || {
+        let attempts = Arc::clone(&attempts_clone);
+        let mut mock = MockDnsListener::new();
+        mock.expect_start_listening().returning(move |_| {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(anyhow::anyhow!("address in use"))
+        });
+        Box::new(mock)
+    });
+
+    let statuses = Arc::new(Mutex::new(Vec::new()));
+    let statuses_clone = Arc::clone(&statuses);
+    let mut mock_publisher = MockMessagePublisher::new();
+    mock_publisher.expect_send_birth().returning(|| Ok(()));
+    mock_publisher
+        .expect_send_status()
+        .returning(move |status| {
+            statuses_clone.lock().unwrap().push(status.to_string());
+            Ok(())
+        });
+    mock_publisher.expect_send_death().returning(|| Ok(()));
+
+    let bridge = Bridge::from_components(Box::new(mock_listener), Some(Box::new(mock_publisher)))
+        .with_supervisor(SupervisorConfig {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(5),
+            failure_threshold: 3,
+            exit_on_failure: true,
+            ..Default::default()
+        });
+
+    let result = tokio::time::timeout(
+        Duration::from_secs(5),
+        bridge.run_until(std::future::pending()),
+    )
+    .await
+    .expect("bridge should stop once the listener is down");
+
+    let error = result.unwrap_err().to_string();
+    assert!(error.contains("address in use"), "{}", error);
+    // The supervisor may retry again before the bridge stops it.
+    assert!(attempts.load(Ordering::SeqCst) >= 3);
+    assert_eq!(*statuses.lock().unwrap(), vec!["offline".to_string()]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_silent_dns_source_is_reported_down() {
+    use ring_detector_lib::dns_service::DnsService;
+    use ring_detector_lib::event::BridgeEvent;
+    use tokio::time::Duration;
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let socket_path = temp_dir.path().join("dns.sock");
+
+    let (published_tx, mut published_rx) = mpsc::unbounded_channel();
+    let mut mock_publisher = MockMessagePublisher::new();
+    mock_publisher.expect_send_birth().returning(|| Ok(()));
+    mock_publisher.expect_publish().returning(move |message| {
+        let _ = published_tx.send(message);
+        Ok(())
+    });
+    mock_publisher.expect_send_death().returning(|| Ok(()));
+
+    let bridge = Bridge::with_publishers(
+        Box::new(DnsService::new(socket_path.clone())),
+        vec![Box::new(mock_publisher)],
+    )
+    .with_source_timeout(Duration::from_millis(50));
+    let mut events = bridge.subscribe();
+
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let handle = tokio::spawn(async move {
+        bridge
+            .run_until(async {
+                let _ = stopped.await;
+            })
+            .await
+    });
+
+    let mut states = vec![];
+    for _ in 0..2 {
+        let message = tokio::time::timeout(Duration::from_secs(5), published_rx.recv())
+            .await
+            .expect("a silent source should be reported")
+            .unwrap();
+        let state: serde_json::Value = serde_json::from_slice(message.payload()).unwrap();
+        assert_eq!(state["source"], socket_path.display().to_string());
+        assert_eq!(state["connections"], 0);
+        states.push((message.topic().to_string(), state["state"].clone()));
+    }
+    // The source is announced up at start, then down once it stays silent.
+    let topic = format!(
+        "dnstap-{}/state",
+        socket_path
+            .display()
+            .to_string()
+            .replace(['/', '.'], "_")
+            .trim_matches('_')
+    );
+    assert_eq!(
+        states,
+        vec![(topic.clone(), "up".into()), (topic, "down".into())]
+    );
+
+    // Subscribers see the same changes as events.
+    for up in [true, false] {
+        let event = events.recv().await.unwrap();
+        let BridgeEvent::Source(event) = event else {
+            panic!("expected a source event, got {:?}", event);
+        };
+        assert_eq!(event.up, up);
+        assert_eq!(event.source, socket_path.display().to_string());
+    }
+
+    stop.send(()).unwrap();
+    let result = tokio::time::timeout(Duration::from_secs(10), handle)
+        .await
+        .expect("bridge should stop")
+        .unwrap();
+    assert!(result.is_ok());
+}
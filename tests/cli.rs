@@ -28,3 +28,109 @@ fn need_required_args_fail() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[test]
+fn mqtt_settings_need_broker_fail() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("ring-detector")?;
+
+    cmd.args([
+        "--dns-socket",
+        "/nonexistent/dns.sock",
+        "--mqtt-keep-alive",
+        "10",
+    ])
+    .assert()
+    .failure()
+    .stderr(predicate::str::contains("--mqtt-host"));
+
+    Ok(())
+}
+
+#[test]
+fn mqtt_qos_out_of_range_fail() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("ring-detector")?;
+
+    cmd.args(["--dns-socket", "/nonexistent/dns.sock", "--mqtt-qos", "3"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--mqtt-qos"));
+
+    Ok(())
+}
+
+#[test]
+fn dns_socket_mode_must_be_octal_fail() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("ring-detector")?;
+
+    cmd.args([
+        "--dns-socket",
+        "/nonexistent/dns.sock",
+        "--dns-socket-mode",
+        "rw",
+    ]);
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("expected an octal mode"));
+
+    Ok(())
+}
+
+#[test]
+fn refuses_to_remove_regular_file_fail() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("dns.sock");
+    std::fs::write(&path, "not a socket")?;
+
+    let mut cmd = Command::cargo_bin("ring-detector")?;
+    cmd.arg("--dns-socket").arg(&path);
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("is not a socket"));
+    assert!(path.exists());
+
+    Ok(())
+}
+
+#[test]
+fn abstract_socket_rejects_mode_fail() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("ring-detector")?;
+
+    cmd.args([
+        "--dns-socket",
+        "@ring-detector-cli-test",
+        "--dns-socket-mode",
+        "660",
+    ]);
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("Abstract sockets"));
+
+    Ok(())
+}
+
+#[test]
+fn healthcheck_needs_address_fail() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("ring-detector")?;
+
+    cmd.env_remove("METRICS_LISTEN").env_remove("DNS_SOCKET");
+    cmd.arg("healthcheck")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--metrics-listen"));
+
+    Ok(())
+}
+
+#[test]
+fn healthcheck_without_instance_fail() -> Result<(), Box<dyn std::error::Error>> {
+    // Bind and drop a listener to find a port nothing is serving on.
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+
+    let mut cmd = Command::cargo_bin("ring-detector")?;
+    cmd.args(["healthcheck", "--metrics-listen", &addr.to_string()])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Cannot connect"));
+
+    Ok(())
+}
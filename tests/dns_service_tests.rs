@@ -0,0 +1,231 @@
+/*
+ * Copyright 2025 Kenny Root
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use std::{
+    collections::HashSet,
+    io::{Read, Write},
+    os::unix::net::UnixStream,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    sync::mpsc,
+    time::{sleep, timeout, Duration},
+};
+
+use ring_detector_lib::{
+    dns::DnsSocket,
+    dns_service::DnsService,
+    listener::DnsListener,
+    supervisor::{supervise, SupervisorConfig},
+};
+
+const ACCEPT: u32 = 1;
+const START: u32 = 2;
+const STOP: u32 = 3;
+const READY: u32 = 4;
+const FINISH: u32 = 5;
+
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+/// Writes an fstrm control frame, optionally carrying the dnstap content type.
+fn write_control(mut stream: &UnixStream, control_type: u32, content_type: Option<&[u8]>) {
+    let mut body = control_type.to_be_bytes().to_vec();
+    if let Some(content_type) = content_type {
+        body.extend(1u32.to_be_bytes());
+        body.extend((content_type.len() as u32).to_be_bytes());
+        body.extend(content_type);
+    }
+    let mut frame = 0u32.to_be_bytes().to_vec();
+    frame.extend((body.len() as u32).to_be_bytes());
+    frame.extend(body);
+    stream.write_all(&frame).unwrap();
+}
+
+/// Reads an fstrm control frame and returns its type.
+fn read_control(mut stream: &UnixStream) -> u32 {
+    let mut header = [0u8; 8];
+    stream.read_exact(&mut header).unwrap();
+    assert_eq!(header[..4], [0, 0, 0, 0], "expected a control frame");
+    let length = u32::from_be_bytes(header[4..].try_into().unwrap()) as usize;
+    let mut body = vec![0u8; length];
+    stream.read_exact(&mut body).unwrap();
+    u32::from_be_bytes(body[..4].try_into().unwrap())
+}
+
+/// Acts as a dnstap sender such as Unbound and opens a bidirectional stream.
+fn handshake(stream: &UnixStream) {
+    write_control(stream, READY, Some(CONTENT_TYPE));
+    assert_eq!(read_control(stream), ACCEPT);
+    write_control(stream, START, Some(CONTENT_TYPE));
+}
+
+fn assert_closed(mut stream: &UnixStream) {
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty(), "unexpected data before close: {:?}", rest);
+}
+
+async fn wait_for_socket(path: &Path) {
+    timeout(Duration::from_secs(5), async {
+        while !path.exists() {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("listener should bind its socket");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_stop_is_answered_with_finish() {
+    let (server, client) = UnixStream::pair().unwrap();
+    let (tx, _rx) = mpsc::channel(10);
+    let doorbells = Arc::new(Mutex::new(HashSet::new()));
+
+    let server = tokio::spawn(async move {
+        DnsSocket::new(server, tx, doorbells, Arc::default())
+            .handle_stream()
+            .await
+    });
+
+    let client = tokio::task::spawn_blocking(move || {
+        handshake(&client);
+        write_control(&client, STOP, None);
+        assert_eq!(read_control(&client), FINISH);
+        assert_closed(&client);
+    });
+
+    timeout(Duration::from_secs(5), client)
+        .await
+        .expect("client should finish")
+        .unwrap();
+    let result = timeout(Duration::from_secs(5), server)
+        .await
+        .expect("connection should end after STOP")
+        .unwrap();
+    assert!(result.is_ok());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_stopping_listener_closes_connections_and_removes_socket() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("dns.sock");
+    let (tx, _rx) = mpsc::channel(10);
+    let (health_tx, _health_rx) = mpsc::channel(10);
+
+    let supervised = supervise(
+        0,
+        Box::new(DnsService::new(path.clone())),
+        tx,
+        health_tx,
+        SupervisorConfig::default(),
+    );
+    wait_for_socket(&path).await;
+
+    let connect = path.clone();
+    let clients = tokio::task::spawn_blocking(move || {
+        (0..2)
+            .map(|_| {
+                let client = UnixStream::connect(&connect).unwrap();
+                handshake(&client);
+                client
+            })
+            .collect::<Vec<_>>()
+    });
+    let clients = timeout(Duration::from_secs(5), clients)
+        .await
+        .expect("clients should connect")
+        .unwrap();
+
+    timeout(Duration::from_secs(5), supervised.stop())
+        .await
+        .expect("listener should stop promptly");
+    assert!(!path.exists(), "socket file should be removed");
+
+    let clients = tokio::task::spawn_blocking(move || {
+        // Without a STOP from the sender, there is no FINISH to answer with.
+        for client in clients {
+            assert_closed(&client);
+        }
+    });
+    timeout(Duration::from_secs(5), clients)
+        .await
+        .expect("clients should see the connection close")
+        .unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_finished_connections_are_forgotten() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("dns.sock");
+    let (tx, _rx) = mpsc::channel(10);
+    let (health_tx, _health_rx) = mpsc::channel(10);
+
+    let supervised = supervise(
+        0,
+        Box::new(DnsService::new(path.clone())),
+        tx,
+        health_tx,
+        SupervisorConfig::default(),
+    );
+    wait_for_socket(&path).await;
+
+    // A sender that stops on its own is answered and closed without waiting for shutdown.
+    let client = UnixStream::connect(&path).unwrap();
+    let client = tokio::task::spawn_blocking(move || {
+        handshake(&client);
+        write_control(&client, STOP, None);
+        assert_eq!(read_control(&client), FINISH);
+        assert_closed(&client);
+    });
+    timeout(Duration::from_secs(5), client)
+        .await
+        .expect("client should finish")
+        .unwrap();
+
+    timeout(Duration::from_secs(5), supervised.stop())
+        .await
+        .expect("listener should stop promptly");
+    assert!(!path.exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_inherited_listener_survives_restart() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("dns.sock");
+    let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let service = DnsService::with_listener(path.clone(), listener);
+
+    for _ in 0..2 {
+        let (tx, _rx) = mpsc::channel(10);
+        let running = service.box_clone();
+        let task = tokio::spawn(async move { running.start_listening(tx).await });
+
+        let connect = path.clone();
+        let client = tokio::task::spawn_blocking(move || {
+            let client = UnixStream::connect(&connect).unwrap();
+            handshake(&client);
+        });
+        timeout(Duration::from_secs(5), client)
+            .await
+            .expect("client should connect")
+            .unwrap();
+
+        task.abort();
+        let _ = task.await;
+        assert!(path.exists(), "the service manager owns the socket file");
+    }
+}